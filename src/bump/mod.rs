@@ -0,0 +1,778 @@
+use std::{path::Path, slice, str::FromStr};
+
+use bitcoin::{
+    consensus,
+    hex::{Case, DisplayHex},
+    key::Secp256k1,
+    secp256k1::{rand::RngCore, All},
+    Amount, FeeRate, Network, OutPoint, Script, Transaction, TxOut, Txid, Weight,
+};
+use electrum_client::{Client, ElectrumApi};
+
+use crate::common::{
+    broadcast::{self, Broadcaster, TxStatus},
+    checks, fees,
+    keys::{self, Keychain, ScriptType, StoredKey},
+    timelocks::Timelocks,
+    utxos::{self, Utxo},
+    wallet::{Wallet, WalletStore},
+};
+use crate::{tr, watch, wpkh};
+
+/// Bitcoin Core's default `-incrementalrelayfee`: a replacement must pay at least this much on
+/// top of the fee of what it replaces, for its own size.
+const INCREMENTAL_RELAY_FEE: FeeRate = FeeRate::from_sat_per_vb_unchecked(1);
+
+/// BIP125 rule 5: at most this many transactions, descendants included, may be evicted at once.
+const MAX_REPLACED_TRANSACTIONS: usize = 100;
+
+/// The replacement worked out from the original transaction and the wallet's coins, ready to
+/// sign.
+struct Replacement {
+    inputs: Vec<(OutPoint, TxOut)>,
+    outputs: Vec<TxOut>,
+    /// What the signed transaction will weigh
+    weight: Weight,
+    /// The original's unconfirmed descendants, which the replacement evicts along with it
+    descendants: Vec<Txid>,
+}
+
+impl Replacement {
+    fn check(&self, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let input_value = self.inputs.iter().map(|(_, txout)| txout.value).sum();
+        checks::check_spend(input_value, &self.outputs, self.weight, force)
+    }
+}
+
+/// Replaces our unconfirmed transaction `txid`, spent from the wpkh or tr wallet of the key at
+/// `key_path`, with one paying `fee_rate`, and signs it.
+#[allow(clippy::too_many_arguments)]
+pub fn bump_fee_from_key(
+    secp: &Secp256k1<All>,
+    key_path: &Path,
+    wallet_store_path: &Path,
+    txid: &str,
+    fee_rate: FeeRate,
+    network: Network,
+    timelocks: &Timelocks,
+    force: bool,
+    electrum_client: &Client,
+    rng: &mut dyn RngCore,
+    broadcaster: Option<&Broadcaster>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = keys::read_key(key_path, network)?;
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let (original, prevouts) = load_original(&store, txid, electrum_client)?;
+
+    // Whichever of the key's wallets spent the original signs the replacement
+    let first_input = prevouts.first().ok_or("The transaction has no inputs")?;
+    let (script_type, wallet) = find_wallet(secp, &key, &store, &first_input.script_pubkey)?
+        .ok_or("The transaction does not spend from this key's wpkh or tr wallet")?;
+    let replacement = plan_replacement(
+        &wallet,
+        &mut store,
+        &original,
+        prevouts,
+        fee_rate,
+        electrum_client,
+    )?;
+
+    replacement.check(force)?;
+
+    let tx = sign(
+        secp,
+        &key,
+        script_type,
+        &wallet,
+        &store,
+        replacement.inputs,
+        replacement.outputs,
+        timelocks,
+        rng,
+    )?;
+    store.save()?;
+
+    println!(
+        "Signed tx: {}",
+        consensus::serialize(&tx).to_hex_string(Case::Lower)
+    );
+    if let Some(broadcaster) = broadcaster {
+        broadcaster.broadcast(&tx)?;
+
+        // The original and its descendants can never confirm now, so `rebroadcast` shouldn't try
+        // to resend them
+        let mut store = WalletStore::load(wallet_store_path)?;
+        store.remove_transaction(&original.txid());
+        for txid in &replacement.descendants {
+            store.remove_transaction(txid);
+        }
+        store.save()?;
+    }
+
+    Ok(())
+}
+
+/// Replaces our unconfirmed transaction `txid`, spent from a watch-only wallet, with one paying
+/// `fee_rate`, printing an unsigned PSBT for its signers.
+pub fn bump_fee_from_wallet(
+    wallet: &Wallet,
+    wallet_store_path: &Path,
+    txid: &str,
+    fee_rate: FeeRate,
+    timelocks: &Timelocks,
+    force: bool,
+    electrum_client: &Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let (original, prevouts) = load_original(&store, txid, electrum_client)?;
+    let replacement = plan_replacement(
+        wallet,
+        &mut store,
+        &original,
+        prevouts,
+        fee_rate,
+        electrum_client,
+    )?;
+
+    replacement.check(force)?;
+
+    let psbt = watch::create_psbt_internal(
+        wallet,
+        &store,
+        &replacement.inputs,
+        replacement.outputs,
+        timelocks,
+    )?;
+    store.save()?;
+
+    println!("Psbt: {}", psbt.serialize_hex());
+
+    Ok(())
+}
+
+/// Speeds up the unconfirmed transaction that created `outpoint`, one of the outputs of the
+/// key's wpkh or tr wallet, by spending it back to ourselves in a child transaction paying
+/// enough for the two together to reach `target_fee_rate`.
+#[allow(clippy::too_many_arguments)]
+pub fn cpfp(
+    secp: &Secp256k1<All>,
+    key_path: &Path,
+    wallet_store_path: &Path,
+    outpoint: &str,
+    target_fee_rate: FeeRate,
+    network: Network,
+    timelocks: &Timelocks,
+    force: bool,
+    electrum_client: &Client,
+    rng: &mut dyn RngCore,
+    broadcaster: Option<&Broadcaster>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let outpoint = OutPoint::from_str(outpoint)
+        .map_err(|e| format!("Invalid outpoint {}: {}", outpoint, e))?;
+    let parent = electrum_client.transaction_get(&outpoint.txid)?;
+    let txout = parent
+        .output
+        .get(outpoint.vout as usize)
+        .ok_or("Invalid vout")?
+        .clone();
+    if let TxStatus::Confirmed { height } = broadcast::tx_status(electrum_client, &parent)? {
+        return Err(format!("{} already confirmed at height {}", parent.txid(), height).into());
+    }
+
+    // The parent's fee needs all of its inputs, which may well not be ours
+    let prevouts = parent
+        .input
+        .iter()
+        .map(|input| input.previous_output)
+        .collect::<Vec<_>>();
+    let input_value = utxos::get_txouts(electrum_client, &prevouts)?
+        .iter()
+        .map(|txout| txout.value)
+        .sum::<Amount>();
+    let output_value = parent.output.iter().map(|txout| txout.value).sum();
+    let parent_fee = input_value
+        .checked_sub(output_value)
+        .ok_or("The parent pays out more than its inputs hold")?;
+    println!(
+        "Parent {}: fee {} sat at {}",
+        parent.txid(),
+        parent_fee.to_sat(),
+        fees::format_fee_rate(fee_rate_of(parent_fee, parent.weight()))
+    );
+
+    let key = keys::read_key(key_path, network)?;
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let (script_type, wallet) = find_wallet(secp, &key, &store, &txout.script_pubkey)?
+        .ok_or("The output does not pay this key's wpkh or tr wallet")?;
+
+    let mut change = new_change_output(&wallet, &store)?;
+    let weight = fees::base_weight(slice::from_ref(&change))
+        + fees::input_weight(wallet.descriptor(Keychain::External)?)?;
+    let fee = child_fee(parent_fee, parent.weight(), weight, target_fee_rate).ok_or(format!(
+        "The parent already pays {}",
+        fees::format_fee_rate(fee_rate_of(parent_fee, parent.weight()))
+    ))?;
+    change.value = txout.value.checked_sub(fee).ok_or(format!(
+        "The {} sat output can't cover the {} sat the child must pay",
+        txout.value.to_sat(),
+        fee.to_sat()
+    ))?;
+    store.reveal_next(&wallet, wallet.change_keychain());
+
+    println!(
+        "Child: {} sat back to {}",
+        change.value.to_sat(),
+        bitcoin::Address::from_script(&change.script_pubkey, network)?
+    );
+    println!(
+        "Fee: {} sat at {}, {} for the package",
+        fee.to_sat(),
+        fees::format_fee_rate(fee_rate_of(fee, weight)),
+        fees::format_fee_rate(fee_rate_of(parent_fee + fee, parent.weight() + weight))
+    );
+
+    let outputs = vec![change];
+    checks::check_spend(txout.value, &outputs, weight, force)?;
+    let tx = sign(
+        secp,
+        &key,
+        script_type,
+        &wallet,
+        &store,
+        vec![(outpoint, txout)],
+        outputs,
+        timelocks,
+        rng,
+    )?;
+    store.save()?;
+
+    println!(
+        "Signed tx: {}",
+        consensus::serialize(&tx).to_hex_string(Case::Lower)
+    );
+    if let Some(broadcaster) = broadcaster {
+        broadcaster.broadcast(&tx)?;
+    }
+
+    Ok(())
+}
+
+/// Signs `inputs`, all from the `script_type` wallet of `key`, with that wallet's signer.
+#[allow(clippy::too_many_arguments)]
+fn sign(
+    secp: &Secp256k1<All>,
+    key: &StoredKey,
+    script_type: ScriptType,
+    wallet: &Wallet,
+    store: &WalletStore,
+    inputs: Vec<(OutPoint, TxOut)>,
+    outputs: Vec<TxOut>,
+    timelocks: &Timelocks,
+    rng: &mut dyn RngCore,
+) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let mut signing_inputs = Vec::with_capacity(inputs.len());
+    for (outpoint, txout) in inputs {
+        let (keychain, index) = wallet
+            .derivation_of(store, &txout.script_pubkey)?
+            .ok_or("An input does not belong to the wallet")?;
+        let private_key = keys::signing_key(secp, key, script_type, keychain, index)?;
+        signing_inputs.push((outpoint, txout, private_key));
+    }
+
+    match script_type {
+        ScriptType::Tr => tr::keyspend::create_transaction_internal(
+            secp,
+            &signing_inputs,
+            outputs,
+            timelocks,
+            rng,
+        ),
+        _ => wpkh::create_transaction_internal(secp, &signing_inputs, outputs, timelocks),
+    }
+}
+
+/// Looks up the transaction to replace in the wallet database, along with the outputs it spends.
+fn load_original(
+    store: &WalletStore,
+    txid: &str,
+    electrum_client: &Client,
+) -> Result<(Transaction, Vec<TxOut>), Box<dyn std::error::Error>> {
+    let txid = Txid::from_str(txid)?;
+    let (original, height) = store.transaction(&txid)?.ok_or(format!(
+        "{} is not in the wallet database. Only transactions we sent can be bumped",
+        txid
+    ))?;
+    if let Some(height) = height {
+        return Err(format!("{} already confirmed at height {}", txid, height).into());
+    }
+    let prevouts = original
+        .input
+        .iter()
+        .map(|input| input.previous_output)
+        .collect::<Vec<_>>();
+    let prevouts = utxos::get_txouts(electrum_client, &prevouts)?;
+
+    Ok((original, prevouts))
+}
+
+/// The key's wpkh or tr wallet, whichever `script_pubkey` belongs to.
+fn find_wallet(
+    secp: &Secp256k1<All>,
+    key: &StoredKey,
+    store: &WalletStore,
+    script_pubkey: &Script,
+) -> Result<Option<(ScriptType, Wallet)>, Box<dyn std::error::Error>> {
+    for script_type in [ScriptType::Wpkh, ScriptType::Tr] {
+        let wallet = Wallet::for_key(secp, key, script_type)?;
+        if wallet.derivation_of(store, script_pubkey)?.is_some() {
+            return Ok(Some((script_type, wallet)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Works out a replacement of `original` paying `fee_rate`. The extra fee comes out of the change
+/// output, and when that is not enough more of the wallet's confirmed coins are added. Outputs
+/// to any other address, our receive addresses included, are kept as they are.
+fn plan_replacement(
+    wallet: &Wallet,
+    store: &mut WalletStore,
+    original: &Transaction,
+    prevouts: Vec<TxOut>,
+    fee_rate: FeeRate,
+    electrum_client: &Client,
+) -> Result<Replacement, Box<dyn std::error::Error>> {
+    for prevout in &prevouts {
+        if wallet
+            .derivation_of(store, &prevout.script_pubkey)?
+            .is_none()
+        {
+            return Err(
+                "Some inputs are not from this wallet, so it can't sign a replacement".into(),
+            );
+        }
+    }
+    let input_value = prevouts.iter().map(|txout| txout.value).sum::<Amount>();
+    let output_value = original.output.iter().map(|txout| txout.value).sum();
+    let original_fee = input_value
+        .checked_sub(output_value)
+        .ok_or("The transaction pays out more than its inputs hold")?;
+    println!(
+        "Replacing {}: fee {} sat at {}",
+        original.txid(),
+        original_fee.to_sat(),
+        fees::format_fee_rate(fee_rate_of(original_fee, original.weight()))
+    );
+    let (descendants, descendant_fee) = find_descendants(original, electrum_client)?;
+    if !descendants.is_empty() {
+        println!(
+            "Evicting {} unconfirmed descendant(s) paying {} sat",
+            descendants.len(),
+            descendant_fee.to_sat()
+        );
+    }
+
+    // Only change can shrink: a payment to one of our receive addresses is still a payment,
+    // unless the wallet has no change keychain and sends its change there too
+    let mut change_position = None;
+    for (position, output) in original.output.iter().enumerate() {
+        let derivation = wallet.derivation_of(store, &output.script_pubkey)?;
+        if matches!(derivation, Some((keychain, _)) if keychain == wallet.change_keychain()) {
+            change_position = Some(position);
+            break;
+        }
+    }
+
+    let mut outputs = original.output.clone();
+    let original_change = change_position.map(|position| outputs.remove(position));
+    let payment_value = outputs.iter().map(|txout| txout.value).sum::<Amount>();
+
+    let mut inputs = original
+        .input
+        .iter()
+        .map(|input| input.previous_output)
+        .zip(prevouts)
+        .collect::<Vec<_>>();
+    // Only confirmed coins can be added: BIP125 forbids new unconfirmed inputs
+    let mut extra_coins = utxos::list_unspent(wallet, store, electrum_client)?
+        .into_iter()
+        .filter(|utxo| utxo.height.is_some())
+        .filter(|utxo| {
+            !inputs
+                .iter()
+                .any(|(outpoint, _)| *outpoint == utxo.outpoint)
+        })
+        .collect::<Vec<_>>();
+    extra_coins.sort_by_key(|utxo| utxo.txout.value);
+    let mut added = Vec::new();
+
+    let input_weight = fees::input_weight(wallet.descriptor(Keychain::External)?)?;
+    let (change, fee, weight) = loop {
+        let input_value = inputs.iter().map(|(_, txout)| txout.value).sum::<Amount>();
+        let spendable = input_value
+            .checked_sub(payment_value)
+            .unwrap_or(Amount::ZERO);
+        let inputs_weight = input_weight * inputs.len() as u64;
+
+        // Keep (or add) a change output if what is left over is worth one
+        let change_output = match &original_change {
+            Some(change) => change.clone(),
+            None => new_change_output(wallet, store)?,
+        };
+        let weight =
+            fees::base_weight(&outputs) + fees::output_weight(&change_output) + inputs_weight;
+        let fee = fees::fee_for(fee_rate, weight);
+        if spendable >= fee + change_output.script_pubkey.dust_value() {
+            let change = TxOut {
+                value: spendable - fee,
+                ..change_output
+            };
+            break (Some(change), fee, weight);
+        }
+
+        // Otherwise the change goes to the fee, as long as it covers it
+        let weight = fees::base_weight(&outputs) + inputs_weight;
+        if spendable >= fees::fee_for(fee_rate, weight) {
+            break (None, spendable, weight);
+        }
+
+        let coin = extra_coins.pop().ok_or(format!(
+            "The wallet has no more confirmed coins to pay a fee of {}",
+            fees::format_fee_rate(fee_rate)
+        ))?;
+        inputs.push((coin.outpoint, coin.txout.clone()));
+        added.push(coin);
+    };
+
+    check_replacement(
+        original,
+        original_fee,
+        descendants.len(),
+        descendant_fee,
+        fee,
+        weight,
+    )?;
+
+    // Payments keep their order, and the change its place if there was some
+    if let Some(change) = &change {
+        if change_position.is_none() {
+            store.reveal_next(wallet, wallet.change_keychain());
+        }
+        let position = change_position.unwrap_or(outputs.len());
+        outputs.insert(position, change.clone());
+    }
+
+    print_replacement(
+        wallet,
+        &added,
+        change.as_ref(),
+        fee,
+        fee_rate_of(fee, weight),
+    )?;
+
+    Ok(Replacement {
+        inputs,
+        outputs,
+        weight,
+        descendants,
+    })
+}
+
+/// The unconfirmed transactions spending `original`'s outputs, and theirs in turn, with the fees
+/// they pay altogether. Electrum has no descendants lookup, so they are found in the histories of
+/// the outputs' scripts.
+fn find_descendants(
+    original: &Transaction,
+    electrum_client: &Client,
+) -> Result<(Vec<Txid>, Amount), Box<dyn std::error::Error>> {
+    let mut descendants = Vec::new();
+    let mut fee = Amount::ZERO;
+    let mut parents = vec![original.clone()];
+    while let Some(parent) = parents.pop() {
+        let parent_txid = parent.txid();
+        let histories = electrum_client
+            .batch_script_get_history(parent.output.iter().map(|txout| &*txout.script_pubkey))?;
+        for entry in histories.into_iter().flatten() {
+            // Mempool entries have a height of 0, or -1 when they have unconfirmed inputs
+            if entry.height > 0
+                || entry.tx_hash == original.txid()
+                || descendants.contains(&entry.tx_hash)
+            {
+                continue;
+            }
+            let tx = electrum_client.transaction_get(&entry.tx_hash)?;
+            if !tx
+                .input
+                .iter()
+                .any(|input| input.previous_output.txid == parent_txid)
+            {
+                continue;
+            }
+
+            let prevouts = tx
+                .input
+                .iter()
+                .map(|input| input.previous_output)
+                .collect::<Vec<_>>();
+            let input_value = utxos::get_txouts(electrum_client, &prevouts)?
+                .iter()
+                .map(|txout| txout.value)
+                .sum::<Amount>();
+            let output_value = tx.output.iter().map(|txout| txout.value).sum();
+            fee += input_value
+                .checked_sub(output_value)
+                .ok_or(format!("{} pays out more than its inputs hold", tx.txid()))?;
+            descendants.push(entry.tx_hash);
+            parents.push(tx);
+        }
+    }
+
+    Ok((descendants, fee))
+}
+
+/// An empty output to the next unused change address, not yet revealed.
+fn new_change_output(
+    wallet: &Wallet,
+    store: &WalletStore,
+) -> Result<TxOut, Box<dyn std::error::Error>> {
+    let keychain = wallet.change_keychain();
+    let index = store
+        .last_index(&wallet.id(), keychain)
+        .map_or(0, |i| i + 1);
+
+    Ok(TxOut {
+        value: Amount::ZERO,
+        script_pubkey: wallet.address(keychain, index)?.script_pubkey(),
+    })
+}
+
+fn fee_rate_of(fee: Amount, weight: Weight) -> FeeRate {
+    FeeRate::from_sat_per_kwu(fee.to_sat() * 1000 / weight.to_wu())
+}
+
+/// Checks the BIP125 rules a node applies before letting a replacement evict `original`: the
+/// original must signal replaceability, and the replacement must pay a higher fee rate, at
+/// least the fees of the original and the `descendant_fee` of everything spending from it, and
+/// on top of that the incremental relay fee for its own size. Together with its `descendants`
+/// it may not evict more than 100 transactions. Rule 2, no new unconfirmed inputs, holds because
+/// only confirmed coins are ever added.
+fn check_replacement(
+    original: &Transaction,
+    original_fee: Amount,
+    descendants: usize,
+    descendant_fee: Amount,
+    fee: Amount,
+    weight: Weight,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !original.is_explicitly_rbf() {
+        return Err(format!(
+            "{} does not signal replaceability (BIP125), so nodes won't replace it",
+            original.txid()
+        )
+        .into());
+    }
+    if 1 + descendants > MAX_REPLACED_TRANSACTIONS {
+        return Err(format!(
+            "Replacing {} would evict {} unconfirmed descendants, more than the {} transactions \
+             nodes replace at once (BIP125)",
+            original.txid(),
+            descendants,
+            MAX_REPLACED_TRANSACTIONS
+        )
+        .into());
+    }
+
+    let min_fee = original_fee + descendant_fee + fees::fee_for(INCREMENTAL_RELAY_FEE, weight);
+    let original_fee_rate = fee_rate_of(original_fee, original.weight());
+    if fee < min_fee || fee_rate_of(fee, weight) <= original_fee_rate {
+        return Err(format!(
+            "A fee of {} sat at {} is too low to replace a fee of {} sat at {} and {} sat of \
+             descendants: the replacement must pay at least {} sat and a higher fee rate",
+            fee.to_sat(),
+            fees::format_fee_rate(fee_rate_of(fee, weight)),
+            original_fee.to_sat(),
+            fees::format_fee_rate(original_fee_rate),
+            descendant_fee.to_sat(),
+            min_fee.to_sat()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// What a child weighing `child_weight` must pay for it and its parent together to reach
+/// `target_fee_rate`, or `None` when the parent already pays that much on its own.
+fn child_fee(
+    parent_fee: Amount,
+    parent_weight: Weight,
+    child_weight: Weight,
+    target_fee_rate: FeeRate,
+) -> Option<Amount> {
+    if fees::fee_for(target_fee_rate, parent_weight) <= parent_fee {
+        return None;
+    }
+
+    Some(fees::fee_for(target_fee_rate, parent_weight + child_weight) - parent_fee)
+}
+
+fn print_replacement(
+    wallet: &Wallet,
+    added: &[Utxo],
+    change: Option<&TxOut>,
+    fee: Amount,
+    fee_rate: FeeRate,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for utxo in added {
+        println!(
+            "Added input: {} {} sat ({} index {})",
+            utxo.outpoint,
+            utxo.txout.value.to_sat(),
+            utxo.keychain.name(),
+            utxo.index
+        );
+    }
+    match change {
+        Some(change) => println!(
+            "Change: {} sat to {}",
+            change.value.to_sat(),
+            bitcoin::Address::from_script(&change.script_pubkey, wallet.network())?
+        ),
+        None => println!("Change: none, what was left went to the fee"),
+    }
+    println!(
+        "Fee: {} sat at {}",
+        fee.to_sat(),
+        fees::format_fee_rate(fee_rate)
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute::LockTime, transaction::Version, Amount, FeeRate, OutPoint, ScriptBuf, Sequence,
+        Transaction, TxIn, TxOut, Weight, Witness,
+    };
+
+    use super::{check_replacement, child_fee};
+
+    fn transaction(sequence: Sequence) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_check_replacement() {
+        let original = transaction(Sequence::ENABLE_RBF_NO_LOCKTIME);
+        let original_fee = Amount::from_sat(original.weight().to_vbytes_ceil());
+        let weight = original.weight() + Weight::from_vb_unchecked(10);
+        let vsize = weight.to_vbytes_ceil();
+
+        // The fee must grow by at least 1 sat/vB of the replacement's size
+        let min_fee = original_fee + Amount::from_sat(vsize);
+        assert!(
+            check_replacement(&original, original_fee, 0, Amount::ZERO, min_fee, weight).is_ok()
+        );
+        assert!(check_replacement(
+            &original,
+            original_fee,
+            0,
+            Amount::ZERO,
+            min_fee - Amount::from_sat(1),
+            weight
+        )
+        .is_err());
+
+        // Evicting a child means paying its fee as well
+        let child_fee = Amount::from_sat(1_000);
+        assert!(check_replacement(&original, original_fee, 0, child_fee, min_fee, weight).is_err());
+        assert!(check_replacement(
+            &original,
+            original_fee,
+            0,
+            child_fee,
+            min_fee + child_fee,
+            weight
+        )
+        .is_ok());
+
+        // A bigger replacement can pay a higher fee at a lower fee rate
+        let much_bigger = original.weight() * 4;
+        assert!(check_replacement(
+            &original,
+            original_fee * 3,
+            0,
+            Amount::ZERO,
+            original_fee * 3 + Amount::from_sat(much_bigger.to_vbytes_ceil()),
+            much_bigger
+        )
+        .is_err());
+
+        // Nodes evict at most 100 transactions, the original included
+        assert!(check_replacement(
+            &original,
+            original_fee,
+            99,
+            child_fee,
+            min_fee + child_fee,
+            weight
+        )
+        .is_ok());
+        assert!(check_replacement(
+            &original,
+            original_fee,
+            100,
+            child_fee,
+            min_fee + child_fee,
+            weight
+        )
+        .is_err());
+
+        // Transactions that don't signal can't be replaced at all
+        let final_tx = transaction(Sequence::MAX);
+        assert!(check_replacement(
+            &final_tx,
+            original_fee,
+            0,
+            Amount::ZERO,
+            min_fee * 10,
+            weight
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_child_fee() {
+        let target = FeeRate::from_sat_per_vb_unchecked(10);
+        let parent_weight = Weight::from_vb_unchecked(200);
+        let child_weight = Weight::from_vb_unchecked(110);
+
+        // A parent at 2 sat/vB leaves the child 8 sat/vB of the parent's size to make up
+        let fee = child_fee(Amount::from_sat(400), parent_weight, child_weight, target);
+        assert_eq!(fee, Some(Amount::from_sat(3100 - 400)));
+
+        // Nearly there, the child pays a little more than the target rate for itself
+        let fee = child_fee(Amount::from_sat(1990), parent_weight, child_weight, target);
+        assert_eq!(fee, Some(Amount::from_sat(1110)));
+
+        assert_eq!(
+            child_fee(Amount::from_sat(2000), parent_weight, child_weight, target),
+            None
+        );
+    }
+}
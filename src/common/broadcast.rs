@@ -0,0 +1,243 @@
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoin::{Transaction, Txid};
+use electrum_client::{Client, ElectrumApi, GetHistoryRes};
+
+use crate::common::{utxos, wallet::WalletStore};
+
+/// How often to check on a transaction while waiting for it to confirm.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many polls in a row a just-broadcast transaction may stay unknown to the server before
+/// we conclude it never propagated.
+const MAX_UNKNOWN_POLLS: u32 = 12;
+
+/// Where a transaction stands, as far as the Electrum server knows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxStatus {
+    Confirmed {
+        height: u32,
+    },
+    Mempool,
+    /// Never seen, or dropped from the mempool
+    Unknown,
+}
+
+impl TxStatus {
+    pub fn confirmations(&self, tip_height: u32) -> u32 {
+        match self {
+            TxStatus::Confirmed { height } => tip_height.saturating_sub(*height) + 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Submits signed transactions through Electrum and keeps track of them until they confirm.
+pub struct Broadcaster<'a> {
+    electrum_client: &'a Client,
+    wallet_store_path: PathBuf,
+    /// Confirmations to wait for after broadcasting, if any
+    wait_confirm: Option<u32>,
+    /// How long to wait for them before giving up
+    wait_timeout: Duration,
+}
+
+impl<'a> Broadcaster<'a> {
+    pub fn new(
+        electrum_client: &'a Client,
+        wallet_store_path: PathBuf,
+        wait_confirm: Option<u32>,
+        wait_timeout: Duration,
+    ) -> Self {
+        Broadcaster {
+            electrum_client,
+            wallet_store_path,
+            wait_confirm,
+            wait_timeout,
+        }
+    }
+
+    pub fn broadcast(&self, tx: &Transaction) -> Result<(), Box<dyn std::error::Error>> {
+        let txid = self.electrum_client.transaction_broadcast(tx)?;
+        println!("Broadcast: {}", txid);
+
+        // Recorded as unconfirmed, so `rebroadcast` can resend it if it gets dropped
+        let mut store = WalletStore::load(&self.wallet_store_path)?;
+        store.add_transaction(tx, None);
+        store.save()?;
+
+        if let Some(confirmations) = self.wait_confirm {
+            let status =
+                wait_for_confirmations(self.electrum_client, tx, confirmations, self.wait_timeout)?;
+            if let TxStatus::Confirmed { height } = status {
+                let mut store = WalletStore::load(&self.wallet_store_path)?;
+                store.set_transaction_height(&txid, Some(height));
+                store.save()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Looks `tx` up in the history of its first output's script.
+pub fn tx_status(
+    electrum_client: &Client,
+    tx: &Transaction,
+) -> Result<TxStatus, Box<dyn std::error::Error>> {
+    let output = tx.output.first().ok_or("Transaction has no outputs")?;
+    let history = electrum_client.script_get_history(&output.script_pubkey)?;
+
+    Ok(status_in_history(&tx.txid(), &history))
+}
+
+fn status_in_history(txid: &Txid, history: &[GetHistoryRes]) -> TxStatus {
+    // Unconfirmed entries have a height of 0, or -1 when they spend other unconfirmed ones
+    match history.iter().find(|entry| entry.tx_hash == *txid) {
+        Some(entry) if entry.height > 0 => TxStatus::Confirmed {
+            height: entry.height as u32,
+        },
+        Some(_) => TxStatus::Mempool,
+        None => TxStatus::Unknown,
+    }
+}
+
+/// Polls until `tx` has `confirmations` confirmations, reporting each new block. Returns the
+/// last status seen. Fails after `timeout`, or once the transaction is dropped or never shows up.
+pub fn wait_for_confirmations(
+    electrum_client: &Client,
+    tx: &Transaction,
+    confirmations: u32,
+    timeout: Duration,
+) -> Result<TxStatus, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let mut last_seen = None;
+    let mut seen = false;
+    let mut unknown_polls = 0;
+    loop {
+        let tip_height = utxos::tip_height(electrum_client)?;
+        let status = tx_status(electrum_client, tx)?;
+        unknown_polls = match status {
+            TxStatus::Unknown => unknown_polls + 1,
+            _ => 0,
+        };
+        check_still_known(seen, unknown_polls).map_err(|e| format!("{}: {}", tx.txid(), e))?;
+        seen |= status != TxStatus::Unknown;
+
+        let confirmed = status.confirmations(tip_height);
+        if last_seen != Some(confirmed) {
+            println!(
+                "{}: {}/{} confirmation(s)",
+                tx.txid(),
+                confirmed,
+                confirmations
+            );
+            last_seen = Some(confirmed);
+        }
+        if confirmed >= confirmations {
+            return Ok(status);
+        }
+        if started.elapsed() >= timeout {
+            return Err(format!(
+                "{}: gave up after {} minute(s) at {}/{} confirmation(s). It is still in the \
+                 wallet database, so `rebroadcast` can check on it later",
+                tx.txid(),
+                timeout.as_secs() / 60,
+                confirmed,
+                confirmations
+            )
+            .into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Fails when the server has lost track of a transaction it had `seen`, or after it has stayed
+/// unknown for `unknown_polls` polls in a row since the broadcast.
+fn check_still_known(seen: bool, unknown_polls: u32) -> Result<(), String> {
+    match (seen, unknown_polls) {
+        (_, 0) => Ok(()),
+        (true, _) => Err(
+            "dropped out of the mempool, evicted or replaced. `rebroadcast` can resend it".into(),
+        ),
+        (false, polls) if polls >= MAX_UNKNOWN_POLLS => {
+            Err("never reached the server's mempool".into())
+        }
+        (false, _) => Ok(()),
+    }
+}
+
+/// Resends every transaction of ours that has not confirmed yet, and marks the ones that have.
+pub fn rebroadcast(
+    wallet_store_path: &Path,
+    electrum_client: &Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let transactions = store.unconfirmed_transactions()?;
+    if transactions.is_empty() {
+        println!("No unconfirmed transactions");
+    }
+
+    for tx in transactions {
+        let txid = tx.txid();
+        match tx_status(electrum_client, &tx)? {
+            TxStatus::Confirmed { height } => {
+                println!("{}: confirmed at height {}", txid, height);
+                store.set_transaction_height(&txid, Some(height));
+            }
+            status => match electrum_client.transaction_broadcast(&tx) {
+                Ok(_) if status == TxStatus::Mempool => {
+                    println!("{}: still in the mempool, rebroadcast", txid)
+                }
+                Ok(_) => println!("{}: rebroadcast", txid),
+                // A double spend or an evicted parent can make a transaction invalid for good
+                Err(e) => println!("{}: rejected: {}", txid, e),
+            },
+        }
+    }
+    store.save()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Txid;
+    use electrum_client::GetHistoryRes;
+
+    use super::{check_still_known, status_in_history, TxStatus, MAX_UNKNOWN_POLLS};
+
+    #[test]
+    fn test_tx_status() {
+        let txid = |byte: u8| {
+            format!("{:02x}", byte)
+                .repeat(32)
+                .parse::<Txid>()
+                .expect("Txid")
+        };
+        let entry = |byte, height| GetHistoryRes {
+            height,
+            tx_hash: txid(byte),
+            fee: None,
+        };
+        let history = [entry(1, 100), entry(2, 0), entry(3, -1)];
+
+        let confirmed = status_in_history(&txid(1), &history);
+        assert_eq!(confirmed, TxStatus::Confirmed { height: 100 });
+        assert_eq!(status_in_history(&txid(2), &history), TxStatus::Mempool);
+        assert_eq!(status_in_history(&txid(3), &history), TxStatus::Mempool);
+        assert_eq!(status_in_history(&txid(4), &history), TxStatus::Unknown);
+
+        // The confirming block counts as the first confirmation
+        assert_eq!(confirmed.confirmations(100), 1);
+        assert_eq!(confirmed.confirmations(105), 6);
+        assert_eq!(TxStatus::Mempool.confirmations(105), 0);
+
+        assert!(check_still_known(false, MAX_UNKNOWN_POLLS - 1).is_ok());
+        assert!(check_still_known(false, MAX_UNKNOWN_POLLS).is_err());
+        assert!(check_still_known(true, 0).is_ok());
+        assert!(check_still_known(true, 1).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use bitcoin::{Amount, FeeRate, TxOut, Weight};
+
+use crate::common::fees;
+
+/// Fee rates above this are almost certainly a mistake, such as an amount missing a few digits.
+pub const MAX_FEE_RATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(500);
+
+/// Checks a spend before it is signed: the outputs must not pay more than the inputs hold, none
+/// of them may be dust, and the fee must stay under `MAX_FEE_RATE`. `weight` is what the signed
+/// transaction will weigh. With `force`, dust and fee problems are only warned about, but an
+/// overspend is invalid by consensus and always fails.
+pub fn check_spend(
+    input_value: Amount,
+    outputs: &[TxOut],
+    weight: Weight,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let output_value: Amount = outputs.iter().map(|output| output.value).sum();
+    if output_value > input_value {
+        return Err(format!(
+            "The outputs pay {} sat but the inputs only hold {} sat",
+            output_value.to_sat(),
+            input_value.to_sat()
+        )
+        .into());
+    }
+
+    report(spend_problems(input_value, outputs, weight), force)
+}
+
+/// Fails with every problem found, or with `force` only warns about them.
+pub fn report(problems: Vec<String>, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    if force {
+        for problem in &problems {
+            eprintln!("Warning: {}", problem);
+        }
+        return Ok(());
+    }
+    Err(format!("{}. Pass --force to sign anyway", problems.join(". ")).into())
+}
+
+fn spend_problems(input_value: Amount, outputs: &[TxOut], weight: Weight) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (index, output) in outputs.iter().enumerate() {
+        let dust_limit = output.script_pubkey.dust_value();
+        if output.value < dust_limit {
+            problems.push(format!(
+                "Output {} pays {} sat, below its dust limit of {} sat",
+                index,
+                output.value.to_sat(),
+                dust_limit.to_sat()
+            ));
+        }
+    }
+
+    // check_spend has made sure the outputs don't overspend
+    let output_value: Amount = outputs.iter().map(|output| output.value).sum();
+    let fee = input_value - output_value;
+    let fee_rate = FeeRate::from_sat_per_kwu(fee.to_sat() * 1000 / weight.to_wu());
+    if fee_rate > MAX_FEE_RATE {
+        problems.push(format!(
+            "The fee of {} sat pays {}, above the {} ceiling",
+            fee.to_sat(),
+            fees::format_fee_rate(fee_rate),
+            fees::format_fee_rate(MAX_FEE_RATE)
+        ));
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{Address, Amount, TxOut, Weight};
+
+    use super::{check_spend, spend_problems};
+
+    #[test]
+    fn test_spend_problems() {
+        let script_pubkey = Address::from_str("bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu")
+            .expect("Unable to parse address")
+            .assume_checked()
+            .script_pubkey();
+        let output = |value| TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: script_pubkey.clone(),
+        };
+        // One P2WPKH input and output
+        let weight = Weight::from_wu(437);
+
+        assert!(spend_problems(Amount::from_sat(100_000), &[output(99_000)], weight).is_empty());
+
+        // Not even --force signs an overspend
+        let overspend = check_spend(Amount::from_sat(100_000), &[output(100_001)], weight, true);
+        assert!(overspend.unwrap_err().to_string().contains("only hold"));
+        assert!(check_spend(Amount::from_sat(100_000), &[output(293)], weight, true).is_ok());
+
+        let problems = spend_problems(Amount::from_sat(100_000), &[output(293)], weight);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("dust"));
+        assert!(problems[1].contains("ceiling"));
+    }
+}
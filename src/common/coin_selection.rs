@@ -0,0 +1,293 @@
+use std::str::FromStr;
+
+use bitcoin::secp256k1::rand::{seq::SliceRandom, RngCore};
+use bitcoin::{Amount, FeeRate, Weight};
+
+use crate::common::{fees::fee_for, utxos::Utxo};
+
+/// Branch-and-bound gives up after this many steps, like Bitcoin Core.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// How a spend picks its inputs from the wallet's UTXOs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoinSelection {
+    /// Searches for inputs that cover the payment closely enough to skip the change output
+    BranchAndBound,
+    LargestFirst,
+    SingleRandomDraw,
+}
+
+impl CoinSelection {
+    pub const ALL: [CoinSelection; 3] = [
+        CoinSelection::BranchAndBound,
+        CoinSelection::LargestFirst,
+        CoinSelection::SingleRandomDraw,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoinSelection::BranchAndBound => "bnb",
+            CoinSelection::LargestFirst => "largest-first",
+            CoinSelection::SingleRandomDraw => "srd",
+        }
+    }
+}
+
+impl FromStr for CoinSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CoinSelection::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == s)
+            .ok_or(format!("Unknown coin selection algorithm `{}`", s))
+    }
+}
+
+/// What a spend costs on top of the payment itself.
+pub struct SelectionParams {
+    pub fee_rate: FeeRate,
+    /// Weight of the transaction with its payment outputs but no inputs and no change
+    pub base_weight: Weight,
+    /// Weight each input adds, witness included
+    pub input_weight: Weight,
+    /// Weight the change output adds
+    pub change_weight: Weight,
+    /// Smallest change worth creating an output for. Anything less goes to the fee
+    pub min_change: Amount,
+}
+
+impl SelectionParams {
+    fn fee(&self, inputs: usize, change: bool) -> Amount {
+        let mut weight = self.base_weight + self.input_weight * inputs as u64;
+        if change {
+            weight += self.change_weight;
+        }
+        fee_for(self.fee_rate, weight)
+    }
+}
+
+pub struct Selection {
+    pub utxos: Vec<Utxo>,
+    pub fee: Amount,
+    pub change: Option<Amount>,
+    /// The algorithm that made the selection, since branch-and-bound falls back to a single
+    /// random draw when no changeless selection exists
+    pub algorithm: CoinSelection,
+}
+
+/// Picks inputs paying `amount` plus fees out of `utxos`.
+pub fn select_coins(
+    utxos: Vec<Utxo>,
+    amount: Amount,
+    params: &SelectionParams,
+    algorithm: CoinSelection,
+    rng: &mut dyn RngCore,
+) -> Result<Selection, Box<dyn std::error::Error>> {
+    let input_fee = fee_for(params.fee_rate, params.input_weight);
+    // Outputs that cost more to spend than they are worth would only add to the fee
+    let mut utxos = utxos
+        .into_iter()
+        .filter(|utxo| utxo.txout.value > input_fee)
+        .collect::<Vec<_>>();
+    let target = amount + params.fee(0, false);
+    let change_cost = fee_for(params.fee_rate, params.change_weight);
+
+    let mut algorithm = algorithm;
+    if algorithm == CoinSelection::BranchAndBound {
+        utxos.sort_by_key(|utxo| std::cmp::Reverse(utxo.txout.value));
+        let effective_values = utxos
+            .iter()
+            .map(|utxo| (utxo.txout.value - input_fee).to_sat())
+            .collect::<Vec<_>>();
+        // A match within the cost of creating and later spending a change output beats having one
+        let cost_of_change = change_cost + input_fee;
+        match branch_and_bound(&effective_values, target.to_sat(), cost_of_change.to_sat()) {
+            Some(indices) => {
+                let selected = indices.into_iter().map(|i| utxos[i].clone()).collect();
+                return finish(selected, amount, params, algorithm);
+            }
+            None => algorithm = CoinSelection::SingleRandomDraw,
+        }
+    }
+
+    match algorithm {
+        CoinSelection::LargestFirst => {
+            utxos.sort_by_key(|utxo| std::cmp::Reverse(utxo.txout.value))
+        }
+        _ => utxos.shuffle(rng),
+    }
+
+    // Keep adding inputs until there is enough for a change output, so the excess is not
+    // burned as fee
+    let goal = target + change_cost + params.min_change;
+    let mut selected = Vec::new();
+    let mut effective_value = Amount::ZERO;
+    for utxo in utxos {
+        if effective_value >= goal {
+            break;
+        }
+        effective_value += utxo.txout.value - input_fee;
+        selected.push(utxo);
+    }
+    if effective_value < target {
+        return Err(format!(
+            "Insufficient funds: {} sat available after fees, {} sat needed",
+            effective_value.to_sat(),
+            target.to_sat()
+        )
+        .into());
+    }
+
+    finish(selected, amount, params, algorithm)
+}
+
+/// Adds change if the selection leaves enough over, otherwise leaves the excess to the fee.
+fn finish(
+    utxos: Vec<Utxo>,
+    amount: Amount,
+    params: &SelectionParams,
+    algorithm: CoinSelection,
+) -> Result<Selection, Box<dyn std::error::Error>> {
+    let value: Amount = utxos.iter().map(|utxo| utxo.txout.value).sum();
+    let fee_with_change = params.fee(utxos.len(), true);
+    if value >= amount + fee_with_change + params.min_change {
+        return Ok(Selection {
+            change: Some(value - amount - fee_with_change),
+            fee: fee_with_change,
+            utxos,
+            algorithm,
+        });
+    }
+
+    if value < amount + params.fee(utxos.len(), false) {
+        return Err("Insufficient funds for the payment and its fee".into());
+    }
+    Ok(Selection {
+        change: None,
+        fee: value - amount,
+        utxos,
+        algorithm,
+    })
+}
+
+/// Depth-first search for the subset of `values` (sorted descending) that lands in
+/// `target..=target + cost_of_change` with the least excess.
+fn branch_and_bound(values: &[u64], target: u64, cost_of_change: u64) -> Option<Vec<usize>> {
+    // remaining[i] is what the values from `i` on can still add
+    let mut remaining = vec![0; values.len() + 1];
+    for i in (0..values.len()).rev() {
+        remaining[i] = remaining[i + 1] + values[i];
+    }
+
+    let mut included: Vec<usize> = Vec::new();
+    let mut index = 0;
+    let mut current = 0;
+    let mut best: Option<(u64, Vec<usize>)> = None;
+    for _ in 0..BNB_MAX_TRIES {
+        let backtrack = if current + remaining[index] < target
+            || current > target + cost_of_change
+            || best
+                .as_ref()
+                .is_some_and(|(excess, _)| current >= target && current - target >= *excess)
+        {
+            true
+        } else if current >= target {
+            best = Some((current - target, included.clone()));
+            if current == target {
+                break;
+            }
+            true
+        } else {
+            false
+        };
+
+        if backtrack {
+            // Swap the most recently included value for the branch without it
+            let Some(last) = included.pop() else {
+                break;
+            };
+            current -= values[last];
+            index = last + 1;
+        } else {
+            included.push(index);
+            current += values[index];
+            index += 1;
+        }
+    }
+
+    best.map(|(_, indices)| indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{
+        secp256k1::rand::SeedableRng, Amount, FeeRate, OutPoint, ScriptBuf, TxOut, Txid, Weight,
+    };
+    use rand_chacha::ChaCha20Rng;
+
+    use super::{select_coins, CoinSelection, SelectionParams};
+    use crate::common::{keys::Keychain, utxos::Utxo};
+
+    #[test]
+    fn test_coin_selection() {
+        let txid =
+            Txid::from_str("bf210c79258b733a0b5076c96fc26eef206f63789a14719db9552212b5e0ed8d")
+                .expect("Invalid txid");
+        let utxos = [10_000, 30_000, 50_000, 120_000]
+            .into_iter()
+            .enumerate()
+            .map(|(vout, value)| Utxo {
+                outpoint: OutPoint::new(txid, vout as u32),
+                txout: TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey: ScriptBuf::new(),
+                },
+                keychain: Keychain::External,
+                index: vout as u32,
+                height: Some(100),
+            })
+            .collect::<Vec<_>>();
+        let params = SelectionParams {
+            fee_rate: FeeRate::from_sat_per_vb_unchecked(1),
+            base_weight: Weight::from_vb_unchecked(43),
+            input_weight: Weight::from_vb_unchecked(68),
+            change_weight: Weight::from_vb_unchecked(31),
+            min_change: Amount::from_sat(294),
+        };
+        let select = |amount, algorithm| {
+            select_coins(
+                utxos.clone(),
+                Amount::from_sat(amount),
+                &params,
+                algorithm,
+                &mut ChaCha20Rng::from_seed([7; 32]),
+            )
+        };
+
+        // 30k + 50k pays 79_800 plus 43 + 2 * 68 vB of fee exactly, without change
+        let selection = select(79_821, CoinSelection::BranchAndBound).expect("Selection");
+        assert_eq!(selection.algorithm, CoinSelection::BranchAndBound);
+        assert_eq!(selection.change, None);
+        assert_eq!(selection.utxos.len(), 2);
+        assert_eq!(selection.fee, Amount::from_sat(179));
+
+        // Largest-first takes the 120k coin alone and sends the rest back as change
+        let selection = select(79_821, CoinSelection::LargestFirst).expect("Selection");
+        assert_eq!(selection.utxos.len(), 1);
+        assert_eq!(selection.fee, Amount::from_sat(43 + 68 + 31));
+        assert_eq!(
+            selection.change,
+            Some(Amount::from_sat(120_000 - 79_821 - 142))
+        );
+
+        // With no changeless match, branch-and-bound falls back to a random draw with change
+        let selection = select(1_000, CoinSelection::BranchAndBound).expect("Selection");
+        assert_eq!(selection.algorithm, CoinSelection::SingleRandomDraw);
+        assert!(selection.change.is_some());
+
+        assert!(select(210_000, CoinSelection::SingleRandomDraw).is_err());
+    }
+}
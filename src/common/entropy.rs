@@ -0,0 +1,29 @@
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::rand::{rngs::OsRng, RngCore, SeedableRng};
+use bitcoin::Network;
+use rand_chacha::ChaCha20Rng;
+
+/// The source of randomness for key generation and signing: the OS, or a ChaCha20 stream seeded
+/// from `--deterministic-seed` so that workshop material and tests can pin exact outputs.
+pub fn rng(
+    deterministic_seed: Option<&str>,
+    network: Network,
+) -> Result<Box<dyn RngCore>, Box<dyn std::error::Error>> {
+    let Some(seed) = deterministic_seed else {
+        return Ok(Box::new(OsRng));
+    };
+    if network == Network::Bitcoin {
+        return Err(
+            "--deterministic-seed makes keys guessable and is not allowed on mainnet".into(),
+        );
+    }
+
+    eprintln!(
+        "Warning: --deterministic-seed is set. Anyone who knows the seed can rebuild these keys."
+    );
+    Ok(Box::new(seeded_rng(seed)))
+}
+
+fn seeded_rng(seed: &str) -> ChaCha20Rng {
+    ChaCha20Rng::from_seed(sha256::Hash::hash(seed.as_bytes()).to_byte_array())
+}
@@ -0,0 +1,290 @@
+use bitcoin::{consensus, Amount, FeeRate, TxOut, Weight};
+use electrum_client::{Client, ElectrumApi};
+use miniscript::descriptor::{Descriptor, DescriptorPublicKey, WshInner};
+
+/// Confirmation target, in blocks, for estimated fee rates.
+pub const DEFAULT_TARGET_BLOCKS: usize = 6;
+
+/// Outpoint, empty script sig and sequence of a segwit input.
+const TXIN_BASE_WEIGHT: u64 = (32 + 4 + 1 + 4) * 4;
+
+/// Weight of a P2WPKH input: the item count, then a low-R signature (70 bytes DER plus the
+/// sighash type) and a compressed key, each behind a length byte.
+pub const WPKH_INPUT_WEIGHT: Weight = Weight::from_wu(TXIN_BASE_WEIGHT + 1 + (1 + 71) + (1 + 33));
+
+/// Weight of a P2TR key-path input: the item count, then a 64-byte signature. SIGHASH_DEFAULT
+/// adds no sighash byte.
+pub const TR_KEY_SPEND_INPUT_WEIGHT: Weight = Weight::from_wu(TXIN_BASE_WEIGHT + 1 + (1 + 64));
+
+/// Weight of a P2PKH input spending a compressed key: the script sig pushes a low-R signature
+/// and the key. In a segwit transaction it also needs an empty witness.
+pub const P2PKH_INPUT_WEIGHT: Weight =
+    Weight::from_wu(TXIN_BASE_WEIGHT + ((1 + 71) + (1 + 33)) * 4 + 1);
+
+/// Weight of a P2SH-P2WPKH input: the script sig pushes the 22-byte P2WPKH script, and the
+/// witness is the same as a P2WPKH input's.
+pub const P2SH_WPKH_INPUT_WEIGHT: Weight =
+    Weight::from_wu(TXIN_BASE_WEIGHT + (1 + 22) * 4 + 1 + (1 + 71) + (1 + 33));
+
+/// Parses a fee rate in sat/vB, fractions allowed.
+pub fn parse_fee_rate(s: &str) -> Result<FeeRate, String> {
+    let sat_per_vb = s
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| rate.is_finite() && *rate >= 0.0)
+        .ok_or(format!("`{}` is not a fee rate in sat/vB", s))?;
+
+    Ok(FeeRate::from_sat_per_kwu((sat_per_vb * 250.0).ceil() as u64))
+}
+
+pub fn format_fee_rate(fee_rate: FeeRate) -> String {
+    format!("{} sat/vB", fee_rate.to_sat_per_kwu() as f64 / 250.0)
+}
+
+/// The fee rate to pay: `fee_rate` when given, otherwise the server's estimate for
+/// `target_blocks`. Either way it must reach the node's minimum relay fee.
+pub fn fee_rate(
+    fee_rate: Option<FeeRate>,
+    target_blocks: usize,
+    electrum_client: &Client,
+) -> Result<FeeRate, Box<dyn std::error::Error>> {
+    let relay_fee = from_btc_per_kvb(electrum_client.relay_fee()?);
+
+    let fee_rate = match fee_rate {
+        Some(fee_rate) if fee_rate < relay_fee => {
+            return Err(format!(
+                "{} is below the minimum relay fee of {}",
+                format_fee_rate(fee_rate),
+                format_fee_rate(relay_fee)
+            )
+            .into())
+        }
+        Some(fee_rate) => fee_rate,
+        None => {
+            // Servers answer -1 when their node has not seen enough blocks to estimate
+            let estimate = electrum_client.estimate_fee(target_blocks)?;
+            let fee_rate = match estimate > 0.0 {
+                true => from_btc_per_kvb(estimate).max(relay_fee),
+                false => relay_fee,
+            };
+            println!(
+                "Fee rate: {} (estimated for {} blocks)",
+                format_fee_rate(fee_rate),
+                target_blocks
+            );
+            fee_rate
+        }
+    };
+
+    Ok(fee_rate)
+}
+
+/// Electrum reports fee rates in BTC/kvB.
+fn from_btc_per_kvb(btc_per_kvb: f64) -> FeeRate {
+    let sat_per_kvb = (btc_per_kvb * 100_000_000.0).round();
+    FeeRate::from_sat_per_kwu((sat_per_kvb / 4.0).ceil() as u64)
+}
+
+/// The fee for `weight` at `fee_rate`, rounded up to whole virtual bytes and satoshis so the
+/// transaction never pays less than the rate.
+pub fn fee_for(fee_rate: FeeRate, weight: Weight) -> Amount {
+    let vbytes = weight.to_vbytes_ceil();
+    Amount::from_sat((fee_rate.to_sat_per_kwu() * vbytes * 4).div_ceil(1000))
+}
+
+/// Adds `change` to `outputs`, worth what `input_value` holds beyond them and the fee at
+/// `fee_rate`, unless that would be dust. `input_weight` is the weight of the signed inputs.
+/// Returns the fee paid, which takes the leftover when there is no change.
+pub fn add_change(
+    outputs: &mut Vec<TxOut>,
+    mut change: TxOut,
+    input_value: Amount,
+    input_weight: Weight,
+    fee_rate: FeeRate,
+) -> Result<Amount, Box<dyn std::error::Error>> {
+    let output_value = outputs.iter().map(|output| output.value).sum::<Amount>();
+    let fee = fee_for(fee_rate, base_weight(outputs) + input_weight);
+    let leftover = input_value.checked_sub(output_value + fee).ok_or(format!(
+        "The inputs hold {} sat, short of the {} sat sent plus the {} sat fee",
+        input_value.to_sat(),
+        output_value.to_sat(),
+        fee.to_sat()
+    ))?;
+
+    let change_fee = fee_for(
+        fee_rate,
+        base_weight(outputs) + output_weight(&change) + input_weight,
+    );
+    match leftover.checked_sub(change_fee - fee) {
+        Some(value) if value >= change.script_pubkey.dust_value() => {
+            change.value = value;
+            outputs.push(change);
+            Ok(change_fee)
+        }
+        _ => Ok(fee + leftover),
+    }
+}
+
+/// Weight of a transaction with `outputs` and no inputs yet: version, locktime, the input and
+/// output counts and the segwit marker and flag.
+pub fn base_weight(outputs: &[TxOut]) -> Weight {
+    Weight::from_non_witness_data_size(4 + 4 + 1 + 1)
+        + Weight::from_wu(2)
+        + outputs.iter().map(output_weight).sum::<Weight>()
+}
+
+pub fn output_weight(txout: &TxOut) -> Weight {
+    Weight::from_non_witness_data_size(consensus::serialize(txout).len() as u64)
+}
+
+/// Weight of an input spending `descriptor`, witness included, as our signers produce it.
+pub fn input_weight(
+    descriptor: &Descriptor<DescriptorPublicKey>,
+) -> Result<Weight, Box<dyn std::error::Error>> {
+    let witness_weight = match descriptor {
+        Descriptor::Wpkh(_) => return Ok(WPKH_INPUT_WEIGHT),
+        Descriptor::Tr(tr) if tr.tap_tree().is_none() => return Ok(TR_KEY_SPEND_INPUT_WEIGHT),
+        // Item count, the empty item CHECKMULTISIG pops, the signatures and the witness script.
+        // Other signers may not grind for low R, so signatures are counted at their 72-byte
+        // maximum
+        Descriptor::Wsh(wsh) => match wsh.as_inner() {
+            WshInner::SortedMulti(multi) => {
+                let script_size = multi.script_size();
+                varint_len(multi.k + 2)
+                    + 1
+                    + multi.k * (1 + 72)
+                    + varint_len(script_size)
+                    + script_size
+            }
+            WshInner::Ms(_) => descriptor.max_weight_to_satisfy()?,
+        },
+        _ => descriptor.max_weight_to_satisfy()?,
+    };
+
+    Ok(Weight::from_wu(TXIN_BASE_WEIGHT) + Weight::from_wu_usize(witness_weight))
+}
+
+fn varint_len(n: usize) -> usize {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{
+        key::Secp256k1,
+        secp256k1::{rand::SeedableRng, SecretKey},
+        Address, Amount, FeeRate, Network, OutPoint, PrivateKey, TxOut,
+    };
+    use miniscript::{Descriptor, DescriptorPublicKey};
+    use rand_chacha::ChaCha20Rng;
+
+    use super::{add_change, base_weight, input_weight, parse_fee_rate, WPKH_INPUT_WEIGHT};
+    use crate::common::timelocks::Timelocks;
+    use crate::{tr, wpkh};
+
+    #[test]
+    fn test_exact_weights() {
+        let secp = Secp256k1::new();
+        let private_key = PrivateKey::new(
+            SecretKey::from_slice(&[1; 32]).expect("Invalid private key"),
+            Network::Regtest,
+        );
+        let public_key = private_key.public_key(&secp);
+        let prevout = OutPoint::from_str(
+            "bf210c79258b733a0b5076c96fc26eef206f63789a14719db9552212b5e0ed8d:1",
+        )
+        .expect("Invalid outpoint");
+        let outputs = vec![TxOut {
+            value: Amount::from_sat(99_000),
+            script_pubkey: Address::from_str("bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu")
+                .expect("Unable to parse address")
+                .assume_checked()
+                .script_pubkey(),
+        }];
+        let inputs = |descriptor: &Descriptor<DescriptorPublicKey>| {
+            let utxo = TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: descriptor
+                    .at_derivation_index(0)
+                    .expect("Definite descriptor")
+                    .script_pubkey(),
+            };
+            [0, 1].map(|vout| (OutPoint { vout, ..prevout }, utxo.clone(), private_key))
+        };
+
+        let wpkh = Descriptor::from_str(&format!("wpkh({})", public_key)).expect("Descriptor");
+        let tx = wpkh::create_transaction_internal(
+            &secp,
+            &inputs(&wpkh),
+            outputs.clone(),
+            &Timelocks::default(),
+        )
+        .expect("Signed transaction");
+        assert_eq!(
+            tx.weight(),
+            base_weight(&outputs) + input_weight(&wpkh).expect("Weight") * 2
+        );
+
+        let tr = Descriptor::from_str(&format!("tr({})", public_key)).expect("Descriptor");
+        let tx = tr::keyspend::create_transaction_internal(
+            &secp,
+            &inputs(&tr),
+            outputs.clone(),
+            &Timelocks::default(),
+            &mut ChaCha20Rng::from_seed([7; 32]),
+        )
+        .expect("Signed transaction");
+        assert_eq!(
+            tx.weight(),
+            base_weight(&outputs) + input_weight(&tr).expect("Weight") * 2
+        );
+
+        assert_eq!(
+            parse_fee_rate("2.5").expect("Fee rate").to_sat_per_kwu(),
+            625
+        );
+        assert!(parse_fee_rate("-1").is_err());
+    }
+
+    #[test]
+    fn test_add_change() {
+        let script_pubkey = Address::from_str("bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu")
+            .expect("Unable to parse address")
+            .assume_checked()
+            .script_pubkey();
+        let output = |value| TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: script_pubkey.clone(),
+        };
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(2);
+        let add = |input_value| {
+            let mut outputs = vec![output(50_000)];
+            add_change(
+                &mut outputs,
+                output(0),
+                Amount::from_sat(input_value),
+                WPKH_INPUT_WEIGHT,
+                fee_rate,
+            )
+            .map(|fee| (fee.to_sat(), outputs))
+        };
+
+        // 141 vB with change, 110 vB without
+        let (fee, outputs) = add(100_000).expect("Change");
+        assert_eq!(fee, 282);
+        assert_eq!(outputs[1].value, Amount::from_sat(49_718));
+
+        // 218 sat of change would be dust, so it goes to the fee
+        let (fee, outputs) = add(50_500).expect("No change");
+        assert_eq!(fee, 500);
+        assert_eq!(outputs.len(), 1);
+
+        assert!(add(50_100).is_err());
+    }
+}
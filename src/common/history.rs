@@ -0,0 +1,222 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use bitcoin::{Amount, ScriptBuf, SignedAmount, Txid};
+use electrum_client::Client;
+
+use crate::common::labels::{LabelType, Labels};
+use crate::common::utxos;
+use crate::common::wallet::{Wallet, WalletStore, WalletTransaction};
+
+/// A transaction as it affects one wallet.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub txid: Txid,
+    /// Height of the confirming block, or `None` while unconfirmed
+    pub height: Option<u32>,
+    /// What the outputs pay to the wallet
+    pub received: Amount,
+    /// What the wallet's coins spent as inputs were worth
+    pub sent: Amount,
+    /// Only known when every input's previous transaction is one of ours
+    pub fee: Option<Amount>,
+}
+
+impl HistoryEntry {
+    pub fn net(&self) -> SignedAmount {
+        SignedAmount::from_sat(self.received.to_sat() as i64 - self.sent.to_sat() as i64)
+    }
+}
+
+/// The transactions among `transactions` that pay to or spend from `script_pubkeys`, oldest
+/// first with unconfirmed ones last.
+pub fn wallet_history(
+    script_pubkeys: &HashSet<ScriptBuf>,
+    transactions: &[WalletTransaction],
+) -> Vec<HistoryEntry> {
+    let by_txid = transactions
+        .iter()
+        .map(|(tx, _)| (tx.txid(), tx))
+        .collect::<HashMap<_, _>>();
+
+    let mut history = Vec::new();
+    for (tx, height) in transactions {
+        let received = tx
+            .output
+            .iter()
+            .filter(|output| script_pubkeys.contains(&output.script_pubkey))
+            .map(|output| output.value)
+            .sum::<Amount>();
+
+        // The outputs spent by each input, where we have their transaction
+        let prevouts = tx
+            .input
+            .iter()
+            .map(|input| {
+                by_txid
+                    .get(&input.previous_output.txid)
+                    .and_then(|prev_tx| prev_tx.output.get(input.previous_output.vout as usize))
+            })
+            .collect::<Vec<_>>();
+        let sent = prevouts
+            .iter()
+            .flatten()
+            .filter(|prevout| script_pubkeys.contains(&prevout.script_pubkey))
+            .map(|prevout| prevout.value)
+            .sum::<Amount>();
+
+        if received == Amount::ZERO && sent == Amount::ZERO {
+            continue;
+        }
+        let output_value = tx.output.iter().map(|output| output.value).sum();
+        let fee = prevouts
+            .into_iter()
+            .map(|prevout| prevout.map(|prevout| prevout.value))
+            .sum::<Option<Amount>>()
+            .and_then(|input_value| input_value.checked_sub(output_value));
+
+        history.push(HistoryEntry {
+            txid: tx.txid(),
+            height: *height,
+            received,
+            sent,
+            fee,
+        });
+    }
+    history.sort_by_key(|entry| (entry.height.is_none(), entry.height, entry.txid));
+
+    history
+}
+
+pub fn print_history(
+    wallets: &[(String, Wallet)],
+    wallet_store_path: &Path,
+    labels_path: &Path,
+    electrum_client: &Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let labels = Labels::load(labels_path)?;
+    let tip_height = utxos::tip_height(electrum_client)?;
+
+    for (name, wallet) in wallets {
+        utxos::sync(wallet, &mut store, electrum_client)?;
+        let script_pubkeys = wallet
+            .script_pubkeys(&store)?
+            .into_iter()
+            .map(|(_, _, script_pubkey)| script_pubkey)
+            .collect::<HashSet<_>>();
+        let history = wallet_history(&script_pubkeys, &store.transactions()?);
+
+        println!("{} wallet: {} transaction(s)", name, history.len());
+        for entry in history {
+            let status = match entry.height {
+                Some(height) => format!(
+                    "height {}, {} confirmation(s)",
+                    height,
+                    tip_height.saturating_sub(height) + 1
+                ),
+                None => "unconfirmed".to_string(),
+            };
+            let fee = match entry.fee {
+                Some(fee) => format!(", fee {} sat", fee.to_sat()),
+                None => String::new(),
+            };
+            let label = match labels.get(LabelType::Tx, &entry.txid.to_string()) {
+                Some(label) => format!(" \"{}\"", label),
+                None => String::new(),
+            };
+            println!(
+                "  {} {:+} sat, {}{}{}",
+                entry.txid,
+                entry.net().to_sat(),
+                status,
+                fee,
+                label
+            );
+        }
+    }
+    store.save()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use bitcoin::{
+        absolute::LockTime, transaction::Version, Address, Amount, OutPoint, ScriptBuf, Sequence,
+        Transaction, TxIn, TxOut, Witness,
+    };
+
+    use super::wallet_history;
+
+    fn script(address: &str) -> ScriptBuf {
+        Address::from_str(address)
+            .expect("Unable to parse address")
+            .assume_checked()
+            .script_pubkey()
+    }
+
+    fn transaction(inputs: Vec<OutPoint>, outputs: Vec<(ScriptBuf, u64)>) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .into_iter()
+                .map(|(script_pubkey, value)| TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_wallet_history() {
+        let ours = script("bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu");
+        let change = script("bcrt1q74y9y6qr0whce5hm7hp08uzks8r2r6yzz22czc");
+        let theirs = script("bcrt1qset9wrnzguwgpk2uk7g7ewgxh660u9creeve79");
+        let script_pubkeys = HashSet::from([ours.clone(), change.clone()]);
+
+        // Someone pays us, we pay them back with change, and an unrelated transaction
+        let funding = transaction(
+            vec![OutPoint::null()],
+            vec![(theirs.clone(), 10_000), (ours.clone(), 50_000)],
+        );
+        let spend = transaction(
+            vec![OutPoint::new(funding.txid(), 1)],
+            vec![(theirs.clone(), 20_000), (change.clone(), 29_700)],
+        );
+        let unrelated = transaction(vec![OutPoint::null()], vec![(theirs, 1_000)]);
+
+        let history = wallet_history(
+            &script_pubkeys,
+            &[
+                (spend.clone(), None),
+                (unrelated, Some(5)),
+                (funding.clone(), Some(10)),
+            ],
+        );
+        assert_eq!(history.len(), 2);
+
+        assert_eq!(history[0].txid, funding.txid());
+        assert_eq!(history[0].net().to_sat(), 50_000);
+        assert_eq!(history[0].fee, None);
+
+        assert_eq!(history[1].txid, spend.txid());
+        assert_eq!(history[1].height, None);
+        assert_eq!(history[1].net().to_sat(), -20_300);
+        assert_eq!(history[1].fee, Some(Amount::from_sat(300)));
+    }
+}
@@ -0,0 +1,355 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use bitcoin::bip32::Xpriv;
+use bitcoin::hex::FromHex;
+use bitcoin::key::Secp256k1;
+use bitcoin::secp256k1::{All, SecretKey};
+use bitcoin::{Network, PrivateKey};
+use miniscript::descriptor::{DescriptorSecretKey, DescriptorXKey, Wildcard};
+use miniscript::{Descriptor, DescriptorPublicKey};
+
+use crate::common::keys::{self, Keychain, ScriptType, StoredKey};
+
+/// The formats keys can be imported from and exported to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyFormat {
+    Wif,
+    Hex,
+    Xprv,
+    Descriptor,
+}
+
+impl KeyFormat {
+    pub const ALL: [KeyFormat; 4] = [
+        KeyFormat::Wif,
+        KeyFormat::Hex,
+        KeyFormat::Xprv,
+        KeyFormat::Descriptor,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyFormat::Wif => "wif",
+            KeyFormat::Hex => "hex",
+            KeyFormat::Xprv => "xprv",
+            KeyFormat::Descriptor => "descriptor",
+        }
+    }
+}
+
+impl FromStr for KeyFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KeyFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == s)
+            .ok_or(format!("Unknown key format `{}`", s))
+    }
+}
+
+/// Imports a key brought from another wallet into a new key file.
+pub fn import_key(
+    secp: &Secp256k1<All>,
+    path: &Path,
+    secret: Option<&str>,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if path.exists() {
+        panic!(
+            "Key already exists at {}. Refusing to overwrite",
+            path.display()
+        );
+    }
+
+    // Prompting keeps the secret out of the shell history
+    let secret = match secret {
+        Some(secret) => secret.to_string(),
+        None => rpassword::prompt_password("Key to import: ")?,
+    };
+    match parse_secret(secp, &secret, network)? {
+        StoredKey::Master(xpriv) => keys::write_key(secp, path, &xpriv),
+        StoredKey::Single(private_key) => {
+            println!("Public key: {}", private_key.public_key(secp));
+            keys::write_secret_key(path, &private_key.to_wif())
+        }
+    }
+}
+
+/// Parses a WIF key, a raw hex key, an xprv, or a descriptor holding a single private key.
+fn parse_secret(
+    secp: &Secp256k1<All>,
+    secret: &str,
+    network: Network,
+) -> Result<StoredKey, Box<dyn std::error::Error>> {
+    let secret = secret.trim();
+    let mut key = if let Ok(xpriv) = Xpriv::from_str(secret) {
+        master_key(xpriv)?
+    } else if let Ok(private_key) = PrivateKey::from_wif(secret) {
+        StoredKey::Single(private_key)
+    } else if let Some(bytes) = Vec::<u8>::from_hex(secret).ok().filter(|b| b.len() == 32) {
+        StoredKey::Single(PrivateKey::new(SecretKey::from_slice(&bytes)?, network))
+    } else if let Some(key) = descriptor_secret_key(secp, secret)? {
+        match key {
+            DescriptorSecretKey::Single(single) => StoredKey::Single(single.key),
+            DescriptorSecretKey::XPrv(xkey) => {
+                warn_path_dropped(!xkey.derivation_path.is_empty());
+                master_key(xkey.xkey)?
+            }
+            DescriptorSecretKey::MultiXPrv(xkey) => {
+                warn_path_dropped(true);
+                master_key(xkey.xkey)?
+            }
+        }
+    } else {
+        return Err(
+            "Unrecognized key. Expected a WIF key, 64 hex characters, an xprv or a descriptor \
+             with a private key"
+                .into(),
+        );
+    };
+
+    let key_network = match &mut key {
+        StoredKey::Master(xpriv) => std::mem::replace(&mut xpriv.network, network),
+        StoredKey::Single(private_key) => std::mem::replace(&mut private_key.network, network),
+    };
+    keys::check_network(key_network, network)?;
+
+    Ok(key)
+}
+
+/// The private key of a descriptor, or of a bare descriptor key like `[fp/path]xprv/0/*`.
+fn descriptor_secret_key(
+    secp: &Secp256k1<All>,
+    descriptor: &str,
+) -> Result<Option<DescriptorSecretKey>, Box<dyn std::error::Error>> {
+    if let Ok(key) = DescriptorSecretKey::from_str(descriptor) {
+        return Ok(Some(key));
+    }
+    let Ok((_, key_map)) = Descriptor::<DescriptorPublicKey>::parse_descriptor(secp, descriptor)
+    else {
+        return Ok(None);
+    };
+
+    let mut keys = key_map.into_values();
+    match (keys.next(), keys.next()) {
+        (Some(key), None) => Ok(Some(key)),
+        (None, _) => Err("The descriptor has no private key".into()),
+        _ => Err("The descriptor has more than one private key. Import them one at a time".into()),
+    }
+}
+
+/// Only master keys can be imported as extended keys, since every account is derived from them.
+fn master_key(xpriv: Xpriv) -> Result<StoredKey, Box<dyn std::error::Error>> {
+    if xpriv.depth != 0 {
+        return Err(format!(
+            "Only master extended keys can be imported, but this one is at depth {}. Export the \
+             master key from the other wallet instead",
+            xpriv.depth
+        )
+        .into());
+    }
+
+    Ok(StoredKey::Master(xpriv))
+}
+
+fn warn_path_dropped(has_path: bool) {
+    if has_path {
+        eprintln!(
+            "Note: the descriptor's derivation path is not kept. Accounts are derived from the \
+             master key following BIP84, BIP86 and BIP48."
+        );
+    }
+}
+
+/// Prints the key at `path` in `format`. Secrets are only shown when `private` is set.
+#[allow(clippy::too_many_arguments)]
+pub fn export_key(
+    secp: &Secp256k1<All>,
+    path: &Path,
+    format: KeyFormat,
+    script_type: ScriptType,
+    keychain: Keychain,
+    index: u32,
+    private: bool,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = keys::read_key(path, network)?;
+    println!(
+        "{}",
+        export_key_internal(secp, &key, format, script_type, keychain, index, private)?
+    );
+
+    Ok(())
+}
+
+fn export_key_internal(
+    secp: &Secp256k1<All>,
+    key: &StoredKey,
+    format: KeyFormat,
+    script_type: ScriptType,
+    keychain: Keychain,
+    index: u32,
+    private: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // WIF and hex hold a single key, which for a master key is the one at the chosen derivation
+    let single_key = || match key {
+        StoredKey::Master(xpriv) => {
+            keys::derive_private_key(secp, xpriv, script_type, keychain, index)
+        }
+        StoredKey::Single(private_key) => Ok(*private_key),
+    };
+
+    match format {
+        KeyFormat::Wif if !private => {
+            Err("A WIF key is always secret. Pass `--private` to show it".into())
+        }
+        KeyFormat::Wif => Ok(single_key()?.to_wif()),
+        KeyFormat::Hex if !private => Ok(single_key()?.public_key(secp).to_string()),
+        KeyFormat::Hex => Ok(single_key()?.inner.display_secret().to_string()),
+        KeyFormat::Xprv => {
+            let StoredKey::Master(xpriv) = key else {
+                return Err("A single imported key has no extended key".into());
+            };
+            match private {
+                true => Ok(xpriv.to_string()),
+                false => Ok(keys::account_xpub(secp, xpriv, script_type)?.to_string()),
+            }
+        }
+        KeyFormat::Descriptor => export_descriptor(secp, key, script_type, private),
+    }
+}
+
+fn export_descriptor(
+    secp: &Secp256k1<All>,
+    key: &StoredKey,
+    script_type: ScriptType,
+    private: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if let ScriptType::Wsh = script_type {
+        return Err(
+            "A multisig descriptor needs every party's key. Use `wsh generate-descriptor`".into(),
+        );
+    }
+
+    let descriptor_keys = match (key, private) {
+        // The master key itself, so that the descriptors can be imported back. Private keys can't
+        // be multipath, so there is one descriptor per keychain
+        (StoredKey::Master(xpriv), true) => Keychain::ALL
+            .iter()
+            .map(|keychain| {
+                DescriptorSecretKey::XPrv(DescriptorXKey {
+                    origin: None,
+                    xkey: *xpriv,
+                    derivation_path: script_type
+                        .account_path(xpriv.network)
+                        .extend([keychain.child()]),
+                    wildcard: Wildcard::Unhardened,
+                })
+                .to_string()
+            })
+            .collect(),
+        (StoredKey::Master(xpriv), false) => {
+            vec![keys::account_keychains(keys::account_xpub(secp, xpriv, script_type)?).to_string()]
+        }
+        (StoredKey::Single(private_key), true) => vec![private_key.to_wif()],
+        (StoredKey::Single(private_key), false) => vec![private_key.public_key(secp).to_string()],
+    };
+
+    let descriptors = descriptor_keys
+        .iter()
+        .map(|descriptor_key| {
+            let (descriptor, key_map) = Descriptor::<DescriptorPublicKey>::parse_descriptor(
+                secp,
+                &format!("{}({})", script_type.name(), descriptor_key),
+            )?;
+            Ok(descriptor.to_string_with_secret(&key_map))
+        })
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+
+    Ok(descriptors.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{bip32::Xpriv, key::Secp256k1, secp256k1::SecretKey, Network, PrivateKey};
+
+    use miniscript::{Descriptor, DescriptorPublicKey};
+
+    use super::{export_key_internal, parse_secret, KeyFormat};
+    use crate::common::keys::{Keychain, ScriptType, StoredKey};
+    use crate::common::wallet::Wallet;
+
+    #[test]
+    fn test_import_export() {
+        let secp = Secp256k1::new();
+        let network = Network::Regtest;
+        let private_key = PrivateKey::new(
+            SecretKey::from_slice(&[1; 32]).expect("Invalid private key"),
+            network,
+        );
+        let export = |key: &StoredKey, format: KeyFormat, private: bool| {
+            export_key_internal(
+                &secp,
+                key,
+                format,
+                ScriptType::Wpkh,
+                Keychain::External,
+                0,
+                private,
+            )
+        };
+
+        // A single key reads back the same from every format it exports to
+        let single = StoredKey::Single(private_key);
+        assert!(export(&single, KeyFormat::Wif, false).is_err());
+        for format in [KeyFormat::Wif, KeyFormat::Hex, KeyFormat::Descriptor] {
+            let exported = export(&single, format, true).expect("Export");
+            match parse_secret(&secp, &exported, network).expect("Import") {
+                StoredKey::Single(imported) => assert_eq!(imported, private_key),
+                StoredKey::Master(_) => panic!("Imported a master key"),
+            }
+        }
+        assert!(export(&single, KeyFormat::Descriptor, false)
+            .expect("Export")
+            .starts_with(&format!("wpkh({})#", private_key.public_key(&secp))));
+
+        // So does a master key, and its public descriptor matches the private one
+        let mut xpriv = Xpriv::from_str("tprv8ZgxMBicQKsPdE9CKqUW84nQ7o529Cn3oyBpC6mAYPybohfSWVu5UbvhJ38orCjVDuJsztvh37uQuUXi6F25BGWXrtTmBWuAJpNDa8spieL")
+            .expect("Invalid xprv");
+        xpriv.network = network;
+        let master = StoredKey::Master(xpriv);
+        for format in [KeyFormat::Xprv, KeyFormat::Descriptor] {
+            let exported = export(&master, format, true).expect("Export");
+            let exported = exported.lines().last().expect("Exported key");
+            match parse_secret(&secp, exported, network).expect("Import") {
+                StoredKey::Master(imported) => assert_eq!(imported, xpriv),
+                StoredKey::Single(_) => panic!("Imported a single key"),
+            }
+        }
+        let public = Wallet::from_descriptor_str(
+            &export(&master, KeyFormat::Descriptor, false).expect("Export"),
+            network,
+        )
+        .expect("Public descriptor");
+        let private = export(&master, KeyFormat::Descriptor, true).expect("Export");
+        for (keychain, private) in Keychain::ALL.into_iter().zip(private.lines()) {
+            let (private, _) = Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, private)
+                .expect("Private descriptor");
+            assert_eq!(
+                public.address(keychain, 5).expect("Address"),
+                private
+                    .at_derivation_index(5)
+                    .expect("Derivation")
+                    .address(network)
+                    .expect("Address")
+            );
+        }
+
+        // Keys for another network are refused
+        assert!(parse_secret(&secp, &private_key.to_wif(), Network::Bitcoin).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use bitcoin::key::Secp256k1;
+use bitcoin::secp256k1::All;
+use bitcoin::Network;
+
+use crate::common::{keys, keystore};
+
+const KEY_EXTENSION: &str = "key";
+const WALLET_STORE_FILE: &str = "wallets.json";
+const LABELS_FILE: &str = "labels.jsonl";
+/// Where versions before the keyring kept their only key.
+const LEGACY_KEY_FILE: &str = "key.txt";
+/// The key that commands use without `--key`, and that a legacy key file becomes.
+const DEFAULT_KEY: &str = "default";
+
+/// A directory of named key files, so that a single machine can hold every party's key.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    dir: PathBuf,
+}
+
+impl Keyring {
+    pub fn new(dir: &str) -> Self {
+        Keyring {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    /// Path of the key file for `name`. The file may not exist yet.
+    pub fn path(&self, name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        check_name(name)?;
+        Ok(self.dir.join(format!("{}.{}", name, KEY_EXTENSION)))
+    }
+
+    /// Path of the key file for `name`, creating the keyring directory if needed.
+    pub fn new_key_path(&self, name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        fs::create_dir_all(&self.dir)?;
+        self.path(name)
+    }
+
+    /// Path of the key file for `name`, failing if there is no such key.
+    pub fn existing_key_path(&self, name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let path = self.path(name)?;
+        if path.exists() || (name == DEFAULT_KEY && self.migrate_legacy_key(&path)?) {
+            return Ok(path);
+        }
+
+        Err(format!(
+            "No key named `{}` in {}. Run `tx-fun keygen --key {}` to create it",
+            name,
+            self.dir.display(),
+            name
+        )
+        .into())
+    }
+
+    /// Offers to move a `key.txt` left in the working directory by an older version to `path`,
+    /// returning whether it was moved.
+    fn migrate_legacy_key(&self, path: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+        let legacy_path = Path::new(LEGACY_KEY_FILE);
+        if !legacy_path.is_file() {
+            return Ok(false);
+        }
+
+        print!(
+            "Found {} from an older version. Move it to {}? [y/N] ",
+            legacy_path.display(),
+            path.display()
+        );
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Ok(false);
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        // A rename can't cross file systems, so fall back to copying
+        if fs::rename(legacy_path, path).is_err() {
+            fs::copy(legacy_path, path)?;
+            fs::remove_file(legacy_path)?;
+        }
+        println!("Moved {} to {}", legacy_path.display(), path.display());
+        if !keystore::is_encrypted_file(path)? {
+            println!("Run `tx-fun encrypt-key` to encrypt it");
+        }
+
+        Ok(true)
+    }
+
+    /// Path of the store holding the derivation indices of every wallet.
+    pub fn wallet_store_path(&self) -> PathBuf {
+        self.dir.join(WALLET_STORE_FILE)
+    }
+
+    pub fn labels_path(&self) -> PathBuf {
+        self.dir.join(LABELS_FILE)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.path(name).map(|path| path.exists()).unwrap_or(false)
+    }
+
+    pub fn names(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(KEY_EXTENSION) {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+}
+
+/// Names of keys and wallets double as file names, so keep them to a safe character set.
+pub fn check_name(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "Invalid name `{}`. Use letters, digits, `-` and `_` only",
+            name
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+pub fn list_keys(keyring: &Keyring) -> Result<(), Box<dyn std::error::Error>> {
+    let names = keyring.names()?;
+    if names.is_empty() {
+        println!("No keys found. Run `tx-fun keygen --key <name>` to create one");
+    }
+    for name in names {
+        let status = if keystore::is_encrypted_file(keyring.path(&name)?)? {
+            "encrypted"
+        } else {
+            "plaintext"
+        };
+        println!("{} ({})", name, status);
+    }
+
+    Ok(())
+}
+
+pub fn show_key(
+    secp: &Secp256k1<All>,
+    keyring: &Keyring,
+    name: &str,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = keyring.existing_key_path(name)?;
+    println!("Key: {}", name);
+    println!("Path: {}", path.display());
+    keys::read_pubkey(secp, &path, network)
+}
+
+pub fn delete_key(
+    keyring: &Keyring,
+    name: &str,
+    yes: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = keyring.existing_key_path(name)?;
+
+    if !yes {
+        print!(
+            "This permanently deletes {}. Type the key name to confirm: ",
+            path.display()
+        );
+        io::stdout().flush()?;
+        let mut confirmation = String::new();
+        io::stdin().read_line(&mut confirmation)?;
+        if confirmation.trim() != name {
+            println!("Aborted");
+            return Ok(());
+        }
+    }
+
+    fs::remove_file(&path)?;
+    println!("Deleted key `{}`", name);
+
+    Ok(())
+}
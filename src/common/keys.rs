@@ -1,48 +1,478 @@
-use bitcoin::secp256k1::rand::rngs::OsRng;
+use bitcoin::secp256k1::rand::RngCore;
 
-use bitcoin::key::PrivateKey;
+use bip39::{Language, Mnemonic};
+use bitcoin::bip32::{ChildNumber, DerivationPath, Xpriv, Xpub};
+use bitcoin::key::{PrivateKey, PublicKey};
 use bitcoin::secp256k1::All;
 use bitcoin::Network;
+use miniscript::descriptor::{DerivPaths, DescriptorMultiXKey, DescriptorXKey, Wildcard};
+use miniscript::DescriptorPublicKey;
+
+use crate::common::keystore;
+use crate::common::slip39::{self, Scheme};
 
-use std::fs::File;
-use std::io::{Read, Write};
 use std::str::FromStr;
 
 use bitcoin::key::Secp256k1;
 
 use std::path::Path;
 
-pub fn generate_key(secp: &Secp256k1<All>, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// The script types we derive keys for. Each one gets its own account so that keys are never
+/// shared between script types.
+#[derive(Clone, Copy, Debug)]
+pub enum ScriptType {
+    Wpkh,
+    Tr,
+    Wsh,
+}
+
+impl ScriptType {
+    pub const ALL: [ScriptType; 3] = [ScriptType::Wpkh, ScriptType::Tr, ScriptType::Wsh];
+
+    /// Account path following BIP84 (wpkh), BIP86 (tr) and BIP48 (wsh multisig). Coin type is 0
+    /// on mainnet and 1 on every test network.
+    pub fn account_path(&self, network: Network) -> DerivationPath {
+        let coin_type = match network {
+            Network::Bitcoin => 0,
+            _ => 1,
+        };
+        let path = match self {
+            ScriptType::Wpkh => format!("m/84'/{}'/0'", coin_type),
+            ScriptType::Tr => format!("m/86'/{}'/0'", coin_type),
+            ScriptType::Wsh => format!("m/48'/{}'/0'/2'", coin_type),
+        };
+        DerivationPath::from_str(&path).expect("Valid derivation path")
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScriptType::Wpkh => "wpkh",
+            ScriptType::Tr => "tr",
+            ScriptType::Wsh => "wsh",
+        }
+    }
+}
+
+impl FromStr for ScriptType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ScriptType::ALL
+            .into_iter()
+            .find(|script_type| script_type.name() == s)
+            .ok_or(format!("Unknown script type `{}`", s))
+    }
+}
+
+/// The two derivation chains of an account: `/0/*` for addresses we hand out, `/1/*` for change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Keychain {
+    External,
+    Internal,
+}
+
+impl Keychain {
+    pub const ALL: [Keychain; 2] = [Keychain::External, Keychain::Internal];
+
+    pub fn child(&self) -> ChildNumber {
+        match self {
+            Keychain::External => ChildNumber::Normal { index: 0 },
+            Keychain::Internal => ChildNumber::Normal { index: 1 },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Keychain::External => "receive",
+            Keychain::Internal => "change",
+        }
+    }
+}
+
+pub fn generate_key(
+    secp: &Secp256k1<All>,
+    path: &Path,
+    word_count: usize,
+    passphrase: Option<&str>,
+    shares: Option<Scheme>,
+    network: Network,
+    rng: &mut dyn RngCore,
+) -> Result<(), Box<dyn std::error::Error>> {
     // Step 0: abort if we already created a key
-    let path = Path::new(path);
     if path.exists() {
         panic!("Key already created. To print pubkey, run `tx-fun pubkey`");
     }
 
-    // Generate keypair
-    let (sk, pk) = secp.generate_keypair(&mut OsRng);
-    let priv_key = PrivateKey::new(sk, Network::Regtest);
-    println!("Public key: {}", pk);
+    if let Some(scheme) = shares {
+        return generate_shamir_key(secp, path, word_count, passphrase, scheme, network, rng);
+    }
+
+    // Generate mnemonic
+    let mut entropy = vec![0u8; word_count / 3 * 4];
+    rng.fill_bytes(&mut entropy);
+    let mnemonic = Mnemonic::from_entropy_in(Language::English, &entropy)?;
+    println!("Mnemonic: {}", mnemonic);
+    println!("Write these words down. They are the only way to restore this key.");
+
+    write_key_from_mnemonic(secp, path, &mnemonic, passphrase, network)
+}
+
+pub fn restore_key(
+    secp: &Secp256k1<All>,
+    path: &Path,
+    words: &str,
+    passphrase: Option<&str>,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if path.exists() {
+        panic!(
+            "Key already exists at {}. Refusing to overwrite",
+            path.display()
+        );
+    }
+
+    let mnemonic = Mnemonic::parse_in(Language::English, words)?;
+    write_key_from_mnemonic(secp, path, &mnemonic, passphrase, network)
+}
+
+/// Generates a master secret of the same strength as a `word_count` mnemonic, and backs it up
+/// as SLIP-39 shares instead.
+fn generate_shamir_key(
+    secp: &Secp256k1<All>,
+    path: &Path,
+    word_count: usize,
+    passphrase: Option<&str>,
+    scheme: Scheme,
+    network: Network,
+    rng: &mut dyn RngCore,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut master_secret = vec![0u8; word_count / 12 * 16];
+    rng.fill_bytes(&mut master_secret);
 
-    // Write WIF privkey to key.txt
-    let mut file = File::create(path).expect("Unable to create file");
-    file.write_all(priv_key.to_wif().as_bytes())
-        .expect("Unable to write data");
+    let shares = slip39::split(&master_secret, passphrase.unwrap_or(""), scheme, rng)?;
+    for (i, share) in shares.iter().enumerate() {
+        println!("Share {}: {}", i + 1, share);
+    }
+    println!(
+        "Give each share to a different person. Any {} of them restore this key.",
+        scheme.threshold
+    );
+
+    write_key(secp, path, &Xpriv::new_master(network, &master_secret)?)
+}
+
+/// Rebuilds a key file from enough SLIP-39 shares.
+pub fn recover_key(
+    secp: &Secp256k1<All>,
+    path: &Path,
+    shares: &[String],
+    passphrase: Option<&str>,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if path.exists() {
+        panic!(
+            "Key already exists at {}. Refusing to overwrite",
+            path.display()
+        );
+    }
+
+    let master_secret = slip39::combine(shares, passphrase.unwrap_or(""))?;
+    write_key(secp, path, &Xpriv::new_master(network, &master_secret)?)
+}
+
+fn write_key_from_mnemonic(
+    secp: &Secp256k1<All>,
+    path: &Path,
+    mnemonic: &Mnemonic,
+    passphrase: Option<&str>,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_key(
+        secp,
+        path,
+        &xpriv_from_mnemonic(mnemonic, passphrase, network)?,
+    )
+}
+
+pub fn write_key(
+    secp: &Secp256k1<All>,
+    path: &Path,
+    xpriv: &Xpriv,
+) -> Result<(), Box<dyn std::error::Error>> {
+    print_account_xpubs(secp, xpriv)?;
+
+    // Write encrypted xprv to the key file
+    write_secret_key(path, &xpriv.to_string())
+}
+
+/// Writes a key file holding `secret`, asking for the password to encrypt it with.
+pub fn write_secret_key(path: &Path, secret: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let password = keystore::new_password(keystore::PASSWORD_ENV)?;
+    keystore::write_secret(path, secret, &password)?;
 
     Ok(())
 }
 
-pub fn read_pubkey(secp: &Secp256k1<All>, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let path = Path::new(path);
+/// Derives the BIP32 master key from a mnemonic and optional BIP39 passphrase.
+fn xpriv_from_mnemonic(
+    mnemonic: &Mnemonic,
+    passphrase: Option<&str>,
+    network: Network,
+) -> Result<Xpriv, Box<dyn std::error::Error>> {
+    let seed = mnemonic.to_seed(passphrase.unwrap_or(""));
+    Ok(Xpriv::new_master(network, &seed)?)
+}
+
+pub fn read_pubkey(
+    secp: &Secp256k1<All>,
+    path: &Path,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
     if path.exists() {
-        let mut private_key_str = String::new();
-        File::open(path)?.read_to_string(&mut private_key_str)?;
-        let private_key = PrivateKey::from_str(&private_key_str)?;
-        let public_key = private_key.public_key(secp).inner;
-        println!("Public key is: {}", public_key);
+        match read_key(path, network)? {
+            StoredKey::Master(xpriv) => print_account_xpubs(secp, &xpriv)?,
+            StoredKey::Single(private_key) => {
+                println!("Public key: {}", private_key.public_key(secp))
+            }
+        }
     } else {
         println!("No key found at path: {}", path.display());
     }
 
     Ok(())
 }
+
+fn print_account_xpubs(
+    secp: &Secp256k1<All>,
+    xpriv: &Xpriv,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for script_type in ScriptType::ALL {
+        println!(
+            "{} account xpub: {}",
+            script_type.name(),
+            account_xpub(secp, xpriv, script_type)?
+        );
+    }
+
+    Ok(())
+}
+
+/// What a key file holds: a BIP32 master key that accounts are derived from, or a single
+/// private key imported from elsewhere.
+pub enum StoredKey {
+    Master(Xpriv),
+    Single(PrivateKey),
+}
+
+/// Reads the key stored at `path`, unlocking it if it is encrypted. The key is bound to
+/// `network` for all further derivations.
+pub fn read_key<P: AsRef<Path>>(
+    path: P,
+    network: Network,
+) -> Result<StoredKey, Box<dyn std::error::Error>> {
+    let secret = keystore::read_secret(path)?;
+    if let Ok(mut xpriv) = Xpriv::from_str(&secret) {
+        check_network(xpriv.network, network)?;
+        xpriv.network = network;
+        return Ok(StoredKey::Master(xpriv));
+    }
+
+    let mut private_key = PrivateKey::from_wif(&secret)
+        .map_err(|e| format!("Key file does not contain a private key: {}", e))?;
+    check_network(private_key.network, network)?;
+    private_key.network = network;
+
+    Ok(StoredKey::Single(private_key))
+}
+
+/// Reads the master extended private key from `path`, failing for single imported keys.
+pub fn read_xpriv<P: AsRef<Path>>(
+    path: P,
+    network: Network,
+) -> Result<Xpriv, Box<dyn std::error::Error>> {
+    match read_key(path, network)? {
+        StoredKey::Master(xpriv) => Ok(xpriv),
+        StoredKey::Single(_) => Err(
+            "This key is a single imported private key, so it has no accounts to derive from"
+                .into(),
+        ),
+    }
+}
+
+/// Checks that a key made for `key_network` may be used on `network`.
+pub fn check_network(
+    key_network: Network,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Keys only encode mainnet vs. test networks, so that's all we can check
+    if (key_network == Network::Bitcoin) != (network == Network::Bitcoin) {
+        return Err(format!(
+            "Key was created for {}, but the selected network is {}",
+            match key_network {
+                Network::Bitcoin => "mainnet",
+                _ => "a test network",
+            },
+            network
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// The account xpub for `script_type`, with its `[fingerprint/path]` key origin.
+pub fn account_xpub(
+    secp: &Secp256k1<All>,
+    xpriv: &Xpriv,
+    script_type: ScriptType,
+) -> Result<DescriptorPublicKey, Box<dyn std::error::Error>> {
+    let path = script_type.account_path(xpriv.network);
+    let account_xpriv = xpriv.derive_priv(secp, &path)?;
+
+    Ok(DescriptorPublicKey::XPub(DescriptorXKey {
+        origin: Some((xpriv.fingerprint(secp), path)),
+        xkey: Xpub::from_priv(secp, &account_xpriv),
+        derivation_path: DerivationPath::master(),
+        wildcard: Wildcard::None,
+    }))
+}
+
+/// Reads the key file at `path` and returns its account xpub for `script_type`.
+pub fn read_account_xpub(
+    secp: &Secp256k1<All>,
+    path: &Path,
+    script_type: ScriptType,
+    network: Network,
+) -> Result<DescriptorPublicKey, Box<dyn std::error::Error>> {
+    account_xpub(secp, &read_xpriv(path, network)?, script_type)
+}
+
+/// The private key at `index` of the given keychain of the `script_type` account.
+pub fn derive_private_key(
+    secp: &Secp256k1<All>,
+    xpriv: &Xpriv,
+    script_type: ScriptType,
+    keychain: Keychain,
+    index: u32,
+) -> Result<PrivateKey, Box<dyn std::error::Error>> {
+    let path = script_type
+        .account_path(xpriv.network)
+        .extend([keychain.child(), ChildNumber::from_normal_idx(index)?]);
+
+    Ok(xpriv.derive_priv(secp, &path)?.to_priv())
+}
+
+/// The private key at `index` of the given keychain of `key`'s `script_type` account. A single
+/// imported key is the only key of its wallet, so it is returned as-is.
+pub fn signing_key(
+    secp: &Secp256k1<All>,
+    key: &StoredKey,
+    script_type: ScriptType,
+    keychain: Keychain,
+    index: u32,
+) -> Result<PrivateKey, Box<dyn std::error::Error>> {
+    match key {
+        StoredKey::Master(xpriv) => derive_private_key(secp, xpriv, script_type, keychain, index),
+        StoredKey::Single(private_key) => Ok(*private_key),
+    }
+}
+
+/// Turns a bare account xpub into its receive chain, `<account>/0/*`. Any other key is
+/// returned as-is.
+pub fn receive_chain(key: DescriptorPublicKey) -> DescriptorPublicKey {
+    match key {
+        DescriptorPublicKey::XPub(mut xkey)
+            if xkey.derivation_path.is_empty() && xkey.wildcard == Wildcard::None =>
+        {
+            xkey.derivation_path = DerivationPath::from(vec![ChildNumber::Normal { index: 0 }]);
+            xkey.wildcard = Wildcard::Unhardened;
+            DescriptorPublicKey::XPub(xkey)
+        }
+        key => key,
+    }
+}
+
+/// The `<0;1>` step that covers both keychains of an account.
+pub fn keychain_paths() -> DerivPaths {
+    let paths = Keychain::ALL
+        .iter()
+        .map(|keychain| DerivationPath::from(vec![keychain.child()]))
+        .collect();
+    DerivPaths::new(paths).expect("Two keychains")
+}
+
+/// Turns a bare account xpub into a multipath key covering both keychains, `<account>/<0;1>/*`.
+/// Any other key is returned as-is.
+pub fn account_keychains(key: DescriptorPublicKey) -> DescriptorPublicKey {
+    match key {
+        DescriptorPublicKey::XPub(xkey)
+            if xkey.derivation_path.is_empty() && xkey.wildcard == Wildcard::None =>
+        {
+            DescriptorPublicKey::MultiXPub(DescriptorMultiXKey {
+                origin: xkey.origin,
+                xkey: xkey.xkey,
+                derivation_paths: keychain_paths(),
+                wildcard: Wildcard::Unhardened,
+            })
+        }
+        key => key,
+    }
+}
+
+/// Resolves a raw public key, or an account xpub, to the public key at receive index `index`.
+pub fn receive_public_key(
+    secp: &Secp256k1<All>,
+    key: &str,
+    index: u32,
+) -> Result<PublicKey, Box<dyn std::error::Error>> {
+    let key = receive_chain(DescriptorPublicKey::from_str(key)?);
+    Ok(key.at_derivation_index(index)?.derive_public_key(secp)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bip39::Mnemonic;
+    use bitcoin::{bip32::Xpriv, key::Secp256k1, Network};
+
+    use super::{
+        account_xpub, derive_private_key, receive_public_key, xpriv_from_mnemonic, Keychain,
+        ScriptType,
+    };
+
+    #[test]
+    fn test_mnemonic_restore() {
+        // BIP39 test vector, passphrase "TREZOR"
+        let mnemonic = Mnemonic::from_str("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .expect("Invalid mnemonic");
+        let expected = Xpriv::from_str("xprv9s21ZrQH143K3h3fDYiay8mocZ3afhfULfb5GX8kCBdno77K4HiA15Tg23wpbeF1pLfs1c5SPmYHrEpTuuRhxMwvKDwqdKiGJS9XFKzUsAF")
+            .expect("Invalid xprv");
+
+        let xpriv = xpriv_from_mnemonic(&mnemonic, Some("TREZOR"), Network::Bitcoin)
+            .expect("Key derivation failed");
+        assert_eq!(xpriv.private_key, expected.private_key);
+        assert_eq!(xpriv.chain_code, expected.chain_code);
+    }
+
+    #[test]
+    fn test_account_derivation() {
+        let secp = Secp256k1::new();
+        let mnemonic = Mnemonic::from_str("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .expect("Invalid mnemonic");
+        let xpriv =
+            xpriv_from_mnemonic(&mnemonic, None, Network::Regtest).expect("Key derivation failed");
+
+        // Receive keys derived from the account xpub must match the ones we sign with
+        for script_type in ScriptType::ALL {
+            let xpub = account_xpub(&secp, &xpriv, script_type).expect("Account xpub");
+            assert!(xpub.to_string().starts_with("[73c5da0a/"));
+
+            let public_key =
+                receive_public_key(&secp, &xpub.to_string(), 3).expect("Receive public key");
+            let private_key = derive_private_key(&secp, &xpriv, script_type, Keychain::External, 3)
+                .expect("Receive private key");
+            assert_eq!(public_key, private_key.public_key(&secp));
+        }
+    }
+}
@@ -0,0 +1,222 @@
+#[cfg(unix)]
+use std::fs::Permissions;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use argon2::Argon2;
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::secp256k1::rand::{rngs::OsRng, RngCore};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+/// First line of every encrypted key file. It doubles as associated data for the AEAD so that
+/// a file can't be decrypted under a different format version.
+const HEADER: &str = "tx-fun encrypted key v1";
+
+/// Environment variable holding the password used to unlock (or create) a key file.
+pub const PASSWORD_ENV: &str = "TX_FUN_PASSWORD";
+/// Environment variable holding the replacement password for `change-password`.
+pub const NEW_PASSWORD_ENV: &str = "TX_FUN_NEW_PASSWORD";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Reads the secret stored at `path`, decrypting it first if needed. Plaintext files are still
+/// accepted so that keys created before encryption keep working until they are migrated.
+pub fn read_secret<P: AsRef<Path>>(path: P) -> Result<String, Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    if !is_encrypted(&contents) {
+        eprintln!(
+            "Warning: {} is not encrypted. Run `tx-fun encrypt-key` to protect it.",
+            path.display()
+        );
+        return Ok(contents.trim().to_string());
+    }
+
+    let password = unlock_password(PASSWORD_ENV, path)?;
+    decrypt(&contents, &password)
+}
+
+/// Encrypts `secret` under `password` and writes it to `path`, replacing any existing file.
+pub fn write_secret<P: AsRef<Path>>(
+    path: P,
+    secret: &str,
+    password: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Only the owner may read the key, even though it is encrypted
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to new files, so tighten older plaintext files being encrypted too
+    #[cfg(unix)]
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(encrypt(secret, password)?.as_bytes())?;
+
+    Ok(())
+}
+
+pub fn encrypt_key(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    if is_encrypted(&contents) {
+        println!("{} is already encrypted", path.display());
+        return Ok(());
+    }
+
+    let password = new_password(PASSWORD_ENV)?;
+    write_secret(path, contents.trim(), &password)?;
+    println!("Encrypted {}", path.display());
+
+    Ok(())
+}
+
+pub fn change_password(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    if !is_encrypted(&contents) {
+        return Err(format!(
+            "{} is not encrypted. Run `tx-fun encrypt-key` first",
+            path.display()
+        )
+        .into());
+    }
+
+    let secret = decrypt(&contents, &unlock_password(PASSWORD_ENV, path)?)?;
+    let password = new_password(NEW_PASSWORD_ENV)?;
+    write_secret(path, &secret, &password)?;
+    println!("Password changed for {}", path.display());
+
+    Ok(())
+}
+
+pub fn is_encrypted_file<P: AsRef<Path>>(path: P) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(is_encrypted(&contents))
+}
+
+fn is_encrypted(contents: &str) -> bool {
+    contents.lines().next() == Some(HEADER)
+}
+
+fn unlock_password(env: &str, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    match std::env::var(env) {
+        Ok(password) => Ok(password),
+        Err(_) => Ok(rpassword::prompt_password(format!(
+            "Password for {}: ",
+            path.display()
+        ))?),
+    }
+}
+
+/// Gets a password for a newly encrypted file, either from `env` or by prompting twice.
+pub fn new_password(env: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if let Ok(password) = std::env::var(env) {
+        if password.is_empty() {
+            return Err(format!("{} must not be empty", env).into());
+        }
+        return Ok(password);
+    }
+
+    let password = rpassword::prompt_password("New password: ")?;
+    if password.is_empty() {
+        return Err("Password must not be empty".into());
+    }
+    if rpassword::prompt_password("Confirm password: ")? != password {
+        return Err("Passwords do not match".into());
+    }
+
+    Ok(password)
+}
+
+fn derive_cipher(
+    password: &str,
+    salt: &[u8],
+) -> Result<ChaCha20Poly1305, Box<dyn std::error::Error>> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn encrypt(secret: &str, password: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = derive_cipher(password, &salt)?
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: secret.as_bytes(),
+                aad: HEADER.as_bytes(),
+            },
+        )
+        .map_err(|_| "Encryption failed")?;
+
+    Ok(format!(
+        "{}\n{}\n{}\n{}\n",
+        HEADER,
+        salt.to_lower_hex_string(),
+        nonce.to_lower_hex_string(),
+        ciphertext.to_lower_hex_string()
+    ))
+}
+
+fn decrypt(contents: &str, password: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut lines = contents.lines().skip(1);
+    let mut next_field = |name: &str| -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let line = lines
+            .next()
+            .ok_or(format!("Encrypted key file is missing its {}", name))?;
+        Ok(Vec::from_hex(line.trim())?)
+    };
+    let salt = next_field("salt")?;
+    let nonce = next_field("nonce")?;
+    let ciphertext = next_field("ciphertext")?;
+    if nonce.len() != NONCE_LEN {
+        return Err("Encrypted key file has an invalid nonce".into());
+    }
+
+    let plaintext = derive_cipher(password, &salt)?
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: HEADER.as_bytes(),
+            },
+        )
+        .map_err(|_| "Wrong password, or the key file is corrupted")?;
+
+    Ok(String::from_utf8(plaintext)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt, encrypt, is_encrypted};
+
+    #[test]
+    fn test_encrypt_roundtrip() {
+        let secret = "tprv8ZgxMBicQKsPdE9CKqUW84nQ7o529Cn3oyBpC6mAYPybohfSWVu5UbvhJ38orCjVDuJsztvh37uQuUXi6F25BGWXrtTmBWuAJpNDa8spieL";
+        let encrypted = encrypt(secret, "hunter2").expect("Encryption failed");
+
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains(secret));
+        assert_eq!(
+            decrypt(&encrypted, "hunter2").expect("Decryption failed"),
+            secret
+        );
+        assert!(decrypt(&encrypted, "hunter3").is_err());
+    }
+}
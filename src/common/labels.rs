@@ -0,0 +1,269 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// What a BIP329 label is attached to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LabelType {
+    Tx,
+    Addr,
+    Pubkey,
+    /// An outpoint spent by one of our transactions
+    Input,
+    /// An outpoint created by a transaction, `txid:vout`
+    Output,
+    Xpub,
+}
+
+impl LabelType {
+    pub const ALL: [LabelType; 6] = [
+        LabelType::Tx,
+        LabelType::Addr,
+        LabelType::Pubkey,
+        LabelType::Input,
+        LabelType::Output,
+        LabelType::Xpub,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LabelType::Tx => "tx",
+            LabelType::Addr => "addr",
+            LabelType::Pubkey => "pubkey",
+            LabelType::Input => "input",
+            LabelType::Output => "output",
+            LabelType::Xpub => "xpub",
+        }
+    }
+}
+
+impl FromStr for LabelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LabelType::ALL
+            .into_iter()
+            .find(|label_type| label_type.name() == s)
+            .ok_or(format!("Unknown label type `{}`", s))
+    }
+}
+
+/// One line of a BIP329 export. Fields we don't use, such as `origin` or `spendable`, are kept
+/// so that labels from other wallets survive a round trip.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Label {
+    #[serde(rename = "type")]
+    label_type: String,
+    #[serde(rename = "ref")]
+    reference: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+/// Labels on transactions, addresses and outputs, persisted in the BIP329 JSONL format so they
+/// can be exchanged with other wallets.
+#[derive(Default)]
+pub struct Labels {
+    path: PathBuf,
+    labels: Vec<Label>,
+}
+
+impl Labels {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let labels = match path.exists() {
+            true => parse_jsonl(&fs::read_to_string(path)?)?,
+            false => Vec::new(),
+        };
+
+        Ok(Labels {
+            path: path.to_path_buf(),
+            labels,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, to_jsonl(&self.labels)?)?;
+        fs::rename(tmp_path, &self.path)?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, label_type: LabelType, reference: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|label| label.label_type == label_type.name() && label.reference == reference)
+            .and_then(|label| label.label.as_deref())
+    }
+
+    /// Sets the label of `reference`, replacing any it had.
+    pub fn set(&mut self, label_type: LabelType, reference: &str, text: &str) {
+        self.insert(Label {
+            label_type: label_type.name().to_string(),
+            reference: reference.to_string(),
+            label: Some(text.to_string()),
+            other: Map::new(),
+        });
+    }
+
+    /// Merges labels from another BIP329 file, which win over the ones we have. Returns how many
+    /// were read.
+    pub fn import(&mut self, path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+        let imported = parse_jsonl(&fs::read_to_string(path)?)?;
+        let count = imported.len();
+        for label in imported {
+            self.insert(label);
+        }
+
+        Ok(count)
+    }
+
+    pub fn export(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, to_jsonl(&self.labels)?)?;
+
+        Ok(())
+    }
+
+    fn insert(&mut self, label: Label) {
+        match self.labels.iter_mut().find(|existing| {
+            existing.label_type == label.label_type && existing.reference == label.reference
+        }) {
+            Some(existing) => *existing = label,
+            None => self.labels.push(label),
+        }
+    }
+}
+
+fn parse_jsonl(jsonl: &str) -> Result<Vec<Label>, Box<dyn std::error::Error>> {
+    let mut labels = Vec::new();
+    for (i, line) in jsonl.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let label: Label = serde_json::from_str(line)
+            .map_err(|e| format!("Invalid label on line {}: {}", i + 1, e))?;
+        LabelType::from_str(&label.label_type)
+            .map_err(|e| format!("Invalid label on line {}: {}", i + 1, e))?;
+        labels.push(label);
+    }
+
+    Ok(labels)
+}
+
+fn to_jsonl(labels: &[Label]) -> Result<String, Box<dyn std::error::Error>> {
+    let mut jsonl = String::new();
+    for label in labels {
+        jsonl.push_str(&serde_json::to_string(label)?);
+        jsonl.push('\n');
+    }
+
+    Ok(jsonl)
+}
+
+/// Labels `reference` and saves the labels file.
+pub fn set_label(
+    labels_path: &Path,
+    label_type: LabelType,
+    reference: &str,
+    text: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut labels = Labels::load(labels_path)?;
+    labels.set(label_type, reference, text);
+    labels.save()?;
+
+    println!("Labeled {} {}: {}", label_type.name(), reference, text);
+
+    Ok(())
+}
+
+pub fn list_labels(labels_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let labels = Labels::load(labels_path)?;
+    if labels.labels.is_empty() {
+        println!("No labels. Run `tx-fun label set <type> <ref> <label>` to add one");
+    }
+    for label in &labels.labels {
+        println!(
+            "{} {}: {}",
+            label.label_type,
+            label.reference,
+            label.label.as_deref().unwrap_or("")
+        );
+    }
+
+    Ok(())
+}
+
+pub fn import_labels(labels_path: &Path, file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut labels = Labels::load(labels_path)?;
+    let count = labels.import(file)?;
+    labels.save()?;
+
+    println!("Imported {} label(s) from {}", count, file.display());
+
+    Ok(())
+}
+
+pub fn export_labels(labels_path: &Path, file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let labels = Labels::load(labels_path)?;
+    labels.export(file)?;
+
+    println!(
+        "Exported {} label(s) to {}",
+        labels.labels.len(),
+        file.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_jsonl, to_jsonl, LabelType, Labels};
+
+    #[test]
+    fn test_bip329_round_trip() {
+        // From the BIP329 test vectors, including fields we don't use
+        let jsonl = r#"{"type":"tx","ref":"f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd","label":"Transaction","origin":"wpkh([d34db33f/84'/0'/0'])"}
+{"type":"addr","ref":"bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c","label":"Address"}
+
+{"type":"output","ref":"f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:0","label":"Output","spendable":false}
+"#;
+        let parsed = parse_jsonl(jsonl).expect("Valid labels");
+        assert_eq!(parsed.len(), 3);
+        let mut labels = Labels {
+            labels: parsed,
+            ..Default::default()
+        };
+        assert_eq!(
+            labels.get(
+                LabelType::Output,
+                "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:0"
+            ),
+            Some("Output")
+        );
+
+        // Setting a label replaces the old one in place and keeps the other fields of the rest
+        labels.set(
+            LabelType::Addr,
+            "bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c",
+            "Savings",
+        );
+        let exported = to_jsonl(&labels.labels).expect("Serialized labels");
+        let lines = exported.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains(r#""origin":"wpkh([d34db33f/84'/0'/0'])""#));
+        assert!(lines[1].contains(r#""label":"Savings""#));
+        assert!(lines[2].contains(r#""spendable":false"#));
+
+        assert!(parse_jsonl(r#"{"type":"coin","ref":"x","label":"y"}"#).is_err());
+    }
+}
@@ -1 +1,15 @@
+pub mod broadcast;
+pub mod checks;
+pub mod coin_selection;
+pub mod entropy;
+pub mod fees;
+pub mod history;
+pub mod key_formats;
+pub mod keyring;
 pub mod keys;
+pub mod keystore;
+pub mod labels;
+pub mod slip39;
+pub mod timelocks;
+pub mod utxos;
+pub mod wallet;
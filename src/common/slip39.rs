@@ -0,0 +1,596 @@
+//! SLIP-39 Shamir backups: splitting a master secret into mnemonic shares, any `threshold` of
+//! which recover it. See https://github.com/satoshilabs/slips/blob/master/slip-0039.md
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use bitcoin::secp256k1::rand::RngCore;
+
+const WORDLIST: &str = include_str!("slip39_wordlist.txt");
+
+const RADIX_BITS: usize = 10;
+const RADIX: u32 = 1 << RADIX_BITS;
+/// Identifier, extendable flag and iteration exponent, then the group and member parameters
+const HEADER_WORDS: usize = 4;
+const CHECKSUM_WORDS: usize = 3;
+const MIN_SECRET_LEN: usize = 16;
+const MAX_SHARE_COUNT: u8 = 16;
+
+const BASE_ITERATION_COUNT: u32 = 10000;
+const ROUND_COUNT: u8 = 4;
+/// Iteration exponent used for new shares, the same default as the reference implementation
+const ITERATION_EXPONENT: u8 = 1;
+
+const SECRET_INDEX: u8 = 255;
+const DIGEST_INDEX: u8 = 254;
+const DIGEST_LEN: usize = 4;
+
+/// An `M-of-N` sharing scheme: `N` shares, any `M` of which recover the secret.
+#[derive(Clone, Copy, Debug)]
+pub struct Scheme {
+    pub threshold: u8,
+    pub count: u8,
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid share scheme `{}`. Use `M-of-N`, like `2-of-3`", s);
+        let (threshold, count) = s.split_once("-of-").ok_or_else(invalid)?;
+        let scheme = Scheme {
+            threshold: threshold.parse().map_err(|_| invalid())?,
+            count: count.parse().map_err(|_| invalid())?,
+        };
+
+        if scheme.threshold == 0 || scheme.threshold > scheme.count {
+            return Err("The threshold must be between 1 and the number of shares".to_string());
+        }
+        if scheme.count > MAX_SHARE_COUNT {
+            return Err(format!("At most {} shares are supported", MAX_SHARE_COUNT));
+        }
+        if scheme.threshold == 1 && scheme.count > 1 {
+            return Err(
+                "A 1-of-N scheme just copies the secret. Use a threshold of 2 or more".into(),
+            );
+        }
+
+        Ok(scheme)
+    }
+}
+
+/// A single decoded share.
+struct Share {
+    identifier: u16,
+    extendable: bool,
+    iteration_exponent: u8,
+    group_index: u8,
+    group_threshold: u8,
+    group_count: u8,
+    member_index: u8,
+    member_threshold: u8,
+    value: Vec<u8>,
+}
+
+impl Share {
+    fn customization_string(extendable: bool) -> &'static [u8] {
+        match extendable {
+            true => b"shamir_extendable",
+            false => b"shamir",
+        }
+    }
+
+    fn to_mnemonic(&self) -> String {
+        let id_exp = (self.identifier as u32) << 5
+            | (self.extendable as u32) << 4
+            | self.iteration_exponent as u32;
+        let params = (self.group_index as u32) << 16
+            | (self.group_threshold as u32 - 1) << 12
+            | (self.group_count as u32 - 1) << 8
+            | (self.member_index as u32) << 4
+            | (self.member_threshold as u32 - 1);
+
+        let mut data = vec![
+            (id_exp >> RADIX_BITS) as u16,
+            (id_exp % RADIX) as u16,
+            (params >> RADIX_BITS) as u16,
+            (params % RADIX) as u16,
+        ];
+        data.extend(bytes_to_words(&self.value));
+        let checksum = rs1024_create_checksum(Self::customization_string(self.extendable), &data);
+        data.extend(checksum);
+
+        let wordlist: Vec<&str> = WORDLIST.lines().collect();
+        data.iter()
+            .map(|&index| wordlist[index as usize])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn from_mnemonic(mnemonic: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let wordlist: Vec<&str> = WORDLIST.lines().collect();
+        let data = mnemonic
+            .split_whitespace()
+            .map(|word| {
+                let word = word.to_lowercase();
+                wordlist
+                    .binary_search(&word.as_str())
+                    .map(|index| index as u16)
+                    .map_err(|_| format!("`{}` is not a SLIP-39 word", word))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let min_words = HEADER_WORDS + CHECKSUM_WORDS + (MIN_SECRET_LEN * 8).div_ceil(RADIX_BITS);
+        if data.len() < min_words {
+            return Err(format!("Shares must have at least {} words", min_words).into());
+        }
+
+        let id_exp = (data[0] as u32) << RADIX_BITS | data[1] as u32;
+        let extendable = (id_exp >> 4) & 1 == 1;
+        if !rs1024_verify_checksum(Self::customization_string(extendable), &data) {
+            return Err(format!(
+                "Invalid checksum in the share starting with `{}`",
+                mnemonic.split_whitespace().next().unwrap_or_default()
+            )
+            .into());
+        }
+
+        let params = (data[2] as u32) << RADIX_BITS | data[3] as u32;
+        let share = Share {
+            identifier: (id_exp >> 5) as u16,
+            extendable,
+            iteration_exponent: (id_exp & 0xf) as u8,
+            group_index: (params >> 16) as u8,
+            group_threshold: ((params >> 12) & 0xf) as u8 + 1,
+            group_count: ((params >> 8) & 0xf) as u8 + 1,
+            member_index: ((params >> 4) & 0xf) as u8,
+            member_threshold: (params & 0xf) as u8 + 1,
+            value: words_to_bytes(&data[HEADER_WORDS..data.len() - CHECKSUM_WORDS])?,
+        };
+        if share.group_threshold > share.group_count {
+            return Err("Share has a group threshold above its group count".into());
+        }
+
+        Ok(share)
+    }
+}
+
+/// Splits `master_secret` into the mnemonic shares of a single group. `passphrase` encrypts
+/// the secret, so the same passphrase is needed to recover it.
+pub fn split(
+    master_secret: &[u8],
+    passphrase: &str,
+    scheme: Scheme,
+    rng: &mut dyn RngCore,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if master_secret.len() < MIN_SECRET_LEN || !master_secret.len().is_multiple_of(2) {
+        return Err("The master secret must be an even number of bytes, at least 16".into());
+    }
+    check_passphrase(passphrase)?;
+
+    let identifier = (rng.next_u32() & 0x7fff) as u16;
+    let encrypted = crypt(
+        master_secret,
+        passphrase,
+        ITERATION_EXPONENT,
+        identifier,
+        true,
+        false,
+    );
+
+    let shares = split_secret(scheme.threshold, scheme.count, &encrypted, rng)
+        .into_iter()
+        .map(|(member_index, value)| {
+            Share {
+                identifier,
+                extendable: true,
+                iteration_exponent: ITERATION_EXPONENT,
+                group_index: 0,
+                group_threshold: 1,
+                group_count: 1,
+                member_index,
+                member_threshold: scheme.threshold,
+                value,
+            }
+            .to_mnemonic()
+        })
+        .collect();
+
+    Ok(shares)
+}
+
+/// Recovers the master secret from enough mnemonic shares.
+pub fn combine(
+    mnemonics: &[String],
+    passphrase: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    check_passphrase(passphrase)?;
+    let shares = mnemonics
+        .iter()
+        .map(|mnemonic| Share::from_mnemonic(mnemonic))
+        .collect::<Result<Vec<_>, _>>()?;
+    let first = shares.first().ok_or("No shares given")?;
+
+    let mut groups: BTreeMap<u8, Vec<&Share>> = BTreeMap::new();
+    for share in &shares {
+        if share.identifier != first.identifier
+            || share.extendable != first.extendable
+            || share.iteration_exponent != first.iteration_exponent
+            || share.group_threshold != first.group_threshold
+            || share.group_count != first.group_count
+            || share.value.len() != first.value.len()
+        {
+            return Err("The shares belong to different backups".into());
+        }
+        let group = groups.entry(share.group_index).or_default();
+        if group
+            .iter()
+            .any(|s| s.member_threshold != share.member_threshold)
+        {
+            return Err("Shares of the same group have different thresholds".into());
+        }
+        if group.iter().any(|s| s.member_index == share.member_index) {
+            continue;
+        }
+        group.push(share);
+    }
+
+    let mut group_secrets = Vec::new();
+    for (group_index, members) in &groups {
+        let threshold = members[0].member_threshold;
+        if members.len() < threshold as usize {
+            continue;
+        }
+        let members = members
+            .iter()
+            .take(threshold as usize)
+            .map(|share| (share.member_index, share.value.clone()))
+            .collect::<Vec<_>>();
+        group_secrets.push((*group_index, recover_secret(threshold, &members)?));
+    }
+
+    if group_secrets.len() < first.group_threshold as usize {
+        let missing = match groups.values().next() {
+            Some(members) if first.group_count == 1 => {
+                format!(
+                    "{} more share(s)",
+                    members[0].member_threshold as usize - members.len()
+                )
+            }
+            _ => format!(
+                "{} more complete group(s)",
+                first.group_threshold as usize - group_secrets.len()
+            ),
+        };
+        return Err(format!("Not enough shares to recover the secret. Need {}", missing).into());
+    }
+    group_secrets.truncate(first.group_threshold as usize);
+    let encrypted = recover_secret(first.group_threshold, &group_secrets)?;
+
+    Ok(crypt(
+        &encrypted,
+        passphrase,
+        first.iteration_exponent,
+        first.identifier,
+        first.extendable,
+        true,
+    ))
+}
+
+fn check_passphrase(passphrase: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if !passphrase.bytes().all(|b| (32..=126).contains(&b)) {
+        return Err("SLIP-39 passphrases may only contain printable ASCII characters".into());
+    }
+
+    Ok(())
+}
+
+/// Packs bytes into 10-bit words, padding with zero bits at the front.
+fn bytes_to_words(bytes: &[u8]) -> Vec<u16> {
+    let word_count = (bytes.len() * 8).div_ceil(RADIX_BITS);
+    let mut acc: u32 = 0;
+    let mut bits = word_count * RADIX_BITS - bytes.len() * 8;
+    let mut words = Vec::with_capacity(word_count);
+    for byte in bytes {
+        acc = acc << 8 | *byte as u32;
+        bits += 8;
+        while bits >= RADIX_BITS {
+            bits -= RADIX_BITS;
+            words.push((acc >> bits) as u16 & (RADIX - 1) as u16);
+        }
+        acc &= (1 << bits) - 1;
+    }
+
+    words
+}
+
+fn words_to_bytes(words: &[u16]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let padding = (words.len() * RADIX_BITS) % 16;
+    if padding > 8 {
+        return Err("Invalid share length".into());
+    }
+
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut bytes = Vec::new();
+    let mut remaining_padding = padding;
+    for word in words {
+        acc = acc << RADIX_BITS | *word as u32;
+        bits += RADIX_BITS;
+        if remaining_padding > 0 {
+            bits -= remaining_padding;
+            if acc >> bits != 0 {
+                return Err("Invalid padding in share".into());
+            }
+            remaining_padding = 0;
+        }
+        while bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+        acc &= (1 << bits) - 1;
+    }
+
+    Ok(bytes)
+}
+
+fn rs1024_polymod(values: impl IntoIterator<Item = u32>) -> u32 {
+    const GEN: [u32; 10] = [
+        0xE0E040, 0x1C1C080, 0x3838100, 0x7070200, 0xE0E0009, 0x1C0C2412, 0x38086C24, 0x3090FC48,
+        0x21B1F890, 0x3F3F120,
+    ];
+    let mut chk = 1;
+    for value in values {
+        let b = chk >> 20;
+        chk = (chk & 0xFFFFF) << 10 ^ value;
+        for (i, gen) in GEN.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= gen;
+            }
+        }
+    }
+
+    chk
+}
+
+fn rs1024_create_checksum(customization: &[u8], data: &[u16]) -> [u16; CHECKSUM_WORDS] {
+    let values = customization
+        .iter()
+        .map(|b| *b as u32)
+        .chain(data.iter().map(|w| *w as u32))
+        .chain([0; CHECKSUM_WORDS]);
+    let polymod = rs1024_polymod(values) ^ 1;
+
+    [2, 1, 0].map(|i| ((polymod >> (RADIX_BITS * i)) & (RADIX - 1)) as u16)
+}
+
+fn rs1024_verify_checksum(customization: &[u8], data: &[u16]) -> bool {
+    let values = customization
+        .iter()
+        .map(|b| *b as u32)
+        .chain(data.iter().map(|w| *w as u32));
+    rs1024_polymod(values) == 1
+}
+
+/// Exponent and logarithm tables of GF(256) with the Rijndael polynomial, generator 3.
+fn gf256_tables() -> ([u8; 255], [u8; 256]) {
+    let mut exp = [0u8; 255];
+    let mut log = [0u8; 256];
+    let mut poly: u16 = 1;
+    for (i, e) in exp.iter_mut().enumerate() {
+        *e = poly as u8;
+        log[poly as usize] = i as u8;
+        poly = (poly << 1) ^ poly;
+        if poly & 0x100 != 0 {
+            poly ^= 0x11B;
+        }
+    }
+
+    (exp, log)
+}
+
+/// Evaluates at `x` the polynomial going through `shares`, byte by byte.
+fn interpolate(shares: &[(u8, Vec<u8>)], x: u8) -> Vec<u8> {
+    if let Some((_, value)) = shares.iter().find(|(share_x, _)| *share_x == x) {
+        return value.clone();
+    }
+
+    let (exp, log) = gf256_tables();
+    let log_prod: u32 = shares
+        .iter()
+        .map(|(share_x, _)| log[(share_x ^ x) as usize] as u32)
+        .sum();
+
+    let mut result = vec![0u8; shares[0].1.len()];
+    for (share_x, value) in shares {
+        let log_others: u32 = shares
+            .iter()
+            .filter(|(other_x, _)| other_x != share_x)
+            .map(|(other_x, _)| log[(share_x ^ other_x) as usize] as u32)
+            .sum();
+        let log_basis = (log_prod + 255 * shares.len() as u32
+            - log[(share_x ^ x) as usize] as u32
+            - log_others)
+            % 255;
+
+        for (r, y) in result.iter_mut().zip(value) {
+            if *y != 0 {
+                *r ^= exp[((log[*y as usize] as u32 + log_basis) % 255) as usize];
+            }
+        }
+    }
+
+    result
+}
+
+fn share_digest(random_part: &[u8], secret: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(random_part);
+    engine.input(secret);
+    let hmac = hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array();
+
+    hmac[..DIGEST_LEN].try_into().expect("Digest length")
+}
+
+fn split_secret(
+    threshold: u8,
+    count: u8,
+    secret: &[u8],
+    rng: &mut dyn RngCore,
+) -> Vec<(u8, Vec<u8>)> {
+    if threshold == 1 {
+        return (0..count).map(|i| (i, secret.to_vec())).collect();
+    }
+
+    let random_value = |rng: &mut dyn RngCore, len: usize| {
+        let mut value = vec![0u8; len];
+        rng.fill_bytes(&mut value);
+        value
+    };
+    let random_share_count = threshold - 2;
+    let mut shares: Vec<(u8, Vec<u8>)> = (0..random_share_count)
+        .map(|i| (i, random_value(rng, secret.len())))
+        .collect();
+
+    // The digest lets recovery detect a wrong set of shares
+    let random_part = random_value(rng, secret.len() - DIGEST_LEN);
+    let mut digest = share_digest(&random_part, secret).to_vec();
+    digest.extend(random_part);
+
+    let mut base_shares = shares.clone();
+    base_shares.push((DIGEST_INDEX, digest));
+    base_shares.push((SECRET_INDEX, secret.to_vec()));
+    for i in random_share_count..count {
+        shares.push((i, interpolate(&base_shares, i)));
+    }
+
+    shares
+}
+
+fn recover_secret(
+    threshold: u8,
+    shares: &[(u8, Vec<u8>)],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if threshold == 1 {
+        return Ok(shares[0].1.clone());
+    }
+
+    let secret = interpolate(shares, SECRET_INDEX);
+    let digest = interpolate(shares, DIGEST_INDEX);
+    if digest[..DIGEST_LEN] != share_digest(&digest[DIGEST_LEN..], &secret) {
+        return Err(
+            "Invalid digest of the shared secret. Are all shares from the same backup?".into(),
+        );
+    }
+
+    Ok(secret)
+}
+
+/// The PBKDF2-HMAC-SHA256 key derivation function.
+fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32, len: usize) -> Vec<u8> {
+    let prf = |message: &[&[u8]]| {
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(password);
+        for part in message {
+            engine.input(part);
+        }
+        hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+    };
+
+    let mut output = Vec::new();
+    let mut block: u32 = 1;
+    while output.len() < len {
+        let mut u = prf(&[salt, &block.to_be_bytes()]);
+        let mut t = u;
+        for _ in 1..iterations {
+            u = prf(&[&u]);
+            t.iter_mut().zip(u).for_each(|(t, u)| *t ^= u);
+        }
+        output.extend(t);
+        block += 1;
+    }
+    output.truncate(len);
+
+    output
+}
+
+/// Encrypts, or decrypts, the master secret with the four round Feistel network of SLIP-39.
+fn crypt(
+    secret: &[u8],
+    passphrase: &str,
+    iteration_exponent: u8,
+    identifier: u16,
+    extendable: bool,
+    decrypt: bool,
+) -> Vec<u8> {
+    let half = secret.len() / 2;
+    let (mut left, mut right) = (secret[..half].to_vec(), secret[half..].to_vec());
+    let mut salt_prefix = Vec::new();
+    if !extendable {
+        salt_prefix.extend(b"shamir");
+        salt_prefix.extend(identifier.to_be_bytes());
+    }
+    let iterations = (BASE_ITERATION_COUNT / ROUND_COUNT as u32) << iteration_exponent;
+
+    let rounds: Vec<u8> = match decrypt {
+        true => (0..ROUND_COUNT).rev().collect(),
+        false => (0..ROUND_COUNT).collect(),
+    };
+    for round in rounds {
+        let mut password = vec![round];
+        password.extend(passphrase.as_bytes());
+        let mut salt = salt_prefix.clone();
+        salt.extend(&right);
+
+        let f = pbkdf2(&password, &salt, iterations, half);
+        let new_right = left.iter().zip(f).map(|(l, f)| l ^ f).collect();
+        left = std::mem::replace(&mut right, new_right);
+    }
+
+    right.extend(left);
+    right
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hex::DisplayHex;
+    use bitcoin::secp256k1::rand::rngs::OsRng;
+
+    use super::{combine, split, Scheme};
+
+    #[test]
+    fn test_slip39_vectors() {
+        // SLIP-39 test vectors 1 (1-of-1) and 4 (2-of-3), passphrase "TREZOR"
+        let single = ["duckling enlarge academic academic agency result length solution fridge kidney coal piece deal husband erode duke ajar critical decision keyboard".to_string()];
+        assert_eq!(
+            combine(&single, "TREZOR")
+                .expect("Recovery failed")
+                .to_lower_hex_string(),
+            "bb54aac4b89dc868ba37d9cc21b2cece"
+        );
+
+        let two_of_three = [
+            "shadow pistol academic always adequate wildlife fancy gross oasis cylinder mustang wrist rescue view short owner flip making coding armed".to_string(),
+            "shadow pistol academic acid actress prayer class unknown daughter sweater depict flip twice unkind craft early superior advocate guest smoking".to_string(),
+        ];
+        assert_eq!(
+            combine(&two_of_three, "TREZOR")
+                .expect("Recovery failed")
+                .to_lower_hex_string(),
+            "b43ceb7e57a0ea8766221624d01b0864"
+        );
+        assert!(combine(&two_of_three[..1], "TREZOR").is_err());
+    }
+
+    #[test]
+    fn test_split_and_combine() {
+        let secret = [7u8; 32];
+        let scheme = "3-of-5".parse::<Scheme>().expect("Scheme");
+        let shares = split(&secret, "", scheme, &mut OsRng).expect("Split failed");
+        assert_eq!(shares.len(), 5);
+        assert_eq!(shares[0].split_whitespace().count(), 33);
+
+        let any_three = [shares[4].clone(), shares[0].clone(), shares[2].clone()];
+        assert_eq!(combine(&any_three, "").expect("Recovery failed"), secret);
+        assert!(combine(&shares[..2], "").is_err());
+    }
+}
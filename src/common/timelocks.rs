@@ -0,0 +1,364 @@
+use bitcoin::{absolute::LockTime, relative, secp256k1::rand::RngCore, OutPoint, Sequence, TxOut};
+use electrum_client::{Client, ElectrumApi};
+
+use crate::common::{checks, utxos};
+
+/// How many blocks back the median time past looks (BIP113).
+const MEDIAN_TIME_SPAN: u32 = 11;
+
+/// How often, and by up to how many blocks, an anti-fee-sniping locktime is set back. Matches
+/// Bitcoin Core, so transactions that were slow to reach us don't stand out.
+const BACKDATE_ONE_IN: u32 = 10;
+const MAX_BACKDATE: u32 = 100;
+
+/// The nLockTime of a transaction and the nSequence of its inputs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timelocks {
+    pub lock_time: LockTime,
+    /// One per input, or a single one shared by every input. With none, inputs signal RBF
+    /// without a relative timelock
+    pub sequences: Vec<Sequence>,
+}
+
+impl Default for Timelocks {
+    fn default() -> Self {
+        Timelocks {
+            lock_time: LockTime::ZERO,
+            sequences: Vec::new(),
+        }
+    }
+}
+
+impl Timelocks {
+    /// The nSequence of each of `count` inputs.
+    pub fn sequences(&self, count: usize) -> Result<Vec<Sequence>, Box<dyn std::error::Error>> {
+        match self.sequences.as_slice() {
+            // Signals BIP125 replaceability, so `bump-fee` can replace it if it gets stuck
+            [] => Ok(vec![Sequence::ENABLE_RBF_NO_LOCKTIME; count]),
+            [sequence] => Ok(vec![*sequence; count]),
+            sequences if sequences.len() == count => Ok(sequences.to_vec()),
+            sequences => Err(format!(
+                "Got {} sequences for {} inputs. Give one for every input, or a single one for all",
+                sequences.len(),
+                count
+            )
+            .into()),
+        }
+    }
+
+    /// Timelocks with `sequences` and, like Bitcoin Core, a locktime of the chain tip. A miner
+    /// who reorgs the tip to take its fees can't include the transaction in the replacement
+    /// block. Left at zero when every sequence is final, since it would have no effect.
+    pub fn anti_fee_sniping(
+        sequences: Vec<Sequence>,
+        electrum_client: &Client,
+        rng: &mut dyn RngCore,
+    ) -> Result<Timelocks, Box<dyn std::error::Error>> {
+        let lock_time = match sequences.is_empty()
+            || sequences.iter().any(Sequence::enables_absolute_lock_time)
+        {
+            true => anti_fee_sniping_lock_time(utxos::tip_height(electrum_client)?, rng),
+            false => LockTime::ZERO,
+        };
+
+        Ok(Timelocks {
+            lock_time,
+            sequences,
+        })
+    }
+}
+
+/// The tip height, set back by a random number of blocks one time in `BACKDATE_ONE_IN`.
+fn anti_fee_sniping_lock_time(tip_height: u32, rng: &mut dyn RngCore) -> LockTime {
+    let backdate = match rng.next_u32() % BACKDATE_ONE_IN {
+        0 => rng.next_u32() % MAX_BACKDATE,
+        _ => 0,
+    };
+
+    LockTime::from_height(tip_height.saturating_sub(backdate)).expect("Tip height below 500000000")
+}
+
+/// Parses `--locktime`: a block height, or a Unix time from 500000000 on.
+pub fn parse_lock_time(s: &str) -> Result<LockTime, String> {
+    let n = s
+        .parse::<u32>()
+        .map_err(|_| format!("`{}` is not a block height or Unix time", s))?;
+
+    Ok(LockTime::from_consensus(n))
+}
+
+/// Parses `--sequence`, a raw nSequence such as `4294967294` or `0xfffffffe`.
+pub fn parse_sequence(s: &str) -> Result<Sequence, String> {
+    let n = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse::<u32>(),
+    }
+    .map_err(|_| format!("`{}` is not a sequence number", s))?;
+
+    Ok(Sequence::from_consensus(n))
+}
+
+/// Parses `--csv`, a relative timelock of a number of blocks, or of seconds with an `s` suffix
+/// (rounded up to a multiple of 512).
+pub fn parse_csv(s: &str) -> Result<Sequence, String> {
+    match s.strip_suffix('s') {
+        Some(seconds) => {
+            let seconds = seconds
+                .parse::<u32>()
+                .map_err(|_| format!("`{}` is not a number of seconds", seconds))?;
+            Sequence::from_seconds_ceil(seconds).map_err(|e| e.to_string())
+        }
+        None => s
+            .parse::<u16>()
+            .map(Sequence::from_height)
+            .map_err(|_| format!("`{}` is not a number of blocks up to 65535", s)),
+    }
+}
+
+/// Where the chain stands, which decides whether a timelocked transaction can be mined in the
+/// next block.
+#[derive(Clone, Copy, Debug)]
+pub struct ChainState {
+    pub height: u32,
+    pub median_time_past: u32,
+}
+
+/// When a previous output confirmed: its height, and the median time past of the block before,
+/// which relative time locks count from.
+#[derive(Clone, Copy, Debug)]
+pub struct Confirmation {
+    pub height: u32,
+    pub median_time_past: u32,
+}
+
+/// Checks that a transaction with `timelocks`, spending `inputs`, can be mined in the next block,
+/// looking the chain tip and the inputs' confirmations up on the Electrum server. With `force`,
+/// problems are only warned about.
+pub fn check_timelocks(
+    timelocks: &Timelocks,
+    inputs: &[(OutPoint, TxOut)],
+    electrum_client: &Client,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sequences = timelocks.sequences(inputs.len())?;
+    let relative = sequences.iter().any(Sequence::is_relative_lock_time);
+    // Nothing to look up for an ordinary transaction
+    if timelocks.lock_time == LockTime::ZERO && !relative {
+        return Ok(());
+    }
+
+    let height = utxos::tip_height(electrum_client)?;
+    let chain = ChainState {
+        height,
+        median_time_past: median_time_past(electrum_client, height)?,
+    };
+    let mut confirmations = Vec::with_capacity(inputs.len());
+    for ((outpoint, txout), sequence) in inputs.iter().zip(&sequences) {
+        if !sequence.is_relative_lock_time() {
+            confirmations.push(None);
+            continue;
+        }
+        let history = electrum_client.script_get_history(&txout.script_pubkey)?;
+        let confirmation = match history.iter().find(|entry| entry.tx_hash == outpoint.txid) {
+            Some(entry) if entry.height > 0 => Some(Confirmation {
+                height: entry.height as u32,
+                median_time_past: median_time_past(electrum_client, entry.height as u32 - 1)?,
+            }),
+            _ => None,
+        };
+        confirmations.push(confirmation);
+    }
+
+    checks::report(
+        timelock_problems(timelocks.lock_time, &sequences, &chain, &confirmations),
+        force,
+    )
+}
+
+/// The median timestamp of the `MEDIAN_TIME_SPAN` blocks up to `height`.
+fn median_time_past(
+    electrum_client: &Client,
+    height: u32,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let start = height.saturating_sub(MEDIAN_TIME_SPAN - 1);
+    let headers = electrum_client.block_headers(start as usize, (height - start + 1) as usize)?;
+    let mut times = headers
+        .headers
+        .iter()
+        .map(|header| header.time)
+        .collect::<Vec<_>>();
+    times.sort();
+
+    times
+        .get(times.len() / 2)
+        .copied()
+        .ok_or_else(|| format!("No block headers up to height {}", height).into())
+}
+
+/// What keeps a transaction from being mined in the block after `chain`'s tip. `confirmations`
+/// has an entry for every input, which only matters for those with a relative timelock.
+fn timelock_problems(
+    lock_time: LockTime,
+    sequences: &[Sequence],
+    chain: &ChainState,
+    confirmations: &[Option<Confirmation>],
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    // A block can include transactions locked to a lower height than its own, or to a time
+    // before the median time past of its parent
+    match lock_time {
+        _ if lock_time == LockTime::ZERO => {}
+        _ if sequences
+            .iter()
+            .all(|sequence| !sequence.enables_absolute_lock_time()) =>
+        {
+            problems.push(format!(
+                "The locktime {} has no effect since every input's sequence is final",
+                lock_time
+            ))
+        }
+        LockTime::Blocks(height) if height.to_consensus_u32() > chain.height => {
+            problems.push(format!(
+                "The transaction is locked until block {} is mined, {} block(s) from now",
+                height,
+                height.to_consensus_u32() - chain.height
+            ))
+        }
+        LockTime::Seconds(time) if time.to_consensus_u32() >= chain.median_time_past => problems
+            .push(format!(
+                "The transaction is locked until the median time past is after {}, {} second(s) \
+                 from now",
+                time,
+                time.to_consensus_u32() - chain.median_time_past + 1
+            )),
+        _ => {}
+    }
+
+    for (index, (sequence, confirmation)) in sequences.iter().zip(confirmations).enumerate() {
+        let (Some(lock), Some(confirmation)) = (sequence.to_relative_lock_time(), confirmation)
+        else {
+            if sequence.is_relative_lock_time() {
+                problems.push(format!(
+                    "Input {} has a relative timelock but its previous output is unconfirmed",
+                    index
+                ));
+            }
+            continue;
+        };
+        match lock {
+            relative::LockTime::Blocks(blocks) => {
+                let unlock_height = confirmation.height + u32::from(blocks.value());
+                if unlock_height > chain.height + 1 {
+                    problems.push(format!(
+                        "Input {} is locked for {} more block(s)",
+                        index,
+                        unlock_height - chain.height - 1
+                    ));
+                }
+            }
+            relative::LockTime::Time(intervals) => {
+                let unlock_time =
+                    confirmation.median_time_past + u32::from(intervals.value()) * 512;
+                if unlock_time > chain.median_time_past {
+                    problems.push(format!(
+                        "Input {} is locked for {} more second(s) of median time past",
+                        index,
+                        unlock_time - chain.median_time_past
+                    ));
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{absolute::LockTime, secp256k1::rand::SeedableRng, Sequence};
+    use rand_chacha::ChaCha20Rng;
+
+    use super::{
+        anti_fee_sniping_lock_time, parse_csv, timelock_problems, ChainState, Confirmation,
+        Timelocks, MAX_BACKDATE,
+    };
+
+    #[test]
+    fn test_anti_fee_sniping_lock_time() {
+        let mut rng = ChaCha20Rng::from_seed([7; 32]);
+        let heights = (0..1000)
+            .map(|_| match anti_fee_sniping_lock_time(800_000, &mut rng) {
+                LockTime::Blocks(height) => height.to_consensus_u32(),
+                LockTime::Seconds(_) => panic!("Expected a height"),
+            })
+            .collect::<Vec<_>>();
+
+        // Mostly the tip, sometimes up to 99 blocks before it
+        assert!(heights
+            .iter()
+            .all(|h| (800_000 - MAX_BACKDATE..=800_000).contains(h)));
+        let at_tip = heights.iter().filter(|h| **h == 800_000).count();
+        assert!(at_tip > 850 && at_tip < 1000);
+        // Never set back past the genesis block
+        assert!((0..100).all(|_| anti_fee_sniping_lock_time(0, &mut rng) == LockTime::ZERO));
+    }
+
+    #[test]
+    fn test_timelock_problems() {
+        let chain = ChainState {
+            height: 200,
+            median_time_past: 1_700_000_000,
+        };
+        let rbf = [Sequence::ENABLE_RBF_NO_LOCKTIME];
+
+        // The next block is 201, which can include anything locked up to 200
+        let ok = timelock_problems(LockTime::from_consensus(200), &rbf, &chain, &[None]);
+        assert!(ok.is_empty());
+        let early = timelock_problems(LockTime::from_consensus(201), &rbf, &chain, &[None]);
+        assert_eq!(early.len(), 1);
+        assert!(early[0].contains("1 block(s)"));
+        let time = LockTime::from_consensus(1_700_000_000);
+        assert_eq!(timelock_problems(time, &rbf, &chain, &[None]).len(), 1);
+
+        // Final sequences disable the locktime altogether
+        let ignored = timelock_problems(
+            LockTime::from_consensus(100),
+            &[Sequence::MAX],
+            &chain,
+            &[None],
+        );
+        assert!(ignored[0].contains("no effect"));
+
+        // Coins confirmed at 195 can be spent with a CSV of 6 in block 201, but not 7
+        let confirmation = Some(Confirmation {
+            height: 195,
+            median_time_past: 1_699_990_000,
+        });
+        let csv = |s| [parse_csv(s).expect("Valid CSV")];
+        assert!(timelock_problems(LockTime::ZERO, &csv("6"), &chain, &[confirmation]).is_empty());
+        assert!(
+            timelock_problems(LockTime::ZERO, &csv("7"), &chain, &[confirmation])[0]
+                .contains("1 more block(s)")
+        );
+        // 10000 seconds of median time have passed since, 19 intervals of 512 are 9728
+        assert!(
+            timelock_problems(LockTime::ZERO, &csv("9728s"), &chain, &[confirmation]).is_empty()
+        );
+        assert_eq!(
+            timelock_problems(LockTime::ZERO, &csv("9729s"), &chain, &[confirmation]).len(),
+            1
+        );
+        assert_eq!(
+            timelock_problems(LockTime::ZERO, &csv("1"), &chain, &[None]).len(),
+            1
+        );
+
+        assert!(Timelocks {
+            lock_time: LockTime::ZERO,
+            sequences: vec![Sequence::MAX; 2],
+        }
+        .sequences(3)
+        .is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use std::path::Path;
+
+use bitcoin::{
+    hashes::{sha256, Hash},
+    hex::DisplayHex,
+    Address, Amount, OutPoint, ScriptBuf, TxOut, Txid,
+};
+use electrum_client::{Client, ElectrumApi, GetHistoryRes};
+
+use crate::common::keys::Keychain;
+use crate::common::labels::{LabelType, Labels};
+use crate::common::wallet::{CachedUtxo, ScriptCache, Wallet, WalletStore};
+
+/// An unspent output paying to one of a wallet's scripts.
+#[derive(Clone, Debug)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    pub keychain: Keychain,
+    pub index: u32,
+    /// Height of the block that confirmed it, or `None` while it is in the mempool
+    pub height: Option<u32>,
+}
+
+impl Utxo {
+    pub fn confirmations(&self, tip_height: u32) -> u32 {
+        self.height
+            .map_or(0, |height| tip_height.saturating_sub(height) + 1)
+    }
+}
+
+/// Lists the unspent outputs of every revealed (and lookahead) script of `wallet`, syncing the
+/// store with Electrum first.
+pub fn list_unspent(
+    wallet: &Wallet,
+    store: &mut WalletStore,
+    electrum_client: &Client,
+) -> Result<Vec<Utxo>, Box<dyn std::error::Error>> {
+    sync(wallet, store, electrum_client)?;
+
+    let mut utxos = Vec::new();
+    for (keychain, index, script_pubkey) in wallet.script_pubkeys(store)? {
+        let Some(cache) = store.script_cache(&wallet.id(), &script_pubkey) else {
+            continue;
+        };
+        for output in &cache.utxos {
+            utxos.push(Utxo {
+                outpoint: output.outpoint,
+                txout: TxOut {
+                    value: Amount::from_sat(output.value),
+                    script_pubkey: script_pubkey.clone(),
+                },
+                keychain,
+                index,
+                height: output.height,
+            });
+        }
+    }
+    utxos.sort_by_key(|utxo| (utxo.height.is_none(), utxo.height, utxo.outpoint));
+
+    Ok(utxos)
+}
+
+/// Brings the store's UTXOs and transactions for `wallet` up to date. Every script's Electrum
+/// status is fetched, but only the scripts whose status changed since the last sync have their
+/// history and unspent outputs refetched, and only transactions we have not seen yet are
+/// downloaded.
+pub fn sync(
+    wallet: &Wallet,
+    store: &mut WalletStore,
+    electrum_client: &Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let wallet_id = wallet.id();
+    let script_pubkeys = wallet
+        .script_pubkeys(store)?
+        .into_iter()
+        .map(|(_, _, script_pubkey)| script_pubkey)
+        .collect::<Vec<_>>();
+    let statuses = script_statuses(electrum_client, &script_pubkeys)?;
+
+    let changed = script_pubkeys
+        .iter()
+        .zip(statuses)
+        .filter(|(script_pubkey, status)| {
+            let cached = store.script_cache(&wallet_id, script_pubkey);
+            cached.and_then(|cache| cache.status.as_ref()) != status.as_ref()
+        })
+        .collect::<Vec<_>>();
+    // An empty batch would wait forever for a response
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    let histories =
+        electrum_client.batch_script_get_history(changed.iter().map(|(spk, _)| spk.as_script()))?;
+    let unspent = electrum_client
+        .batch_script_list_unspent(changed.iter().map(|(spk, _)| spk.as_script()))?;
+    let mut new_txids: Vec<(Txid, Option<u32>)> = Vec::new();
+    for (((script_pubkey, status), history), outputs) in
+        changed.into_iter().zip(histories).zip(unspent)
+    {
+        let utxos = outputs
+            .into_iter()
+            .map(|output| CachedUtxo {
+                outpoint: OutPoint::new(output.tx_hash, output.tx_pos as u32),
+                value: output.value,
+                height: confirmed_height(output.height as i64),
+            })
+            .collect();
+        store.set_script_cache(wallet, script_pubkey, ScriptCache { status, utxos });
+
+        for entry in history {
+            let height = confirmed_height(entry.height as i64);
+            if store.has_transaction(&entry.tx_hash) {
+                store.set_transaction_height(&entry.tx_hash, height);
+            } else if !new_txids.iter().any(|(txid, _)| *txid == entry.tx_hash) {
+                new_txids.push((entry.tx_hash, height));
+            }
+        }
+    }
+
+    if !new_txids.is_empty() {
+        let transactions =
+            electrum_client.batch_transaction_get(new_txids.iter().map(|(txid, _)| txid))?;
+        for (tx, (_, height)) in transactions.iter().zip(new_txids) {
+            store.add_transaction(tx, height);
+        }
+    }
+
+    Ok(())
+}
+
+/// The Electrum status of each of `script_pubkeys`, from subscribing to them. Scripts can only be
+/// subscribed to once per connection, so when an earlier sync in this run already did, the
+/// statuses are worked out from the scripts' histories instead.
+fn script_statuses(
+    electrum_client: &Client,
+    script_pubkeys: &[ScriptBuf],
+) -> Result<Vec<Option<String>>, Box<dyn std::error::Error>> {
+    if script_pubkeys.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    match electrum_client.batch_script_subscribe(script_pubkeys.iter().map(|spk| spk.as_script())) {
+        Ok(statuses) => Ok(statuses
+            .into_iter()
+            .map(|status| status.map(|status| status.to_lower_hex_string()))
+            .collect()),
+        Err(electrum_client::Error::AlreadySubscribed(_)) => Ok(electrum_client
+            .batch_script_get_history(script_pubkeys.iter().map(|spk| spk.as_script()))?
+            .iter()
+            .map(|history| script_status(history))
+            .collect()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The status Electrum would report for a script with `history`: the SHA256 of each
+/// `txid:height:` in order, or nothing for a script that was never used.
+fn script_status(history: &[GetHistoryRes]) -> Option<String> {
+    if history.is_empty() {
+        return None;
+    }
+    let status = history
+        .iter()
+        .map(|entry| format!("{}:{}:", entry.tx_hash, entry.height))
+        .collect::<String>();
+
+    Some(sha256::Hash::hash(status.as_bytes()).to_string())
+}
+
+/// Electrum reports mempool transactions at height 0, or -1 when they have unconfirmed parents.
+fn confirmed_height(height: i64) -> Option<u32> {
+    match height > 0 {
+        true => Some(height as u32),
+        false => None,
+    }
+}
+
+pub fn tip_height(electrum_client: &Client) -> Result<u32, Box<dyn std::error::Error>> {
+    Ok(electrum_client.block_headers_subscribe()?.height as u32)
+}
+
+pub fn print_utxos(
+    wallets: &[(String, Wallet)],
+    wallet_store_path: &Path,
+    labels_path: &Path,
+    electrum_client: &Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let labels = Labels::load(labels_path)?;
+    let tip_height = tip_height(electrum_client)?;
+
+    for (name, wallet) in wallets {
+        let utxos = list_unspent(wallet, &mut store, electrum_client)?;
+        println!("{} wallet: {} unspent output(s)", name, utxos.len());
+        for utxo in utxos {
+            let address = Address::from_script(&utxo.txout.script_pubkey, wallet.network())?;
+            // An output's own label wins over its address's
+            let label = match labels
+                .get(LabelType::Output, &utxo.outpoint.to_string())
+                .or_else(|| labels.get(LabelType::Addr, &address.to_string()))
+            {
+                Some(label) => format!(" \"{}\"", label),
+                None => String::new(),
+            };
+            println!(
+                "  {} {} sat to {} ({} index {}), {} confirmation(s){}",
+                utxo.outpoint,
+                utxo.txout.value.to_sat(),
+                address,
+                utxo.keychain.name(),
+                utxo.index,
+                utxo.confirmations(tip_height),
+                label
+            );
+        }
+    }
+    store.save()?;
+
+    Ok(())
+}
+
+pub fn print_balance(
+    wallets: &[(String, Wallet)],
+    wallet_store_path: &Path,
+    electrum_client: &Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = WalletStore::load(wallet_store_path)?;
+
+    let mut total_confirmed = Amount::ZERO;
+    let mut total_unconfirmed = Amount::ZERO;
+    for (name, wallet) in wallets {
+        let utxos = list_unspent(wallet, &mut store, electrum_client)?;
+        let (confirmed, unconfirmed): (Vec<_>, Vec<_>) =
+            utxos.iter().partition(|utxo| utxo.height.is_some());
+        let confirmed: Amount = confirmed.iter().map(|utxo| utxo.txout.value).sum();
+        let unconfirmed: Amount = unconfirmed.iter().map(|utxo| utxo.txout.value).sum();
+
+        println!(
+            "{} wallet: {} sat confirmed, {} sat unconfirmed",
+            name,
+            confirmed.to_sat(),
+            unconfirmed.to_sat()
+        );
+        total_confirmed += confirmed;
+        total_unconfirmed += unconfirmed;
+    }
+    if wallets.len() > 1 {
+        println!(
+            "Total: {} sat confirmed, {} sat unconfirmed",
+            total_confirmed.to_sat(),
+            total_unconfirmed.to_sat()
+        );
+    }
+    store.save()?;
+
+    Ok(())
+}
+
+/// Looks up the outputs being spent by `prevouts`, failing on duplicates since a transaction
+/// can only spend each output once.
+pub fn get_txouts(
+    electrum_client: &Client,
+    prevouts: &[OutPoint],
+) -> Result<Vec<TxOut>, Box<dyn std::error::Error>> {
+    let mut txouts = Vec::with_capacity(prevouts.len());
+    for (i, prevout) in prevouts.iter().enumerate() {
+        if prevouts[..i].contains(prevout) {
+            return Err(format!("{} is spent more than once", prevout).into());
+        }
+        let prev_tx = electrum_client.transaction_get(&prevout.txid)?;
+        let txout = prev_tx
+            .output
+            .get(prevout.vout as usize)
+            .ok_or_else(|| format!("{} has no output {}", prevout.txid, prevout.vout))?;
+        txouts.push(txout.clone());
+    }
+
+    Ok(txouts)
+}
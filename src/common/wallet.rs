@@ -0,0 +1,729 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use bitcoin::bip32::Xpriv;
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::key::Secp256k1;
+use bitcoin::secp256k1::All;
+use bitcoin::{
+    consensus, Address, Amount, Network, OutPoint, PrivateKey, Script, ScriptBuf, Transaction,
+    TxOut, Txid,
+};
+use miniscript::descriptor::{SinglePub, SinglePubKey};
+use miniscript::{Descriptor, DescriptorPublicKey, ForEachKey};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::common::keys::{self, Keychain, ScriptType, StoredKey};
+
+/// How far past the last revealed index we look when matching a script back to its derivation.
+const LOOKAHEAD: u32 = 20;
+
+/// The format version of the wallet store written by this build.
+const STORE_VERSION: u32 = 1;
+
+/// Upgrades a store from the format version at its index to the next one.
+const MIGRATIONS: [fn(&mut Map<String, Value>); STORE_VERSION as usize] = [migrate_v0];
+
+/// A script of a wallet, along with the keychain and index it was derived at.
+pub type DerivedScript = (Keychain, u32, ScriptBuf);
+
+/// A transaction of ours, along with the height of its confirming block if it has one.
+pub type WalletTransaction = (Transaction, Option<u32>);
+
+/// A pair of ranged descriptors, one per keychain.
+pub struct Wallet {
+    /// The descriptor the wallet was built from, covering both keychains
+    descriptor: Descriptor<DescriptorPublicKey>,
+    external: Descriptor<DescriptorPublicKey>,
+    internal: Option<Descriptor<DescriptorPublicKey>>,
+    network: Network,
+}
+
+impl Wallet {
+    /// The single-signature wallet for the `script_type` account of `xpriv`.
+    pub fn single_sig(
+        secp: &Secp256k1<All>,
+        xpriv: &Xpriv,
+        script_type: ScriptType,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_account_xpub(
+            script_type,
+            keys::account_xpub(secp, xpriv, script_type)?,
+            xpriv.network,
+        )
+    }
+
+    /// The single-signature `script_type` wallet of `key`. A single imported key makes a wallet of
+    /// just its own address, which change goes back to.
+    pub fn for_key(
+        secp: &Secp256k1<All>,
+        key: &StoredKey,
+        script_type: ScriptType,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let private_key = match key {
+            StoredKey::Master(xpriv) => return Self::single_sig(secp, xpriv, script_type),
+            StoredKey::Single(private_key) => private_key,
+        };
+        let public_key = private_key.public_key(secp);
+        let descriptor = match script_type {
+            ScriptType::Wpkh => Descriptor::new_wpkh(DescriptorPublicKey::Single(SinglePub {
+                origin: None,
+                key: SinglePubKey::FullKey(public_key),
+            }))?,
+            ScriptType::Tr => Descriptor::new_tr(
+                DescriptorPublicKey::Single(SinglePub {
+                    origin: None,
+                    key: SinglePubKey::XOnly(public_key.inner.x_only_public_key().0),
+                }),
+                None,
+            )?,
+            ScriptType::Wsh => {
+                return Err("Single keys sign multisig spends with `wsh sign`".into())
+            }
+        };
+
+        Self::from_descriptor(descriptor, private_key.network)
+    }
+
+    /// The single-signature wallet for an account xpub.
+    pub fn from_account_xpub(
+        script_type: ScriptType,
+        xpub: DescriptorPublicKey,
+        network: Network,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let key = keys::account_keychains(xpub);
+        let descriptor = match script_type {
+            ScriptType::Wpkh => Descriptor::new_wpkh(key)?,
+            ScriptType::Tr => Descriptor::new_tr(key, None)?,
+            ScriptType::Wsh => return Err("Multisig wallets are built from a descriptor".into()),
+        };
+
+        Self::from_descriptor(descriptor, network)
+    }
+
+    /// A wallet from a descriptor. Multipath `<0;1>/*` descriptors get a change keychain, any
+    /// other descriptor only has a receive keychain.
+    pub fn from_descriptor(
+        descriptor: Descriptor<DescriptorPublicKey>,
+        network: Network,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // Extended keys only encode mainnet vs. test networks, so that's all we can check
+        let matches_network = descriptor.for_each_key(|key| {
+            let key_network = match key {
+                DescriptorPublicKey::XPub(xkey) => xkey.xkey.network,
+                DescriptorPublicKey::MultiXPub(xkey) => xkey.xkey.network,
+                DescriptorPublicKey::Single(_) => return true,
+            };
+            (key_network == Network::Bitcoin) == (network == Network::Bitcoin)
+        });
+        if !matches_network {
+            return Err(format!("Descriptor has keys for another network than {}", network).into());
+        }
+
+        if !descriptor.is_multipath() {
+            return Ok(Wallet {
+                descriptor: descriptor.clone(),
+                external: descriptor,
+                internal: None,
+                network,
+            });
+        }
+
+        let mut descriptors = descriptor.clone().into_single_descriptors()?;
+        if descriptors.len() != Keychain::ALL.len() {
+            return Err("Multipath descriptors must have exactly two paths, like `<0;1>/*`".into());
+        }
+        let internal = descriptors.pop();
+        let external = descriptors.pop().expect("Two descriptors");
+
+        Ok(Wallet {
+            descriptor,
+            external,
+            internal,
+            network,
+        })
+    }
+
+    pub fn from_descriptor_str(
+        descriptor: &str,
+        network: Network,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_descriptor(Descriptor::from_str(descriptor)?, network)
+    }
+
+    /// A wallet from public material only: either a descriptor, or an account xpub together with
+    /// the script type it is used for.
+    pub fn from_public_str(
+        public: &str,
+        script_type: Option<ScriptType>,
+        network: Network,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Ok(descriptor) = Descriptor::from_str(public) {
+            return Self::from_descriptor(descriptor, network);
+        }
+
+        let xpub = DescriptorPublicKey::from_str(public)
+            .map_err(|_| format!("`{}` is neither a descriptor nor an xpub", public))?;
+        let script_type =
+            script_type.ok_or("An xpub needs a script type to know which addresses to derive")?;
+        Self::from_account_xpub(script_type, xpub, network)
+    }
+
+    /// The descriptor covering both keychains, as a multipath descriptor if there is a change
+    /// keychain.
+    pub fn full_descriptor(&self) -> &Descriptor<DescriptorPublicKey> {
+        &self.descriptor
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Identifies this wallet in the [`WalletStore`].
+    pub fn id(&self) -> String {
+        self.external.to_string()
+    }
+
+    pub fn descriptor(
+        &self,
+        keychain: Keychain,
+    ) -> Result<&Descriptor<DescriptorPublicKey>, Box<dyn std::error::Error>> {
+        match keychain {
+            Keychain::External => Ok(&self.external),
+            Keychain::Internal => self.internal.as_ref().ok_or_else(|| {
+                "This descriptor has no change keychain. Use a multipath `<0;1>/*` descriptor"
+                    .into()
+            }),
+        }
+    }
+
+    /// The keychain change goes to. Descriptors without a change keychain send change back to a
+    /// receive address.
+    pub fn change_keychain(&self) -> Keychain {
+        match self.internal {
+            Some(_) => Keychain::Internal,
+            None => Keychain::External,
+        }
+    }
+
+    pub fn address(
+        &self,
+        keychain: Keychain,
+        index: u32,
+    ) -> Result<Address, Box<dyn std::error::Error>> {
+        Ok(self
+            .descriptor(keychain)?
+            .at_derivation_index(index)?
+            .address(self.network)?)
+    }
+
+    /// Finds the keychain and index that produce `script_pubkey`.
+    pub fn derivation_of(
+        &self,
+        store: &WalletStore,
+        script_pubkey: &Script,
+    ) -> Result<Option<(Keychain, u32)>, Box<dyn std::error::Error>> {
+        for keychain in Keychain::ALL {
+            let Ok(descriptor) = self.descriptor(keychain) else {
+                continue;
+            };
+            for index in self.scan_range(store, keychain) {
+                if descriptor.at_derivation_index(index)?.script_pubkey() == *script_pubkey {
+                    return Ok(Some((keychain, index)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Every script we might have received coins on: all revealed indices plus the lookahead, on
+    /// both keychains.
+    pub fn script_pubkeys(
+        &self,
+        store: &WalletStore,
+    ) -> Result<Vec<DerivedScript>, Box<dyn std::error::Error>> {
+        let mut script_pubkeys = Vec::new();
+        for keychain in Keychain::ALL {
+            let Ok(descriptor) = self.descriptor(keychain) else {
+                continue;
+            };
+            for index in self.scan_range(store, keychain) {
+                let script_pubkey = descriptor.at_derivation_index(index)?.script_pubkey();
+                script_pubkeys.push((keychain, index, script_pubkey));
+            }
+        }
+
+        Ok(script_pubkeys)
+    }
+
+    fn scan_range(&self, store: &WalletStore, keychain: Keychain) -> Range<u32> {
+        match self.external.has_wildcard() {
+            true => 0..store.last_index(&self.id(), keychain).map_or(0, |i| i + 1) + LOOKAHEAD,
+            false => 0..1,
+        }
+    }
+}
+
+/// What the store knows about one wallet.
+#[derive(Default, Serialize, Deserialize)]
+struct WalletData {
+    /// The descriptor covering both keychains
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    descriptor: Option<String>,
+    external: Option<u32>,
+    internal: Option<u32>,
+    /// The last synced state of each script, by script hex
+    #[serde(default)]
+    scripts: BTreeMap<String, ScriptCache>,
+}
+
+impl WalletData {
+    fn get_mut(&mut self, keychain: Keychain) -> &mut Option<u32> {
+        match keychain {
+            Keychain::External => &mut self.external,
+            Keychain::Internal => &mut self.internal,
+        }
+    }
+}
+
+/// A script's Electrum status hash and the unspent outputs it had when last synced. The status
+/// changes whenever the script's history does, so an unchanged status means nothing to refetch.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ScriptCache {
+    pub status: Option<String>,
+    pub utxos: Vec<CachedUtxo>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CachedUtxo {
+    #[serde(with = "as_string")]
+    pub outpoint: OutPoint,
+    pub value: u64,
+    /// Height of the confirming block, or `None` while in the mempool
+    pub height: Option<u32>,
+}
+
+/// A transaction of ours, along with its confirmation status.
+#[derive(Serialize, Deserialize)]
+struct StoredTransaction {
+    hex: String,
+    /// Height of the confirming block, or `None` while unconfirmed
+    height: Option<u32>,
+}
+
+/// The wallet database, persisted as versioned JSON: the descriptor and last revealed indices of
+/// every wallet we have handed out addresses for, so that addresses are never handed out twice,
+/// their last synced UTXOs, our transactions and the descriptors of watch-only wallets, by name.
+#[derive(Default, Serialize, Deserialize)]
+pub struct WalletStore {
+    #[serde(skip)]
+    path: PathBuf,
+    version: u32,
+    wallets: BTreeMap<String, WalletData>,
+    #[serde(default)]
+    watch_only: BTreeMap<String, String>,
+    /// By txid
+    #[serde(default)]
+    transactions: BTreeMap<String, StoredTransaction>,
+}
+
+impl WalletStore {
+    /// Loads the store at `path`, migrating it to the current format first if it is older. The
+    /// old file is kept next to it, with its version in the name.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(WalletStore {
+                path: path.to_path_buf(),
+                version: STORE_VERSION,
+                ..Default::default()
+            });
+        }
+
+        let (mut store, version) = Self::from_json(&fs::read_to_string(path)?)?;
+        store.path = path.to_path_buf();
+        if version < STORE_VERSION {
+            fs::copy(path, path.with_extension(format!("v{}.json", version)))?;
+            store.save()?;
+            // Kept off stdout, where scripts read the PSBTs and transactions we print
+            eprintln!(
+                "Migrated {} from format version {} to {}",
+                path.display(),
+                version,
+                STORE_VERSION
+            );
+        }
+
+        Ok(store)
+    }
+
+    /// Parses a store, running any migrations it needs. Also returns the version it was in.
+    fn from_json(json: &str) -> Result<(Self, u32), Box<dyn std::error::Error>> {
+        let mut value: Value = serde_json::from_str(json)?;
+        let fields = value
+            .as_object_mut()
+            .ok_or("The wallet store is not a JSON object")?;
+        // The first format had no version field
+        let version = match fields.get("version") {
+            Some(version) => version.as_u64().ok_or("Invalid wallet store version")? as u32,
+            None => 0,
+        };
+        if version > STORE_VERSION {
+            return Err(format!(
+                "The wallet store has format version {}, but this build only reads up to {}. Upgrade tx-fun",
+                version, STORE_VERSION
+            )
+            .into());
+        }
+
+        for migrate in &MIGRATIONS[version as usize..] {
+            migrate(fields);
+        }
+        fields.insert("version".to_string(), STORE_VERSION.into());
+
+        Ok((serde_json::from_value(value)?, version))
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first so that a crash never leaves a truncated store
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp_path, &self.path)?;
+
+        Ok(())
+    }
+
+    pub fn last_index(&self, wallet_id: &str, keychain: Keychain) -> Option<u32> {
+        let indices = self.wallets.get(wallet_id)?;
+        match keychain {
+            Keychain::External => indices.external,
+            Keychain::Internal => indices.internal,
+        }
+    }
+
+    pub fn watch_only(&self, name: &str) -> Option<&str> {
+        self.watch_only.get(name).map(String::as_str)
+    }
+
+    pub fn watch_only_wallets(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.watch_only
+            .iter()
+            .map(|(name, descriptor)| (name.as_str(), descriptor.as_str()))
+    }
+
+    /// Registers a watch-only wallet. Names are never reused for a different descriptor.
+    pub fn add_watch_only(
+        &mut self,
+        name: &str,
+        descriptor: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.watch_only.get(name) {
+            Some(existing) if existing != descriptor => {
+                Err(format!("A different wallet is already registered as `{}`", name).into())
+            }
+            _ => {
+                self.watch_only
+                    .insert(name.to_string(), descriptor.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    /// Records `tx`, or updates its confirmation height if it is already known.
+    pub fn add_transaction(&mut self, tx: &Transaction, height: Option<u32>) {
+        self.transactions
+            .entry(tx.txid().to_string())
+            .or_insert_with(|| StoredTransaction {
+                hex: consensus::serialize(tx).to_lower_hex_string(),
+                height,
+            })
+            .height = height;
+    }
+
+    pub fn has_transaction(&self, txid: &Txid) -> bool {
+        self.transactions.contains_key(&txid.to_string())
+    }
+
+    /// Updates the confirmation height of a known transaction.
+    pub fn set_transaction_height(&mut self, txid: &Txid, height: Option<u32>) {
+        if let Some(stored) = self.transactions.get_mut(&txid.to_string()) {
+            stored.height = height;
+        }
+    }
+
+    pub fn transaction(
+        &self,
+        txid: &Txid,
+    ) -> Result<Option<WalletTransaction>, Box<dyn std::error::Error>> {
+        self.transactions
+            .get(&txid.to_string())
+            .map(|stored| {
+                Ok((
+                    consensus::deserialize(&Vec::from_hex(&stored.hex)?)?,
+                    stored.height,
+                ))
+            })
+            .transpose()
+    }
+
+    /// Forgets a transaction, such as one that was replaced and can never confirm.
+    pub fn remove_transaction(&mut self, txid: &Txid) {
+        self.transactions.remove(&txid.to_string());
+    }
+
+    /// Every transaction we know of, with its confirmation height.
+    pub fn transactions(&self) -> Result<Vec<WalletTransaction>, Box<dyn std::error::Error>> {
+        self.transactions
+            .values()
+            .map(|stored| {
+                Ok((
+                    consensus::deserialize(&Vec::from_hex(&stored.hex)?)?,
+                    stored.height,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn unconfirmed_transactions(&self) -> Result<Vec<Transaction>, Box<dyn std::error::Error>> {
+        self.transactions
+            .values()
+            .filter(|stored| stored.height.is_none())
+            .map(|stored| Ok(consensus::deserialize(&Vec::from_hex(&stored.hex)?)?))
+            .collect()
+    }
+
+    pub fn script_cache(&self, wallet_id: &str, script_pubkey: &Script) -> Option<&ScriptCache> {
+        self.wallets
+            .get(wallet_id)?
+            .scripts
+            .get(&script_pubkey.to_hex_string())
+    }
+
+    /// Replaces the synced state of one of `wallet`'s scripts.
+    pub fn set_script_cache(
+        &mut self,
+        wallet: &Wallet,
+        script_pubkey: &Script,
+        cache: ScriptCache,
+    ) {
+        self.wallet_data(wallet)
+            .scripts
+            .insert(script_pubkey.to_hex_string(), cache);
+    }
+
+    /// Reveals the next unused index of `keychain`, marking it as used.
+    pub fn reveal_next(&mut self, wallet: &Wallet, keychain: Keychain) -> u32 {
+        let last = self.wallet_data(wallet).get_mut(keychain);
+        let next = last.map_or(0, |i| i + 1);
+        *last = Some(next);
+
+        next
+    }
+
+    fn wallet_data(&mut self, wallet: &Wallet) -> &mut WalletData {
+        let data = self.wallets.entry(wallet.id()).or_default();
+        data.descriptor
+            .get_or_insert_with(|| wallet.full_descriptor().to_string());
+        data
+    }
+}
+
+/// Version 0 had no version field, and kept the transactions we broadcast in `unconfirmed`, as
+/// hex by txid, until they confirmed.
+fn migrate_v0(fields: &mut Map<String, Value>) {
+    let transactions = match fields.remove("unconfirmed") {
+        Some(Value::Object(unconfirmed)) => unconfirmed
+            .into_iter()
+            .map(|(txid, hex)| (txid, serde_json::json!({ "hex": hex, "height": null })))
+            .collect(),
+        _ => Map::new(),
+    };
+    fields.insert("transactions".to_string(), Value::Object(transactions));
+}
+
+/// Serde for bitcoin types through their string form, since bitcoin is built without serde.
+mod as_string {
+    use super::*;
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The private key, from the single-signature `script_type` wallet of `key`, that controls
+/// `script_pubkey`. A single imported key only controls its own `script_type` output.
+pub fn signing_key_for(
+    secp: &Secp256k1<All>,
+    key: &StoredKey,
+    script_type: ScriptType,
+    store_path: &Path,
+    script_pubkey: &Script,
+) -> Result<PrivateKey, Box<dyn std::error::Error>> {
+    let wallet = Wallet::for_key(secp, key, script_type)?;
+    let store = WalletStore::load(store_path)?;
+    let (keychain, index) = wallet
+        .derivation_of(&store, script_pubkey)?
+        .ok_or("The previous output does not belong to this key")?;
+
+    keys::signing_key(secp, key, script_type, keychain, index)
+}
+
+/// The `script_type` output of a single imported key.
+fn single_key_script(
+    secp: &Secp256k1<All>,
+    private_key: &PrivateKey,
+    script_type: ScriptType,
+) -> Result<ScriptBuf, Box<dyn std::error::Error>> {
+    let public_key = private_key.public_key(secp);
+    let address = match script_type {
+        ScriptType::Wpkh => Address::p2wpkh(&public_key, private_key.network)?,
+        ScriptType::Tr => Address::p2tr(secp, public_key.into(), None, private_key.network),
+        ScriptType::Wsh => return Err("Single keys sign multisig spends with `wsh sign`".into()),
+    };
+
+    Ok(address.script_pubkey())
+}
+
+/// An empty output to where change from spending `key`'s `script_type` coins goes: the next
+/// unused change address of its wallet, or a single key's own address. Comes with the wallet to
+/// reveal the change address in once it is used.
+pub fn change_output(
+    secp: &Secp256k1<All>,
+    key: &StoredKey,
+    script_type: ScriptType,
+    store: &WalletStore,
+) -> Result<(TxOut, Option<Wallet>), Box<dyn std::error::Error>> {
+    let (script_pubkey, wallet) = match key {
+        StoredKey::Master(xpriv) => {
+            let wallet = Wallet::single_sig(secp, xpriv, script_type)?;
+            let index = store
+                .last_index(&wallet.id(), Keychain::Internal)
+                .map_or(0, |i| i + 1);
+            let script_pubkey = wallet.address(Keychain::Internal, index)?.script_pubkey();
+            (script_pubkey, Some(wallet))
+        }
+        StoredKey::Single(private_key) => {
+            (single_key_script(secp, private_key, script_type)?, None)
+        }
+    };
+
+    Ok((
+        TxOut {
+            value: Amount::ZERO,
+            script_pubkey,
+        },
+        wallet,
+    ))
+}
+
+/// Hands out the next unused address of `keychain` and persists the new index.
+pub fn new_address(
+    wallet: &Wallet,
+    store_path: &Path,
+    keychain: Keychain,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = WalletStore::load(store_path)?;
+    // Fail before touching the store if the wallet has no such keychain
+    wallet.descriptor(keychain)?;
+
+    let index = store.reveal_next(wallet, keychain);
+    let address = wallet.address(keychain, index)?;
+    store.save()?;
+
+    println!("Address: {}", address);
+    println!("Derivation: {} index {}", keychain.name(), index);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{bip32::Xpriv, key::Secp256k1};
+
+    use super::{Wallet, WalletStore, STORE_VERSION};
+    use crate::common::keys::{Keychain, ScriptType};
+
+    #[test]
+    fn test_keychains() {
+        let secp = Secp256k1::new();
+        let xpriv = Xpriv::from_str("tprv8ZgxMBicQKsPdE9CKqUW84nQ7o529Cn3oyBpC6mAYPybohfSWVu5UbvhJ38orCjVDuJsztvh37uQuUXi6F25BGWXrtTmBWuAJpNDa8spieL")
+            .expect("Invalid xprv");
+        let wallet = Wallet::single_sig(&secp, &xpriv, ScriptType::Wpkh).expect("Wallet");
+
+        let mut store = WalletStore::default();
+        assert_eq!(store.reveal_next(&wallet, Keychain::External), 0);
+        assert_eq!(store.reveal_next(&wallet, Keychain::External), 1);
+        assert_eq!(store.reveal_next(&wallet, Keychain::Internal), 0);
+
+        // Receive and change addresses come from different chains, and map back to them
+        let receive = wallet.address(Keychain::External, 1).expect("Address");
+        let change = wallet.address(Keychain::Internal, 1).expect("Address");
+        assert_ne!(receive, change);
+        assert_eq!(
+            wallet
+                .derivation_of(&store, &receive.script_pubkey())
+                .expect("Lookup"),
+            Some((Keychain::External, 1))
+        );
+        assert_eq!(
+            wallet
+                .derivation_of(&store, &change.script_pubkey())
+                .expect("Lookup"),
+            Some((Keychain::Internal, 1))
+        );
+    }
+
+    #[test]
+    fn test_migrate_v0() {
+        // A store from before format versions, with a broadcast transaction still unconfirmed
+        let tx_hex = "02000000000100000000";
+        let v0 = format!(
+            r#"{{
+                "wallets": {{ "wpkh(xpub)": {{ "external": 4, "internal": null }} }},
+                "watch_only": {{ "vault": "wsh(...)" }},
+                "unconfirmed": {{ "abcd": "{}" }}
+            }}"#,
+            tx_hex
+        );
+
+        let (store, version) = WalletStore::from_json(&v0).expect("Migrated store");
+        assert_eq!(version, 0);
+        assert_eq!(store.version, STORE_VERSION);
+        assert_eq!(store.last_index("wpkh(xpub)", Keychain::External), Some(4));
+        assert_eq!(store.last_index("wpkh(xpub)", Keychain::Internal), None);
+        assert_eq!(store.watch_only("vault"), Some("wsh(...)"));
+        assert_eq!(store.transactions["abcd"].hex, tx_hex);
+        assert_eq!(store.transactions["abcd"].height, None);
+
+        // The current format loads as is, and a newer one is refused
+        let current = serde_json::to_string(&store).expect("Serialized store");
+        let (_, version) = WalletStore::from_json(&current).expect("Current store");
+        assert_eq!(version, STORE_VERSION);
+        assert!(WalletStore::from_json(r#"{ "version": 99, "wallets": {} }"#).is_err());
+    }
+}
@@ -1,20 +1,57 @@
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
-use bitcoin::{key::Secp256k1, OutPoint};
-use clap::{Parser, Subcommand};
-use electrum_client::ElectrumApi;
+use bitcoin::{
+    absolute::LockTime, key::Secp256k1, secp256k1::rand::RngCore, FeeRate, Network, OutPoint,
+    Sequence,
+};
+use clap::{builder::TypedValueParser, Args, Parser, Subcommand};
+use common::{
+    broadcast::{self, Broadcaster},
+    coin_selection::CoinSelection,
+    fees,
+    key_formats::KeyFormat,
+    keyring::Keyring,
+    keys::{Keychain, ScriptType},
+    labels::{self, LabelType},
+    slip39::Scheme,
+    timelocks::{self, Timelocks},
+    wallet::Wallet,
+};
+use electrum_client::Client;
 
+mod bump;
 mod common;
+mod mixed;
+mod send;
+mod sweep;
 mod tr;
+mod watch;
 mod wpkh;
 mod wsh;
 
 #[derive(Clone, Parser)]
 #[clap()]
 pub struct Cli {
-    /// URL for the Electrum node
-    #[clap(short, long, default_value = "tcp://localhost:60401")]
-    electrum: String,
+    /// URL for the Electrum node. Defaults to a local node on the usual port for the network
+    #[clap(short, long)]
+    electrum: Option<String>,
+
+    /// Network to use: mainnet, testnet, signet or regtest
+    #[clap(short, long, global = true, default_value = "regtest", value_parser = parse_network)]
+    network: Network,
+
+    /// Directory holding the named keys
+    #[clap(long, global = true, default_value = "keys")]
+    keyring: String,
+
+    /// Seed all randomness from this string, for reproducible keys and Taproot signatures.
+    /// Never use it for real funds
+    #[clap(long, global = true)]
+    deterministic_seed: Option<String>,
+
+    /// Name of the key to use from the keyring
+    #[clap(short, long, global = true, default_value = "default")]
+    key: String,
 
     #[clap(subcommand)]
     command: Commands,
@@ -36,14 +73,323 @@ enum Commands {
         command: TrCommands,
     },
     Keygen {
-        /// The path to write the key to
-        #[clap(default_value = "key.txt")]
-        path: String,
+        /// Number of mnemonic words to generate
+        #[clap(long, default_value = "12", value_parser = clap::builder::PossibleValuesParser::new(["12", "24"]).map(|s| s.parse::<usize>().unwrap()))]
+        words: usize,
+        /// Optional BIP39 passphrase, or SLIP-39 passphrase with `--shares`
+        #[clap(long)]
+        passphrase: Option<String>,
+        /// Back the key up as SLIP-39 Shamir shares instead of a mnemonic, like `2-of-3`
+        #[clap(long)]
+        shares: Option<Scheme>,
+    },
+    /// Restore a key from its mnemonic
+    Restore {
+        /// The mnemonic words, quoted
+        mnemonic: String,
+        /// Optional BIP39 passphrase
+        #[clap(long)]
+        passphrase: Option<String>,
+    },
+    /// Recover a key from SLIP-39 shares
+    Recover {
+        /// The shares, each quoted
+        #[clap(required = true)]
+        shares: Vec<String>,
+        /// The SLIP-39 passphrase, if one was used
+        #[clap(long)]
+        passphrase: Option<String>,
+    },
+    Pubkey,
+    /// Encrypt an existing plaintext key file
+    EncryptKey,
+    /// Change the password of an encrypted key file
+    ChangePassword,
+    /// Manage the keyring
+    Key {
+        #[clap(subcommand)]
+        command: KeyCommands,
+    },
+    /// List the unspent outputs of the key's wpkh and tr wallets
+    Utxos {
+        /// A watch-only wallet name or a descriptor to list instead, such as a wsh multisig
+        #[clap(long)]
+        wallet: Option<String>,
+    },
+    /// Show the confirmed and unconfirmed balance of the key's wpkh and tr wallets
+    Balance {
+        /// A watch-only wallet name or a descriptor to show instead, such as a wsh multisig
+        #[clap(long)]
+        wallet: Option<String>,
+    },
+    /// List the transactions of the key's wpkh and tr wallets, with their net amount and fee
+    History {
+        /// A watch-only wallet name or a descriptor to list instead, such as a wsh multisig
+        #[clap(long)]
+        wallet: Option<String>,
+    },
+    /// Label transactions, addresses and outputs, in the BIP329 format
+    Label {
+        #[clap(subcommand)]
+        command: LabelCommands,
+    },
+    /// Pay an address, picking the inputs from the wallet's UTXOs and sending the rest back as
+    /// change
+    Send {
+        /// The destination address
+        #[clap(required_unless_present = "batch", conflicts_with = "batch")]
+        destination: Option<String>,
+        /// The amount to send
+        #[clap(required_unless_present = "batch")]
+        amount: Option<String>,
+        /// Pay every `address,amount[,label]` row of a CSV file in one transaction instead
+        #[clap(long)]
+        batch: Option<PathBuf>,
+        /// The account of `--key` to spend from
+        #[clap(long, default_value = "wpkh", value_parser = clap::builder::PossibleValuesParser::new(["wpkh", "tr"]).map(|s| ScriptType::from_str(&s).unwrap()))]
+        script_type: ScriptType,
+        /// Spend from a watch-only wallet name or descriptor instead, such as a wsh multisig.
+        /// Prints an unsigned PSBT for its signers
+        #[clap(long, conflicts_with = "broadcast")]
+        wallet: Option<String>,
+        /// How to pick the inputs
+        #[clap(long, default_value = "bnb", value_parser = clap::builder::PossibleValuesParser::new(["bnb", "largest-first", "srd"]).map(|s| CoinSelection::from_str(&s).unwrap()))]
+        coin_selection: CoinSelection,
+        /// Fee rate in sat/vB. Estimated by the Electrum server when omitted
+        #[clap(long, value_parser = fees::parse_fee_rate)]
+        fee_rate: Option<FeeRate>,
+        /// Confirmation target, in blocks, of the estimated fee rate
+        #[clap(long, default_value_t = fees::DEFAULT_TARGET_BLOCKS)]
+        target_blocks: usize,
+        #[clap(flatten)]
+        anti_fee_sniping: AntiFeeSnipingArgs,
+        /// Sign even if the spend creates dust or pays an absurd fee
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        broadcast: BroadcastArgs,
+    },
+    /// Send every UTXO of a wallet, or of a WIF private key, to one address, less the fee
+    Sweep {
+        /// The destination address
+        destination: String,
+        /// Sweep the P2PKH, P2SH-P2WPKH, P2WPKH and P2TR outputs of this WIF private key
+        #[clap(long, conflicts_with = "wallet")]
+        wif: Option<String>,
+        /// The account of `--key` to sweep
+        #[clap(long, default_value = "wpkh", value_parser = clap::builder::PossibleValuesParser::new(["wpkh", "tr"]).map(|s| ScriptType::from_str(&s).unwrap()))]
+        script_type: ScriptType,
+        /// Sweep a watch-only wallet name or descriptor instead. Prints an unsigned PSBT for its
+        /// signers
+        #[clap(long, conflicts_with = "broadcast")]
+        wallet: Option<String>,
+        /// Fee rate in sat/vB. Estimated by the Electrum server when omitted
+        #[clap(long, value_parser = fees::parse_fee_rate)]
+        fee_rate: Option<FeeRate>,
+        /// Confirmation target, in blocks, of the estimated fee rate
+        #[clap(long, default_value_t = fees::DEFAULT_TARGET_BLOCKS)]
+        target_blocks: usize,
+        #[clap(flatten)]
+        anti_fee_sniping: AntiFeeSnipingArgs,
+        /// Sign even if the sweep pays an absurd fee
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        broadcast: BroadcastArgs,
+    },
+    /// Replace one of our unconfirmed transactions with one paying a higher fee rate (BIP125)
+    BumpFee {
+        /// The transaction to replace
+        txid: String,
+        /// The new fee rate in sat/vB
+        #[clap(long, value_parser = fees::parse_fee_rate)]
+        fee_rate: FeeRate,
+        /// The watch-only wallet name or descriptor that sent it, such as a wsh multisig. Prints
+        /// an unsigned PSBT for its signers
+        #[clap(long, conflicts_with = "broadcast")]
+        wallet: Option<String>,
+        #[clap(flatten)]
+        anti_fee_sniping: AntiFeeSnipingArgs,
+        /// Sign even if the replacement pays an absurd fee
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        broadcast: BroadcastArgs,
+    },
+    /// Speed up an unconfirmed payment to us by spending its output in a child paying for both
+    Cpfp {
+        /// The output of the stuck transaction that pays our wpkh or tr wallet, as `txid:vout`
+        outpoint: String,
+        /// The fee rate in sat/vB the parent and child should reach together
+        #[clap(long, value_parser = fees::parse_fee_rate)]
+        target_fee_rate: FeeRate,
+        #[clap(flatten)]
+        anti_fee_sniping: AntiFeeSnipingArgs,
+        /// Sign even if the child pays an absurd fee
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        broadcast: BroadcastArgs,
+    },
+    /// Spend P2WPKH, P2TR and P2WSH multisig outputs together in one transaction
+    SpendMixed {
+        /// The destination address
+        destination: String,
+        /// The previous outputs to spend, one or more
+        #[clap(required = true)]
+        prevouts: Vec<String>,
+        /// The amount to send. What the prevouts hold beyond it and the fee comes back as change
+        amount: String,
+        /// A watch-only wallet name or descriptor of a P2WSH multisig that some prevouts pay.
+        /// Repeat for several
+        #[clap(long)]
+        multisig: Vec<String>,
+        /// Fee rate in sat/vB. Estimated by the Electrum server when omitted
+        #[clap(long, value_parser = fees::parse_fee_rate)]
+        fee_rate: Option<FeeRate>,
+        /// Confirmation target, in blocks, of the estimated fee rate
+        #[clap(long, default_value_t = fees::DEFAULT_TARGET_BLOCKS)]
+        target_blocks: usize,
+        #[clap(flatten)]
+        timelocks: TimelockArgs,
+        #[clap(flatten)]
+        anti_fee_sniping: AntiFeeSnipingArgs,
+        /// Sign even if the spend creates dust, pays an absurd fee or can't be mined yet
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        broadcast: BroadcastArgs,
+    },
+    /// Resend our broadcast transactions that have not confirmed yet
+    Rebroadcast,
+    /// Watch-only wallets, built from public keys only
+    Watch {
+        #[clap(subcommand)]
+        command: WatchCommands,
+    },
+}
+
+#[derive(Clone, Subcommand)]
+enum LabelCommands {
+    /// Set the label of a transaction, address or output
+    Set {
+        /// What to label: tx, addr, pubkey, input, output or xpub
+        label_type: LabelType,
+        /// The txid, address, `txid:vout` outpoint, public key or xpub
+        reference: String,
+        /// The label
+        label: String,
+    },
+    /// List every label
+    List,
+    /// Merge labels from a BIP329 JSONL file, such as one exported by Sparrow
+    Import {
+        /// The file to read
+        file: PathBuf,
+    },
+    /// Write every label to a BIP329 JSONL file
+    Export {
+        /// The file to write
+        file: PathBuf,
+    },
+}
+
+#[derive(Clone, Subcommand)]
+enum WatchCommands {
+    /// Register a watch-only wallet from an xpub or descriptor
+    Add {
+        /// The name to register the wallet under
+        name: String,
+        /// A descriptor, such as `wsh(sortedmulti(...))` or `tr(...)`, or an account xpub
+        public: String,
+        /// The script type to use when given an account xpub
+        #[clap(long, value_parser = clap::builder::PossibleValuesParser::new(["wpkh", "tr"]).map(|s| ScriptType::from_str(&s).unwrap()))]
+        script_type: Option<ScriptType>,
     },
-    Pubkey {
-        /// The path to read the key from
-        #[clap(default_value = "key.txt")]
-        path: String,
+    /// List the watch-only wallets
+    List,
+    /// Hand out the next unused address of a watch-only wallet
+    NewAddress {
+        /// The name of the wallet
+        name: String,
+        /// Hand out a change address instead of a receive address
+        #[clap(long)]
+        change: bool,
+    },
+    /// Show the balance of a watch-only wallet
+    Balance {
+        /// The name of the wallet
+        name: String,
+    },
+    /// Create an unsigned PSBT for offline signers
+    CreatePsbt {
+        /// The name of the wallet
+        name: String,
+        /// The destination address
+        destination: String,
+        /// The previous output
+        prevout: String,
+        /// The amount to send. What the prevout holds beyond it and the fee comes back as change
+        amount: String,
+        /// Fee rate in sat/vB. Estimated by the Electrum server when omitted
+        #[clap(long, value_parser = fees::parse_fee_rate)]
+        fee_rate: Option<FeeRate>,
+        /// Confirmation target, in blocks, of the estimated fee rate
+        #[clap(long, default_value_t = fees::DEFAULT_TARGET_BLOCKS)]
+        target_blocks: usize,
+        #[clap(flatten)]
+        timelocks: TimelockArgs,
+        #[clap(flatten)]
+        anti_fee_sniping: AntiFeeSnipingArgs,
+        /// Create the PSBT even if the spend creates dust, pays an absurd fee or can't be mined yet
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Subcommand)]
+enum KeyCommands {
+    /// List the keys in the keyring
+    List,
+    /// Show a key's account xpubs
+    Show {
+        /// The name of the key
+        name: String,
+    },
+    /// Delete a key from the keyring
+    Delete {
+        /// The name of the key
+        name: String,
+        /// Skip the confirmation prompt
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Import a WIF, hex, xprv or descriptor private key
+    Import {
+        /// The name to store the key under
+        name: String,
+        /// The key to import. Prompted for when omitted, to keep it out of the shell history
+        secret: Option<String>,
+    },
+    /// Export a key as WIF, hex, xprv or descriptor
+    Export {
+        /// The name of the key
+        name: String,
+        /// The format to export to
+        #[clap(long, default_value = "descriptor", value_parser = clap::builder::PossibleValuesParser::new(["wif", "hex", "xprv", "descriptor"]).map(|s| KeyFormat::from_str(&s).unwrap()))]
+        format: KeyFormat,
+        /// The account to export from
+        #[clap(long, default_value = "wpkh", value_parser = clap::builder::PossibleValuesParser::new(["wpkh", "tr", "wsh"]).map(|s| ScriptType::from_str(&s).unwrap()))]
+        script_type: ScriptType,
+        /// Export the key at this change index, instead of this receive index, for WIF and hex
+        #[clap(long)]
+        change: bool,
+        /// The index of the key to export for WIF and hex
+        #[clap(long, default_value_t = 0)]
+        index: u32,
+        /// Show private keys. Without it, only public keys are exported
+        #[clap(long)]
+        private: bool,
     },
 }
 
@@ -51,17 +397,43 @@ enum Commands {
 enum WpkhCommands {
     /// Generate a new address
     GenerateAddress {
-        /// The public key to generate the address from
-        public_key: String,
+        /// The public key or account xpub to generate the address from. Defaults to the
+        /// account xpub of `--key`
+        public_key: Option<String>,
+        /// The receive index to derive when given an account xpub
+        #[clap(long, default_value_t = 0)]
+        index: u32,
     },
     /// Create a signed transaction
     SignTransaction {
         /// The destination address
         destination: String,
-        /// The previous output
-        prevout: String,
-        /// The amount to send
+        /// The previous outputs to spend, one or more
+        #[clap(required = true)]
+        prevouts: Vec<String>,
+        /// The amount to send. What the prevouts hold beyond it and the fee comes back as change
         amount: String,
+        /// Fee rate in sat/vB. Estimated by the Electrum server when omitted
+        #[clap(long, value_parser = fees::parse_fee_rate)]
+        fee_rate: Option<FeeRate>,
+        /// Confirmation target, in blocks, of the estimated fee rate
+        #[clap(long, default_value_t = fees::DEFAULT_TARGET_BLOCKS)]
+        target_blocks: usize,
+        #[clap(flatten)]
+        timelocks: TimelockArgs,
+        #[clap(flatten)]
+        anti_fee_sniping: AntiFeeSnipingArgs,
+        /// Sign even if the spend creates dust, pays an absurd fee or can't be mined yet
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        broadcast: BroadcastArgs,
+    },
+    /// Hand out the next unused address of `--key`
+    NewAddress {
+        /// Hand out a change address instead of a receive address
+        #[clap(long)]
+        change: bool,
     },
 }
 
@@ -69,30 +441,62 @@ enum WpkhCommands {
 enum TrCommands {
     /// Generate a new address
     GenerateAddress {
-        /// The public key to generate the address from
-        public_key: String,
+        /// The public key or account xpub to generate the address from. Defaults to the
+        /// account xpub of `--key`
+        public_key: Option<String>,
+        /// The receive index to derive when given an account xpub
+        #[clap(long, default_value_t = 0)]
+        index: u32,
     },
     /// Create a signed transaction
     SignTransaction {
         /// The destination address
         destination: String,
-        /// The previous output
-        prevout: String,
-        /// The amount to send
+        /// The previous outputs to spend, one or more
+        #[clap(required = true)]
+        prevouts: Vec<String>,
+        /// The amount to send. What the prevouts hold beyond it and the fee comes back as change
         amount: String,
+        /// Fee rate in sat/vB. Estimated by the Electrum server when omitted
+        #[clap(long, value_parser = fees::parse_fee_rate)]
+        fee_rate: Option<FeeRate>,
+        /// Confirmation target, in blocks, of the estimated fee rate
+        #[clap(long, default_value_t = fees::DEFAULT_TARGET_BLOCKS)]
+        target_blocks: usize,
+        #[clap(flatten)]
+        timelocks: TimelockArgs,
+        #[clap(flatten)]
+        anti_fee_sniping: AntiFeeSnipingArgs,
+        /// Sign even if the spend creates dust, pays an absurd fee or can't be mined yet
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        broadcast: BroadcastArgs,
+    },
+    /// Hand out the next unused address of `--key`
+    NewAddress {
+        /// Hand out a change address instead of a receive address
+        #[clap(long)]
+        change: bool,
     },
 }
 
 #[derive(Clone, Subcommand)]
 enum WshCommands {
     GenerateDescriptor {
-        /// The public key to generate the address from
+        /// The public keys, account xpubs or keyring key names to generate the address from
         public_keys: Vec<String>,
     },
     GenerateAddress {
         /// The descriptor to generate the address from
         descriptor: String,
+        /// The derivation index to use for ranged descriptors
+        #[clap(long, default_value_t = 0)]
+        index: u32,
     },
+    /// Build a multisig spend and sign it. The locktime stays at zero (no anti-fee-sniping)
+    /// unless `--locktime` is given, since every cosigner building it must pick the same one. To
+    /// have one locktime picked for everyone, use `watch create-psbt` and `wsh sign` instead
     SignPsbt {
         /// The descriptor to generate the witness script
         descriptor: String,
@@ -102,43 +506,668 @@ enum WshCommands {
         prevout: String,
         /// The amount to send
         amount: String,
+        #[clap(flatten)]
+        timelocks: TimelockArgs,
+        /// Sign even if the transaction can't be mined yet
+        #[clap(long)]
+        force: bool,
+    },
+    /// Hand out the next unused address of a descriptor
+    NewAddress {
+        /// The descriptor to generate the address from
+        descriptor: String,
+        /// Hand out a change address instead of a receive address
+        #[clap(long)]
+        change: bool,
+    },
+    /// Sign a PSBT created elsewhere, such as by `watch create-psbt`
+    Sign {
+        /// The PSBT to sign
+        psbt: String,
     },
     CombinePsbts {
         /// The PSBTs to combine
         psbts: Vec<String>,
+        #[clap(flatten)]
+        broadcast: BroadcastArgs,
     },
 }
 
+#[derive(Clone, Args)]
+struct BroadcastArgs {
+    /// Broadcast the signed transaction through the Electrum server
+    #[clap(long)]
+    broadcast: bool,
+    /// After broadcasting, wait until the transaction has this many confirmations
+    #[clap(long, requires = "broadcast")]
+    wait_confirm: Option<u32>,
+    /// Give up waiting for confirmations after this many minutes
+    #[clap(long, default_value_t = 120, requires = "wait_confirm")]
+    wait_timeout: u64,
+}
+
+#[derive(Clone, Args)]
+struct TimelockArgs {
+    /// Lock the transaction until this block height, or Unix time from 500000000 on
+    #[clap(long, value_parser = timelocks::parse_lock_time)]
+    locktime: Option<LockTime>,
+    /// The raw nSequence of each input, in order, or one for all of them
+    #[clap(long, value_parser = timelocks::parse_sequence)]
+    sequence: Vec<Sequence>,
+    /// A relative timelock (BIP68) for each input, in order, or one for all of them: a number of
+    /// blocks, or of seconds with an `s` suffix
+    #[clap(long, value_parser = timelocks::parse_csv, conflicts_with = "sequence")]
+    csv: Vec<Sequence>,
+}
+
+impl TimelockArgs {
+    /// The timelocks given, with an anti-fee-sniping locktime unless `--locktime` sets one.
+    fn timelocks(
+        self,
+        anti_fee_sniping: AntiFeeSnipingArgs,
+        electrum_client: &Client,
+        rng: &mut dyn RngCore,
+    ) -> Result<Timelocks, Box<dyn std::error::Error>> {
+        match self.locktime {
+            Some(_) => Ok(self.fixed_timelocks()),
+            None => anti_fee_sniping.timelocks(self.sequences(), electrum_client, rng),
+        }
+    }
+
+    /// The timelocks given, with a locktime of zero unless `--locktime` sets one. Cosigners
+    /// building the same transaction each on their own must all get the same one.
+    fn fixed_timelocks(self) -> Timelocks {
+        Timelocks {
+            lock_time: self.locktime.unwrap_or(LockTime::ZERO),
+            sequences: self.sequences(),
+        }
+    }
+
+    fn sequences(&self) -> Vec<Sequence> {
+        match self.csv.is_empty() {
+            true => self.sequence.clone(),
+            false => self.csv.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Args)]
+struct AntiFeeSnipingArgs {
+    /// Leave the locktime at zero instead of the current block height (anti-fee-sniping)
+    #[clap(long)]
+    no_anti_fee_sniping: bool,
+}
+
+impl AntiFeeSnipingArgs {
+    fn timelocks(
+        self,
+        sequences: Vec<Sequence>,
+        electrum_client: &Client,
+        rng: &mut dyn RngCore,
+    ) -> Result<Timelocks, Box<dyn std::error::Error>> {
+        match self.no_anti_fee_sniping {
+            true => Ok(Timelocks {
+                lock_time: LockTime::ZERO,
+                sequences,
+            }),
+            false => Timelocks::anti_fee_sniping(sequences, electrum_client, rng),
+        }
+    }
+}
+
+fn parse_network(s: &str) -> Result<Network, String> {
+    match s {
+        "mainnet" => Ok(Network::Bitcoin),
+        s => Network::from_str(s).map_err(|e| e.to_string()),
+    }
+}
+
+/// The Electrum server we talk to when none is given: a local node on the default port.
+fn default_electrum_url(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "tcp://localhost:50001",
+        Network::Testnet => "tcp://localhost:60001",
+        Network::Signet => "tcp://localhost:60601",
+        _ => "tcp://localhost:60401",
+    }
+}
+
+/// A broadcaster for the signed transaction when `--broadcast` was passed.
+fn broadcaster<'a>(
+    args: &BroadcastArgs,
+    electrum_client: &'a Client,
+    wallet_store_path: PathBuf,
+) -> Option<Broadcaster<'a>> {
+    args.broadcast.then(|| {
+        Broadcaster::new(
+            electrum_client,
+            wallet_store_path,
+            args.wait_confirm,
+            Duration::from_secs(args.wait_timeout * 60),
+        )
+    })
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
     let secp = Secp256k1::new();
-    let electrum_client = electrum_client::Client::new(&cli.electrum)?;
+    let electrum_url = cli
+        .electrum
+        .clone()
+        .unwrap_or_else(|| default_electrum_url(cli.network).to_string());
+    // Only commands that talk to the chain connect, so offline commands work without a node
+    let electrum_client = || electrum_client::Client::new(&electrum_url);
+    let keyring = Keyring::new(&cli.keyring);
+
+    let account_xpub = |name: &str, script_type: ScriptType| {
+        common::keys::read_account_xpub(
+            &secp,
+            &keyring.existing_key_path(name)?,
+            script_type,
+            cli.network,
+        )
+    };
+    let single_sig_wallet = |script_type: ScriptType| {
+        Wallet::from_account_xpub(
+            script_type,
+            account_xpub(&cli.key, script_type)?,
+            cli.network,
+        )
+    };
+    // Reads the key once for both accounts, so an encrypted key only prompts once
+    let wallets =
+        |wallet: Option<String>| -> Result<Vec<(String, Wallet)>, Box<dyn std::error::Error>> {
+            if let Some(wallet) = wallet {
+                return Ok(vec![watch::resolve_wallet(
+                    &keyring.wallet_store_path(),
+                    &wallet,
+                    cli.network,
+                )?]);
+            }
+            let key = common::keys::read_key(keyring.existing_key_path(&cli.key)?, cli.network)?;
+            [ScriptType::Wpkh, ScriptType::Tr]
+                .into_iter()
+                .map(|script_type| {
+                    let wallet = Wallet::for_key(&secp, &key, script_type)?;
+                    Ok((script_type.name().to_string(), wallet))
+                })
+                .collect()
+        };
+    let rng = || common::entropy::rng(cli.deterministic_seed.as_deref(), cli.network);
+    let keychain = |change: bool| match change {
+        true => Keychain::Internal,
+        false => Keychain::External,
+    };
 
     match cli.command {
-        Commands::Keygen { path } => common::keys::generate_key(&secp, &path),
-        Commands::Pubkey { path } => common::keys::read_pubkey(&secp, &path),
+        Commands::Keygen {
+            words,
+            passphrase,
+            shares,
+        } => common::keys::generate_key(
+            &secp,
+            &keyring.new_key_path(&cli.key)?,
+            words,
+            passphrase.as_deref(),
+            shares,
+            cli.network,
+            &mut *rng()?,
+        ),
+        Commands::Recover { shares, passphrase } => common::keys::recover_key(
+            &secp,
+            &keyring.new_key_path(&cli.key)?,
+            &shares,
+            passphrase.as_deref(),
+            cli.network,
+        ),
+        Commands::Restore {
+            mnemonic,
+            passphrase,
+        } => common::keys::restore_key(
+            &secp,
+            &keyring.new_key_path(&cli.key)?,
+            &mnemonic,
+            passphrase.as_deref(),
+            cli.network,
+        ),
+        Commands::Pubkey => common::keys::read_pubkey(&secp, &keyring.path(&cli.key)?, cli.network),
+        Commands::EncryptKey => {
+            common::keystore::encrypt_key(&keyring.existing_key_path(&cli.key)?)
+        }
+        Commands::ChangePassword => {
+            common::keystore::change_password(&keyring.existing_key_path(&cli.key)?)
+        }
+        Commands::Key { command } => match command {
+            KeyCommands::List => common::keyring::list_keys(&keyring),
+            KeyCommands::Show { name } => {
+                common::keyring::show_key(&secp, &keyring, &name, cli.network)
+            }
+            KeyCommands::Delete { name, yes } => common::keyring::delete_key(&keyring, &name, yes),
+            KeyCommands::Import { name, secret } => common::key_formats::import_key(
+                &secp,
+                &keyring.new_key_path(&name)?,
+                secret.as_deref(),
+                cli.network,
+            ),
+            KeyCommands::Export {
+                name,
+                format,
+                script_type,
+                change,
+                index,
+                private,
+            } => common::key_formats::export_key(
+                &secp,
+                &keyring.existing_key_path(&name)?,
+                format,
+                script_type,
+                keychain(change),
+                index,
+                private,
+                cli.network,
+            ),
+        },
+        Commands::Utxos { wallet } => common::utxos::print_utxos(
+            &wallets(wallet)?,
+            &keyring.wallet_store_path(),
+            &keyring.labels_path(),
+            &electrum_client()?,
+        ),
+        Commands::History { wallet } => common::history::print_history(
+            &wallets(wallet)?,
+            &keyring.wallet_store_path(),
+            &keyring.labels_path(),
+            &electrum_client()?,
+        ),
+        Commands::Label { command } => match command {
+            LabelCommands::Set {
+                label_type,
+                reference,
+                label,
+            } => labels::set_label(&keyring.labels_path(), label_type, &reference, &label),
+            LabelCommands::List => labels::list_labels(&keyring.labels_path()),
+            LabelCommands::Import { file } => labels::import_labels(&keyring.labels_path(), &file),
+            LabelCommands::Export { file } => labels::export_labels(&keyring.labels_path(), &file),
+        },
+        Commands::Balance { wallet } => common::utxos::print_balance(
+            &wallets(wallet)?,
+            &keyring.wallet_store_path(),
+            &electrum_client()?,
+        ),
+        Commands::Send {
+            destination,
+            amount,
+            batch,
+            script_type,
+            wallet,
+            coin_selection,
+            fee_rate,
+            target_blocks,
+            anti_fee_sniping,
+            force,
+            broadcast,
+        } => {
+            let payments = match (batch, destination, amount) {
+                (Some(batch), _, _) => send::read_batch(&batch, cli.network)?,
+                (None, Some(destination), Some(amount)) => {
+                    vec![send::Payment::parse(&destination, &amount, cli.network)?]
+                }
+                _ => unreachable!("clap requires a destination and amount without --batch"),
+            };
+            let electrum_client = electrum_client()?;
+            let fee_rate = fees::fee_rate(fee_rate, target_blocks, &electrum_client)?;
+            let broadcaster =
+                broadcaster(&broadcast, &electrum_client, keyring.wallet_store_path());
+            let mut rng = rng()?;
+            let timelocks = anti_fee_sniping.timelocks(Vec::new(), &electrum_client, &mut *rng)?;
+            match wallet {
+                Some(wallet) => send::send_from_wallet(
+                    &watch::resolve_wallet(&keyring.wallet_store_path(), &wallet, cli.network)?.1,
+                    &keyring.wallet_store_path(),
+                    &keyring.labels_path(),
+                    &send::Recipients::Payments(payments),
+                    coin_selection,
+                    fee_rate,
+                    &timelocks,
+                    force,
+                    &electrum_client,
+                    &mut *rng,
+                ),
+                None => send::send_from_key(
+                    &secp,
+                    &keyring.existing_key_path(&cli.key)?,
+                    script_type,
+                    &keyring.wallet_store_path(),
+                    &keyring.labels_path(),
+                    &send::Recipients::Payments(payments),
+                    coin_selection,
+                    fee_rate,
+                    cli.network,
+                    &timelocks,
+                    force,
+                    &electrum_client,
+                    &mut *rng,
+                    broadcaster.as_ref(),
+                ),
+            }
+        }
+        Commands::Sweep {
+            destination,
+            wif,
+            script_type,
+            wallet,
+            fee_rate,
+            target_blocks,
+            anti_fee_sniping,
+            force,
+            broadcast,
+        } => {
+            let electrum_client = electrum_client()?;
+            let fee_rate = fees::fee_rate(fee_rate, target_blocks, &electrum_client)?;
+            let broadcaster =
+                broadcaster(&broadcast, &electrum_client, keyring.wallet_store_path());
+            let mut rng = rng()?;
+            let timelocks = anti_fee_sniping.timelocks(Vec::new(), &electrum_client, &mut *rng)?;
+            let recipients = send::Recipients::Sweep(
+                bitcoin::Address::from_str(&destination)?.require_network(cli.network)?,
+            );
+            match (wif, wallet) {
+                (Some(wif), _) => sweep::sweep_key(
+                    &secp,
+                    &wif,
+                    &destination,
+                    fee_rate,
+                    cli.network,
+                    &timelocks,
+                    force,
+                    &electrum_client,
+                    &mut *rng,
+                    broadcaster.as_ref(),
+                ),
+                (None, Some(wallet)) => send::send_from_wallet(
+                    &watch::resolve_wallet(&keyring.wallet_store_path(), &wallet, cli.network)?.1,
+                    &keyring.wallet_store_path(),
+                    &keyring.labels_path(),
+                    &recipients,
+                    CoinSelection::BranchAndBound,
+                    fee_rate,
+                    &timelocks,
+                    force,
+                    &electrum_client,
+                    &mut *rng,
+                ),
+                (None, None) => send::send_from_key(
+                    &secp,
+                    &keyring.existing_key_path(&cli.key)?,
+                    script_type,
+                    &keyring.wallet_store_path(),
+                    &keyring.labels_path(),
+                    &recipients,
+                    CoinSelection::BranchAndBound,
+                    fee_rate,
+                    cli.network,
+                    &timelocks,
+                    force,
+                    &electrum_client,
+                    &mut *rng,
+                    broadcaster.as_ref(),
+                ),
+            }
+        }
+        Commands::BumpFee {
+            txid,
+            fee_rate,
+            wallet,
+            anti_fee_sniping,
+            force,
+            broadcast,
+        } => {
+            let electrum_client = electrum_client()?;
+            // With a fee rate given this only checks it against the minimum relay fee
+            let fee_rate = fees::fee_rate(
+                Some(fee_rate),
+                fees::DEFAULT_TARGET_BLOCKS,
+                &electrum_client,
+            )?;
+            let broadcaster =
+                broadcaster(&broadcast, &electrum_client, keyring.wallet_store_path());
+            let mut rng = rng()?;
+            let timelocks = anti_fee_sniping.timelocks(Vec::new(), &electrum_client, &mut *rng)?;
+            match wallet {
+                Some(wallet) => bump::bump_fee_from_wallet(
+                    &watch::resolve_wallet(&keyring.wallet_store_path(), &wallet, cli.network)?.1,
+                    &keyring.wallet_store_path(),
+                    &txid,
+                    fee_rate,
+                    &timelocks,
+                    force,
+                    &electrum_client,
+                ),
+                None => bump::bump_fee_from_key(
+                    &secp,
+                    &keyring.existing_key_path(&cli.key)?,
+                    &keyring.wallet_store_path(),
+                    &txid,
+                    fee_rate,
+                    cli.network,
+                    &timelocks,
+                    force,
+                    &electrum_client,
+                    &mut *rng,
+                    broadcaster.as_ref(),
+                ),
+            }
+        }
+        Commands::Cpfp {
+            outpoint,
+            target_fee_rate,
+            anti_fee_sniping,
+            force,
+            broadcast,
+        } => {
+            let electrum_client = electrum_client()?;
+            // The child pays more than the target for itself, so a target below the minimum
+            // relay fee could only come from a typo
+            let target_fee_rate = fees::fee_rate(
+                Some(target_fee_rate),
+                fees::DEFAULT_TARGET_BLOCKS,
+                &electrum_client,
+            )?;
+            let broadcaster =
+                broadcaster(&broadcast, &electrum_client, keyring.wallet_store_path());
+            let mut rng = rng()?;
+            let timelocks = anti_fee_sniping.timelocks(Vec::new(), &electrum_client, &mut *rng)?;
+            bump::cpfp(
+                &secp,
+                &keyring.existing_key_path(&cli.key)?,
+                &keyring.wallet_store_path(),
+                &outpoint,
+                target_fee_rate,
+                cli.network,
+                &timelocks,
+                force,
+                &electrum_client,
+                &mut *rng,
+                broadcaster.as_ref(),
+            )
+        }
+        Commands::SpendMixed {
+            destination,
+            prevouts,
+            amount,
+            multisig,
+            fee_rate,
+            target_blocks,
+            timelocks,
+            anti_fee_sniping,
+            force,
+            broadcast,
+        } => {
+            let multisig_wallets = multisig
+                .iter()
+                .map(|wallet| {
+                    watch::resolve_wallet(&keyring.wallet_store_path(), wallet, cli.network)
+                        .map(|(_, wallet)| wallet)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let electrum_client = electrum_client()?;
+            let mut rng = rng()?;
+            mixed::create_transaction(
+                &secp,
+                &destination,
+                &prevouts,
+                &amount,
+                &multisig_wallets,
+                &keyring.existing_key_path(&cli.key)?,
+                &keyring.wallet_store_path(),
+                fees::fee_rate(fee_rate, target_blocks, &electrum_client)?,
+                cli.network,
+                &timelocks.timelocks(anti_fee_sniping, &electrum_client, &mut *rng)?,
+                force,
+                &electrum_client,
+                &mut *rng,
+                broadcaster(&broadcast, &electrum_client, keyring.wallet_store_path()).as_ref(),
+            )
+        }
+        Commands::Rebroadcast => {
+            broadcast::rebroadcast(&keyring.wallet_store_path(), &electrum_client()?)
+        }
+        Commands::Watch { command } => match command {
+            WatchCommands::Add {
+                name,
+                public,
+                script_type,
+            } => watch::add_wallet(
+                &keyring.wallet_store_path(),
+                &name,
+                &public,
+                script_type,
+                cli.network,
+            ),
+            WatchCommands::List => watch::list_wallets(&keyring.wallet_store_path()),
+            WatchCommands::NewAddress { name, change } => common::wallet::new_address(
+                &watch::load_wallet(&keyring.wallet_store_path(), &name, cli.network)?,
+                &keyring.wallet_store_path(),
+                keychain(change),
+            ),
+            WatchCommands::Balance { name } => common::utxos::print_balance(
+                &[(
+                    name.clone(),
+                    watch::load_wallet(&keyring.wallet_store_path(), &name, cli.network)?,
+                )],
+                &keyring.wallet_store_path(),
+                &electrum_client()?,
+            ),
+            WatchCommands::CreatePsbt {
+                name,
+                destination,
+                prevout,
+                amount,
+                fee_rate,
+                target_blocks,
+                timelocks,
+                anti_fee_sniping,
+                force,
+            } => {
+                let electrum_client = electrum_client()?;
+                watch::create_psbt(
+                    &watch::load_wallet(&keyring.wallet_store_path(), &name, cli.network)?,
+                    &keyring.wallet_store_path(),
+                    &destination,
+                    &prevout,
+                    &amount,
+                    fees::fee_rate(fee_rate, target_blocks, &electrum_client)?,
+                    cli.network,
+                    &timelocks.timelocks(anti_fee_sniping, &electrum_client, &mut *rng()?)?,
+                    force,
+                    &electrum_client,
+                )
+            }
+        },
         Commands::Wpkh { command } => match command {
-            WpkhCommands::GenerateAddress { public_key } => wpkh::generate_address(public_key),
+            WpkhCommands::GenerateAddress { public_key, index } => {
+                let public_key = match public_key {
+                    Some(public_key) => public_key,
+                    None => account_xpub(&cli.key, ScriptType::Wpkh)?.to_string(),
+                };
+                wpkh::generate_address(&secp, &public_key, index, cli.network)
+            }
             WpkhCommands::SignTransaction {
                 destination,
-                prevout,
+                prevouts,
                 amount,
-            } => wpkh::create_transaction(&destination, &prevout, &amount, &electrum_client),
+                fee_rate,
+                target_blocks,
+                timelocks,
+                anti_fee_sniping,
+                force,
+                broadcast,
+            } => {
+                let electrum_client = electrum_client()?;
+                let fee_rate = fees::fee_rate(fee_rate, target_blocks, &electrum_client)?;
+                wpkh::create_transaction(
+                    &secp,
+                    &destination,
+                    &prevouts,
+                    &amount,
+                    &keyring.existing_key_path(&cli.key)?,
+                    &keyring.wallet_store_path(),
+                    fee_rate,
+                    cli.network,
+                    &timelocks.timelocks(anti_fee_sniping, &electrum_client, &mut *rng()?)?,
+                    force,
+                    &electrum_client,
+                    broadcaster(&broadcast, &electrum_client, keyring.wallet_store_path()).as_ref(),
+                )
+            }
+            WpkhCommands::NewAddress { change } => common::wallet::new_address(
+                &single_sig_wallet(ScriptType::Wpkh)?,
+                &keyring.wallet_store_path(),
+                keychain(change),
+            ),
         },
         Commands::Tr { command } => match command {
-            TrCommands::GenerateAddress { public_key } => {
-                tr::keyspend::generate_address(&secp, &public_key)
+            TrCommands::GenerateAddress { public_key, index } => {
+                let public_key = match public_key {
+                    Some(public_key) => public_key,
+                    None => account_xpub(&cli.key, ScriptType::Tr)?.to_string(),
+                };
+                tr::keyspend::generate_address(&secp, &public_key, index, cli.network)
             }
             TrCommands::SignTransaction {
                 destination,
-                prevout,
+                prevouts,
                 amount,
-            } => tr::keyspend::create_transaction(
-                &secp,
-                &electrum_client,
-                &destination,
-                &prevout,
-                &amount,
+                fee_rate,
+                target_blocks,
+                timelocks,
+                anti_fee_sniping,
+                force,
+                broadcast,
+            } => {
+                let electrum_client = electrum_client()?;
+                let fee_rate = fees::fee_rate(fee_rate, target_blocks, &electrum_client)?;
+                let mut rng = rng()?;
+                tr::keyspend::create_transaction(
+                    &secp,
+                    &electrum_client,
+                    &destination,
+                    &prevouts,
+                    &amount,
+                    &keyring.existing_key_path(&cli.key)?,
+                    &keyring.wallet_store_path(),
+                    fee_rate,
+                    cli.network,
+                    &timelocks.timelocks(anti_fee_sniping, &electrum_client, &mut *rng)?,
+                    force,
+                    &mut *rng,
+                    broadcaster(&broadcast, &electrum_client, keyring.wallet_store_path()).as_ref(),
+                )
+            }
+            TrCommands::NewAddress { change } => common::wallet::new_address(
+                &single_sig_wallet(ScriptType::Tr)?,
+                &keyring.wallet_store_path(),
+                keychain(change),
             ),
         },
         Commands::Wsh { command } => match command {
@@ -146,29 +1175,41 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 if public_keys.len() != 3 {
                     panic!("Must provide only 3 public keys!");
                 }
+                // Keys named after a keyring entry are replaced with their account xpub
+                let public_keys = public_keys
+                    .into_iter()
+                    .map(|key| match keyring.contains(&key) {
+                        true => account_xpub(&key, ScriptType::Wsh).map(|xpub| xpub.to_string()),
+                        false => Ok(key),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
                 wsh::threshold_sig::generate_descriptor(
                     &public_keys[0],
                     &public_keys[1],
                     &public_keys[2],
                 )
             }
-            WshCommands::GenerateAddress { descriptor } => {
-                wsh::threshold_sig::generate_address(&descriptor)
+            WshCommands::GenerateAddress { descriptor, index } => {
+                wsh::threshold_sig::generate_address(&descriptor, index, cli.network)
             }
+            WshCommands::NewAddress { descriptor, change } => common::wallet::new_address(
+                &Wallet::from_descriptor_str(&descriptor, cli.network)?,
+                &keyring.wallet_store_path(),
+                keychain(change),
+            ),
             WshCommands::SignPsbt {
                 descriptor,
                 destination,
                 prevout,
                 amount,
+                timelocks,
+                force,
             } => {
-                let prevout = OutPoint::from_str(&prevout).expect("Invalid outpoint");
-                let prev_tx = electrum_client
-                    .transaction_get(&prevout.txid)
-                    .expect("Unable to get previous transaction details");
-                let utxo_to_spend = prev_tx
-                    .output
-                    .get(prevout.vout as usize)
-                    .expect("Invalid vout");
+                let electrum_client = electrum_client()?;
+                let prevout = OutPoint::from_str(&prevout)
+                    .map_err(|e| format!("Invalid outpoint {}: {}", prevout, e))?;
+                let utxo_to_spend =
+                    common::utxos::get_txouts(&electrum_client, &[prevout])?.remove(0);
 
                 wsh::threshold_sig::create_signed_psbt(
                     &secp,
@@ -177,10 +1218,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     &destination,
                     prevout,
                     &amount,
+                    &keyring.existing_key_path(&cli.key)?,
+                    &keyring.wallet_store_path(),
+                    cli.network,
+                    &timelocks.fixed_timelocks(),
+                    force,
+                    &electrum_client,
                 )
             }
-            WshCommands::CombinePsbts { psbts } => {
-                wsh::threshold_sig::combine_psbts(&secp, &psbts[0], &psbts[1])
+            WshCommands::Sign { psbt } => wsh::threshold_sig::sign_psbt(
+                &secp,
+                &psbt,
+                &keyring.existing_key_path(&cli.key)?,
+                cli.network,
+            ),
+            WshCommands::CombinePsbts { psbts, broadcast } => {
+                // Combining works offline, so only connect when broadcasting
+                let electrum_client = match broadcast.broadcast {
+                    true => Some(electrum_client()?),
+                    false => None,
+                };
+                let broadcaster = electrum_client.as_ref().and_then(|client| {
+                    broadcaster(&broadcast, client, keyring.wallet_store_path())
+                });
+                wsh::threshold_sig::combine_psbts(&secp, &psbts[0], &psbts[1], broadcaster.as_ref())
             }
         },
     }
@@ -0,0 +1,477 @@
+use std::{path::Path, str::FromStr};
+
+use bitcoin::{
+    consensus, ecdsa,
+    hex::{Case, DisplayHex},
+    key::Secp256k1,
+    psbt::Psbt,
+    secp256k1::{rand::RngCore, All, Message},
+    sighash::{EcdsaSighashType, Prevouts, SighashCache},
+    transaction::Version,
+    Address, Amount,
+    Denomination::Satoshi,
+    FeeRate, Network, OutPoint, PrivateKey, Script, ScriptBuf, Transaction, TxIn, TxOut, Weight,
+    Witness,
+};
+use electrum_client::Client;
+use miniscript::{
+    descriptor::WshInner, psbt::PsbtExt, DefiniteDescriptorKey, Descriptor, Terminal,
+};
+
+use crate::common::{
+    broadcast::Broadcaster,
+    checks, fees,
+    keys::{self, Keychain, ScriptType, StoredKey},
+    timelocks::{self, Timelocks},
+    utxos,
+    wallet::{self, Wallet, WalletStore},
+};
+use crate::{tr::keyspend, wpkh, wsh::threshold_sig};
+
+/// How an input of a mixed spend is signed.
+pub(crate) enum MixedInput {
+    Wpkh(PrivateKey),
+    /// A key-path spend of the key tweaked with no script tree, as in BIP86
+    TrKeySpend(PrivateKey),
+    /// A P2WSH multisig output, with our key when we are one of its signers
+    WshMulti {
+        descriptor: Box<Descriptor<DefiniteDescriptorKey>>,
+        private_key: Option<PrivateKey>,
+    },
+}
+
+/// A mixed spend: a transaction once every input is signed, or a PSBT still missing multisig
+/// signatures.
+pub(crate) enum MixedSpend {
+    Transaction(Transaction),
+    Psbt(Psbt),
+}
+
+/// Spends `prevouts` of the key's P2WPKH and P2TR wallets and of the `multisig_wallets`
+/// together, to `destination_address`. What the amount and fee leave goes back to the key's
+/// P2WPKH wallet as change.
+#[allow(clippy::too_many_arguments)]
+pub fn create_transaction(
+    secp: &Secp256k1<All>,
+    destination_address: &str,
+    prevouts: &[String],
+    amount: &str,
+    multisig_wallets: &[Wallet],
+    key_path: &Path,
+    wallet_store_path: &Path,
+    fee_rate: FeeRate,
+    network: Network,
+    timelocks: &Timelocks,
+    force: bool,
+    electrum_client: &Client,
+    rng: &mut dyn RngCore,
+    broadcaster: Option<&Broadcaster>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dest_address = Address::from_str(destination_address)?.require_network(network)?;
+    let prevouts = prevouts
+        .iter()
+        .map(|prevout| {
+            OutPoint::from_str(prevout).map_err(|e| format!("Invalid outpoint {}: {}", prevout, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let amount = Amount::from_str_in(amount, Satoshi)?;
+    let utxos_to_spend = utxos::get_txouts(electrum_client, &prevouts)?;
+
+    let key = keys::read_key(key_path, network)?;
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let mut input_weight = Weight::ZERO;
+    let mut inputs = Vec::with_capacity(prevouts.len());
+    for (prevout, utxo_to_spend) in prevouts.into_iter().zip(utxos_to_spend) {
+        let (input, weight) = resolve_input(
+            secp,
+            &key,
+            multisig_wallets,
+            &store,
+            wallet_store_path,
+            &utxo_to_spend.script_pubkey,
+        )
+        .map_err(|e| format!("Input {}: {}", prevout, e))?;
+        input_weight += weight;
+        inputs.push((prevout, utxo_to_spend, input));
+    }
+
+    // Whatever the amount and fee leave goes back to us as change
+    let (change, change_wallet) = wallet::change_output(secp, &key, ScriptType::Wpkh, &store)?;
+    let mut outputs = vec![TxOut {
+        value: amount,
+        script_pubkey: dest_address.script_pubkey(),
+    }];
+    let input_value = inputs.iter().map(|(_, utxo, _)| utxo.value).sum();
+    let fee = fees::add_change(&mut outputs, change, input_value, input_weight, fee_rate)?;
+    let change_wallet = change_wallet.filter(|_| outputs.len() > 1);
+    let weight = fees::base_weight(&outputs) + input_weight;
+    checks::check_spend(input_value, &outputs, weight, force)?;
+    let prevouts = inputs
+        .iter()
+        .map(|(prevout, utxo, _)| (*prevout, utxo.clone()))
+        .collect::<Vec<_>>();
+    timelocks::check_timelocks(timelocks, &prevouts, electrum_client, force)?;
+
+    let spend = create_transaction_internal(secp, &inputs, outputs, timelocks, rng)?;
+
+    // Only now that the transaction exists is the change address used up
+    if let Some(change_wallet) = change_wallet {
+        store.reveal_next(&change_wallet, Keychain::Internal);
+        store.save()?;
+    }
+    println!(
+        "Fee: {} sat at {}",
+        fee.to_sat(),
+        fees::format_fee_rate(fee_rate)
+    );
+
+    match spend {
+        MixedSpend::Transaction(tx) => {
+            println!(
+                "Signed tx: {}",
+                consensus::serialize(&tx).to_hex_string(Case::Lower)
+            );
+            if let Some(broadcaster) = broadcaster {
+                broadcaster.broadcast(&tx)?;
+            }
+        }
+        MixedSpend::Psbt(psbt) => {
+            println!("Psbt: {}", psbt.serialize_hex());
+            println!(
+                "The multisig inputs need more signatures. Each cosigner adds theirs with `tx-fun \
+                 wsh sign <psbt>`, and `tx-fun wsh combine-psbts` finishes the transaction"
+            );
+            if broadcaster.is_some() {
+                println!("Not broadcasting an incomplete transaction");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Works out how to sign an input paying `script_pubkey`, and its weight once signed.
+fn resolve_input(
+    secp: &Secp256k1<All>,
+    key: &StoredKey,
+    multisig_wallets: &[Wallet],
+    store: &WalletStore,
+    wallet_store_path: &Path,
+    script_pubkey: &Script,
+) -> Result<(MixedInput, Weight), Box<dyn std::error::Error>> {
+    if script_pubkey.is_p2wpkh() {
+        let private_key = wallet::signing_key_for(
+            secp,
+            key,
+            ScriptType::Wpkh,
+            wallet_store_path,
+            script_pubkey,
+        )?;
+        return Ok((MixedInput::Wpkh(private_key), fees::WPKH_INPUT_WEIGHT));
+    }
+    if script_pubkey.is_p2tr() {
+        let private_key =
+            wallet::signing_key_for(secp, key, ScriptType::Tr, wallet_store_path, script_pubkey)?;
+        return Ok((
+            MixedInput::TrKeySpend(private_key),
+            fees::TR_KEY_SPEND_INPUT_WEIGHT,
+        ));
+    }
+    if !script_pubkey.is_p2wsh() {
+        return Err("Only P2WPKH, P2TR and P2WSH multisig outputs can be spent".into());
+    }
+
+    for wallet in multisig_wallets {
+        let Some((keychain, index)) = wallet.derivation_of(store, script_pubkey)? else {
+            continue;
+        };
+        let descriptor = wallet.descriptor(keychain)?;
+        // Without a key of our own in the descriptor, every signature comes from the cosigners
+        let private_key = threshold_sig::find_signing_key(secp, key, descriptor, index)?
+            .map(|(private_key, _)| private_key);
+        return Ok((
+            MixedInput::WshMulti {
+                descriptor: Box::new(descriptor.at_derivation_index(index)?),
+                private_key,
+            },
+            fees::input_weight(wallet.full_descriptor())?,
+        ));
+    }
+
+    Err("The P2WSH output is not in any `--multisig` wallet".into())
+}
+
+/// Builds and signs a transaction spending `inputs` of any kind to `outputs`. Comes out as a PSBT
+/// when the multisig inputs are short of signatures.
+pub(crate) fn create_transaction_internal(
+    secp: &Secp256k1<All>,
+    inputs: &[(OutPoint, TxOut, MixedInput)],
+    outputs: Vec<TxOut>,
+    timelocks: &Timelocks,
+    rng: &mut dyn RngCore,
+) -> Result<MixedSpend, Box<dyn std::error::Error>> {
+    let unsigned_tx = Transaction {
+        version: Version(2),
+        lock_time: timelocks.lock_time,
+        input: inputs
+            .iter()
+            .zip(timelocks.sequences(inputs.len())?)
+            .map(|((prevout, _, _), sequence)| TxIn {
+                previous_output: *prevout,
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: outputs,
+    };
+
+    // Taproot sighashes commit to every output being spent, including the segwit v0 ones
+    let utxos_to_spend = inputs.iter().map(|(_, utxo, _)| utxo).collect::<Vec<_>>();
+    let prevouts = Prevouts::All(&utxos_to_spend);
+
+    let mut psbt = Psbt::from_unsigned_tx(unsigned_tx.clone())?;
+    let mut cache = SighashCache::new(&unsigned_tx);
+    let mut complete = true;
+    for (index, (_, utxo_to_spend, input)) in inputs.iter().enumerate() {
+        psbt.inputs[index].witness_utxo = Some(utxo_to_spend.clone());
+        match input {
+            MixedInput::Wpkh(private_key) => {
+                let signature = wpkh::sign_input(
+                    secp,
+                    &mut cache,
+                    index,
+                    &utxo_to_spend.script_pubkey,
+                    utxo_to_spend.value,
+                    private_key,
+                )?;
+                psbt.inputs[index]
+                    .partial_sigs
+                    .insert(private_key.public_key(secp), signature);
+            }
+            MixedInput::TrKeySpend(private_key) => {
+                let signature =
+                    keyspend::sign_input(secp, &mut cache, index, &prevouts, private_key, rng)?;
+                psbt.inputs[index].tap_internal_key =
+                    Some(private_key.public_key(secp).inner.x_only_public_key().0);
+                psbt.inputs[index].tap_key_sig = Some(signature);
+            }
+            MixedInput::WshMulti {
+                descriptor,
+                private_key,
+            } => {
+                // The witness script and key origins let cosigners sign with `wsh sign`
+                psbt.update_input_with_descriptor(index, descriptor)?;
+                if let Some(private_key) = private_key {
+                    let witness_script = descriptor.derived_descriptor(secp)?.explicit_script()?;
+                    let sighash = cache.p2wsh_signature_hash(
+                        index,
+                        &witness_script,
+                        utxo_to_spend.value,
+                        EcdsaSighashType::All,
+                    )?;
+                    let msg = Message::from_digest_slice(&sighash[..])?;
+                    psbt.inputs[index].partial_sigs.insert(
+                        private_key.public_key(secp),
+                        ecdsa::Signature {
+                            sig: secp.sign_ecdsa_low_r(&msg, &private_key.inner),
+                            hash_ty: EcdsaSighashType::All,
+                        },
+                    );
+                }
+                complete &=
+                    psbt.inputs[index].partial_sigs.len() >= multisig_threshold(descriptor)?;
+            }
+        }
+    }
+    if !complete {
+        return Ok(MixedSpend::Psbt(psbt));
+    }
+
+    // Finalizing checks every signature against its input
+    let psbt = psbt
+        .finalize(secp)
+        .map_err(|(_, errors)| format!("Unable to finalize the transaction: {:?}", errors))?;
+
+    Ok(MixedSpend::Transaction(psbt.extract_tx()?))
+}
+
+/// How many signatures the P2WSH multisig `descriptor` needs.
+fn multisig_threshold(
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    match descriptor {
+        Descriptor::Wsh(wsh) => match wsh.as_inner() {
+            WshInner::SortedMulti(multi) => Ok(multi.k),
+            WshInner::Ms(ms) => match &ms.node {
+                Terminal::Multi(k, _) => Ok(*k),
+                _ => Err("Only multi and sortedmulti P2WSH outputs can be spent".into()),
+            },
+        },
+        _ => Err("Not a P2WSH descriptor".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        bip32::Xpriv,
+        key::Secp256k1,
+        secp256k1::{rand::SeedableRng, SecretKey},
+        Address, Amount, Network, OutPoint, PrivateKey, TxOut,
+    };
+    use miniscript::psbt::PsbtExt;
+    use rand_chacha::ChaCha20Rng;
+
+    use super::{create_transaction_internal, MixedInput, MixedSpend};
+    use crate::common::{
+        keys::{self, Keychain, ScriptType, StoredKey},
+        timelocks::Timelocks,
+        wallet::Wallet,
+    };
+    use crate::wsh::threshold_sig::{find_signing_key, sign_psbt_internal};
+
+    #[test]
+    fn test_mixed_spend() {
+        let secp = Secp256k1::new();
+        let private_key = PrivateKey::new(
+            SecretKey::from_slice(&[1; 32]).expect("Invalid private key"),
+            Network::Regtest,
+        );
+        let public_key = private_key.public_key(&secp);
+        let xprivs = [1u8, 2, 3]
+            .map(|seed| Xpriv::new_master(Network::Regtest, &[seed; 32]).expect("Master key"));
+        let descriptor = format!(
+            "wsh(sortedmulti(2,{}))",
+            xprivs
+                .iter()
+                .map(|xpriv| {
+                    let xpub = keys::account_xpub(&secp, xpriv, ScriptType::Wsh).expect("Xpub");
+                    format!("{}/<0;1>/*", xpub)
+                })
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        let wallet = Wallet::from_descriptor_str(&descriptor, Network::Regtest).expect("Wallet");
+        let multisig = wallet.descriptor(Keychain::External).expect("Descriptor");
+
+        // A key that isn't one of the signers has nothing to sign with, which is not an error
+        let stranger = Xpriv::new_master(Network::Regtest, &[4; 32]).expect("Master key");
+        assert!(
+            find_signing_key(&secp, &StoredKey::Master(stranger), multisig, 0)
+                .expect("Key lookup")
+                .is_none()
+        );
+        assert!(
+            find_signing_key(&secp, &StoredKey::Single(private_key), multisig, 0)
+                .expect("Key lookup")
+                .is_none()
+        );
+
+        let txout = |sats, address: Address| TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: address.script_pubkey(),
+        };
+        let outpoint = |vout| OutPoint {
+            txid: "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"
+                .parse()
+                .expect("Txid"),
+            vout,
+        };
+        let wsh_input = |private_key| MixedInput::WshMulti {
+            descriptor: Box::new(multisig.at_derivation_index(0).expect("Derived")),
+            private_key,
+        };
+        let inputs = |private_key| {
+            vec![
+                (
+                    outpoint(0),
+                    txout(
+                        30_000,
+                        Address::p2wpkh(&public_key, Network::Regtest).expect("Compressed"),
+                    ),
+                    MixedInput::Wpkh(private_key),
+                ),
+                (
+                    outpoint(1),
+                    txout(
+                        40_000,
+                        Address::p2tr(&secp, public_key.inner.into(), None, Network::Regtest),
+                    ),
+                    MixedInput::TrKeySpend(private_key),
+                ),
+                (
+                    outpoint(2),
+                    txout(
+                        50_000,
+                        wallet.address(Keychain::External, 0).expect("Address"),
+                    ),
+                    wsh_input(
+                        find_signing_key(&secp, &StoredKey::Master(xprivs[0]), multisig, 0)
+                            .expect("Key lookup")
+                            .map(|k| k.0),
+                    ),
+                ),
+            ]
+        };
+        let outputs = vec![txout(
+            110_000,
+            Address::p2wpkh(&public_key, Network::Regtest).expect("Compressed"),
+        )];
+
+        // One of two multisig signatures leaves a PSBT for the second cosigner
+        let spend = create_transaction_internal(
+            &secp,
+            &inputs(private_key),
+            outputs.clone(),
+            &Timelocks::default(),
+            &mut ChaCha20Rng::from_seed([7; 32]),
+        )
+        .expect("Mixed spend");
+        let MixedSpend::Psbt(psbt) = spend else {
+            panic!("Expected a PSBT while the multisig is short of signatures");
+        };
+        let psbt =
+            sign_psbt_internal(&secp, psbt, &StoredKey::Master(xprivs[1])).expect("Cosigned");
+
+        // Finalizing verifies the BIP143 and BIP341 signatures of every input
+        let tx = psbt
+            .finalize(&secp)
+            .expect("Finalized")
+            .extract_tx()
+            .expect("Extracted");
+        assert_eq!(tx.input.len(), 3);
+        assert!(tx.input.iter().all(|input| !input.witness.is_empty()));
+
+        // Without any multisig input it is signed outright
+        let mut single_sig = inputs(private_key);
+        single_sig.pop();
+        let spend = create_transaction_internal(
+            &secp,
+            &single_sig,
+            vec![txout(
+                60_000,
+                Address::p2wpkh(&public_key, Network::Regtest).expect("Compressed"),
+            )],
+            &Timelocks::default(),
+            &mut ChaCha20Rng::from_seed([7; 32]),
+        )
+        .expect("Single sig spend");
+        assert!(matches!(spend, MixedSpend::Transaction(_)));
+
+        // A key that doesn't match its input is an error, not a PSBT waiting for cosigners
+        let wrong_key = PrivateKey::new(
+            SecretKey::from_slice(&[2; 32]).expect("Invalid private key"),
+            Network::Regtest,
+        );
+        single_sig[0].2 = MixedInput::Wpkh(wrong_key);
+        assert!(create_transaction_internal(
+            &secp,
+            &single_sig,
+            outputs,
+            &Timelocks::default(),
+            &mut ChaCha20Rng::from_seed([7; 32]),
+        )
+        .is_err());
+    }
+}
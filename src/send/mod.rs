@@ -0,0 +1,498 @@
+use std::{fs, path::Path, slice, str::FromStr};
+
+use bitcoin::{
+    consensus,
+    hex::{Case, DisplayHex},
+    key::Secp256k1,
+    secp256k1::{rand::RngCore, All},
+    Address, Amount,
+    Denomination::Satoshi,
+    FeeRate, Network, ScriptBuf, TxOut, Weight,
+};
+use electrum_client::Client;
+
+use crate::common::{
+    broadcast::Broadcaster,
+    checks,
+    coin_selection::{self, CoinSelection, Selection, SelectionParams},
+    fees,
+    keys::{self, Keychain, ScriptType},
+    labels::{LabelType, Labels},
+    timelocks::Timelocks,
+    utxos::{self, Utxo},
+    wallet::{Wallet, WalletStore},
+};
+use crate::{tr, watch, wpkh};
+
+/// One recipient of a spend.
+pub struct Payment {
+    pub address: Address,
+    pub amount: Amount,
+    /// Saved as the address's label once the transaction is built
+    pub label: Option<String>,
+}
+
+impl Payment {
+    pub fn parse(
+        address: &str,
+        amount: &str,
+        network: Network,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Payment {
+            address: Address::from_str(address)?.require_network(network)?,
+            amount: Amount::from_str_in(amount, Satoshi)?,
+            label: None,
+        })
+    }
+
+    fn txout(&self) -> TxOut {
+        TxOut {
+            value: self.amount,
+            script_pubkey: self.address.script_pubkey(),
+        }
+    }
+}
+
+/// Reads the payments of a batch from a CSV file.
+pub fn read_batch(
+    path: &Path,
+    network: Network,
+) -> Result<Vec<Payment>, Box<dyn std::error::Error>> {
+    parse_batch(&fs::read_to_string(path)?, network)
+}
+
+/// Parses one `address,amount[,label]` row per recipient, with the amount in satoshis. Blank
+/// lines, `#` comments and an `address,amount` header are skipped.
+fn parse_batch(csv: &str, network: Network) -> Result<Vec<Payment>, Box<dyn std::error::Error>> {
+    let mut payments: Vec<Payment> = Vec::new();
+    for (i, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(3, ',').map(str::trim);
+        let address = fields.next().unwrap_or_default();
+        let amount = fields
+            .next()
+            .ok_or(format!("Line {} has no amount", i + 1))?;
+        if payments.is_empty() && address.eq_ignore_ascii_case("address") {
+            continue;
+        }
+
+        let mut payment = Payment::parse(address, amount, network)
+            .map_err(|e| format!("Invalid payment on line {}: {}", i + 1, e))?;
+        if payments
+            .iter()
+            .any(|other| other.address == payment.address)
+        {
+            return Err(format!("Line {} pays {} a second time", i + 1, address).into());
+        }
+        payment.label = fields
+            .next()
+            .map(|label| label.trim_matches('"').to_string())
+            .filter(|label| !label.is_empty());
+        payments.push(payment);
+    }
+    if payments.is_empty() {
+        return Err("The batch has no payments".into());
+    }
+
+    Ok(payments)
+}
+
+/// Who a spend pays.
+pub enum Recipients {
+    Payments(Vec<Payment>),
+    /// Everything the wallet can spend, less the fee, to one address
+    Sweep(Address),
+}
+
+impl Recipients {
+    fn payments(&self) -> &[Payment] {
+        match self {
+            Recipients::Payments(payments) => payments,
+            Recipients::Sweep(_) => &[],
+        }
+    }
+}
+
+/// A spend worked out from the wallet's coins, ready to sign.
+struct Spend {
+    utxos: Vec<Utxo>,
+    outputs: Vec<TxOut>,
+    /// What the signed transaction will weigh
+    weight: Weight,
+}
+
+impl Spend {
+    fn check(&self, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let input_value = self.utxos.iter().map(|utxo| utxo.txout.value).sum();
+        checks::check_spend(input_value, &self.outputs, self.weight, force)
+    }
+}
+
+/// Pays `recipients` from the `script_type` wallet of the key at `key_path`, selecting the
+/// inputs and signing them.
+#[allow(clippy::too_many_arguments)]
+pub fn send_from_key(
+    secp: &Secp256k1<All>,
+    key_path: &Path,
+    script_type: ScriptType,
+    wallet_store_path: &Path,
+    labels_path: &Path,
+    recipients: &Recipients,
+    algorithm: CoinSelection,
+    fee_rate: FeeRate,
+    network: Network,
+    timelocks: &Timelocks,
+    force: bool,
+    electrum_client: &Client,
+    rng: &mut dyn RngCore,
+    broadcaster: Option<&Broadcaster>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = keys::read_key(key_path, network)?;
+    let wallet = Wallet::for_key(secp, &key, script_type)?;
+
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let spend = plan_spend(
+        &wallet,
+        &mut store,
+        recipients,
+        algorithm,
+        fee_rate,
+        electrum_client,
+        rng,
+    )?;
+
+    spend.check(force)?;
+
+    let mut inputs = Vec::with_capacity(spend.utxos.len());
+    for utxo in &spend.utxos {
+        let private_key = keys::signing_key(secp, &key, script_type, utxo.keychain, utxo.index)?;
+        inputs.push((utxo.outpoint, utxo.txout.clone(), private_key));
+    }
+    let tx = match script_type {
+        ScriptType::Wpkh => {
+            wpkh::create_transaction_internal(secp, &inputs, spend.outputs, timelocks)?
+        }
+        ScriptType::Tr => {
+            tr::keyspend::create_transaction_internal(secp, &inputs, spend.outputs, timelocks, rng)?
+        }
+        ScriptType::Wsh => return Err("Multisig spends go through `send --wallet`".into()),
+    };
+    // Only now that the transaction exists is the change address used up
+    store.save()?;
+    save_labels(labels_path, recipients.payments())?;
+
+    println!(
+        "Signed tx: {}",
+        consensus::serialize(&tx).to_hex_string(Case::Lower)
+    );
+    if let Some(broadcaster) = broadcaster {
+        broadcaster.broadcast(&tx)?;
+    }
+
+    Ok(())
+}
+
+/// Pays `recipients` from a watch-only wallet, printing an unsigned PSBT for its signers.
+#[allow(clippy::too_many_arguments)]
+pub fn send_from_wallet(
+    wallet: &Wallet,
+    wallet_store_path: &Path,
+    labels_path: &Path,
+    recipients: &Recipients,
+    algorithm: CoinSelection,
+    fee_rate: FeeRate,
+    timelocks: &Timelocks,
+    force: bool,
+    electrum_client: &Client,
+    rng: &mut dyn RngCore,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let spend = plan_spend(
+        wallet,
+        &mut store,
+        recipients,
+        algorithm,
+        fee_rate,
+        electrum_client,
+        rng,
+    )?;
+
+    spend.check(force)?;
+
+    let inputs = spend
+        .utxos
+        .iter()
+        .map(|utxo| (utxo.outpoint, utxo.txout.clone()))
+        .collect::<Vec<_>>();
+    let psbt = watch::create_psbt_internal(wallet, &store, &inputs, spend.outputs, timelocks)?;
+    store.save()?;
+    save_labels(labels_path, recipients.payments())?;
+
+    println!("Psbt: {}", psbt.serialize_hex());
+
+    Ok(())
+}
+
+/// Works out the inputs and outputs paying `recipients` from the wallet's coins, and reports
+/// the outcome.
+fn plan_spend(
+    wallet: &Wallet,
+    store: &mut WalletStore,
+    recipients: &Recipients,
+    algorithm: CoinSelection,
+    fee_rate: FeeRate,
+    electrum_client: &Client,
+    rng: &mut dyn RngCore,
+) -> Result<Spend, Box<dyn std::error::Error>> {
+    // Coins from others only count once confirmed, but our own change can be spent right away
+    let (utxos, unconfirmed): (Vec<_>, Vec<_>) =
+        utxos::list_unspent(wallet, store, electrum_client)?
+            .into_iter()
+            .partition(|utxo| utxo.height.is_some() || utxo.keychain == Keychain::Internal);
+
+    match recipients {
+        Recipients::Payments(payments) => {
+            plan_payments(wallet, store, utxos, payments, algorithm, fee_rate, rng)
+        }
+        Recipients::Sweep(address) => plan_sweep(wallet, utxos, &unconfirmed, address, fee_rate),
+    }
+}
+
+/// Selects the inputs covering `payments` and reveals a change address if the selection needs
+/// one.
+fn plan_payments(
+    wallet: &Wallet,
+    store: &mut WalletStore,
+    utxos: Vec<Utxo>,
+    payments: &[Payment],
+    algorithm: CoinSelection,
+    fee_rate: FeeRate,
+    rng: &mut dyn RngCore,
+) -> Result<Spend, Box<dyn std::error::Error>> {
+    let amount = payments.iter().map(|payment| payment.amount).sum();
+
+    let change_keychain = wallet.change_keychain();
+    let change_index = store
+        .last_index(&wallet.id(), change_keychain)
+        .map_or(0, |i| i + 1);
+    let change_script = wallet
+        .address(change_keychain, change_index)?
+        .script_pubkey();
+
+    let mut outputs = payments.iter().map(Payment::txout).collect::<Vec<_>>();
+    let params = selection_params(wallet, &outputs, change_script.clone(), fee_rate)?;
+    let selection = coin_selection::select_coins(utxos, amount, &params, algorithm, rng)?;
+
+    let change = match selection.change {
+        Some(value) => {
+            store.reveal_next(wallet, change_keychain);
+            // Put the change at a random position so it can't be told apart by its place
+            let position = rng.next_u32() as usize % (outputs.len() + 1);
+            outputs.insert(
+                position,
+                TxOut {
+                    value,
+                    script_pubkey: change_script,
+                },
+            );
+            Some((change_keychain, change_index))
+        }
+        None => None,
+    };
+
+    print_selection(wallet, &selection, change, payments, fee_rate)?;
+    let weight = fees::base_weight(&outputs) + params.input_weight * selection.utxos.len() as u64;
+
+    Ok(Spend {
+        utxos: selection.utxos,
+        outputs,
+        weight,
+    })
+}
+
+/// Spends every coin to `address`, which gets whatever is left after the fee. The `unconfirmed`
+/// coins from others are left out.
+fn plan_sweep(
+    wallet: &Wallet,
+    utxos: Vec<Utxo>,
+    unconfirmed: &[Utxo],
+    address: &Address,
+    fee_rate: FeeRate,
+) -> Result<Spend, Box<dyn std::error::Error>> {
+    // A sweep is meant to empty the wallet, so say what it can't take along yet
+    if !unconfirmed.is_empty() {
+        println!(
+            "Leaving out {} unconfirmed coin(s) from others until they confirm:",
+            unconfirmed.len()
+        );
+        print_inputs(unconfirmed);
+    }
+    if utxos.is_empty() {
+        return Err("There are no confirmed coins to sweep".into());
+    }
+    let input_value = utxos.iter().map(|utxo| utxo.txout.value).sum::<Amount>();
+
+    let mut output = TxOut {
+        value: Amount::ZERO,
+        script_pubkey: address.script_pubkey(),
+    };
+    let input_weight = fees::input_weight(wallet.descriptor(Keychain::External)?)?;
+    let weight = fees::base_weight(slice::from_ref(&output)) + input_weight * utxos.len() as u64;
+    let fee = fees::fee_for(fee_rate, weight);
+    output.value = input_value.checked_sub(fee).ok_or(format!(
+        "The {} sat swept would not cover the {} sat fee",
+        input_value.to_sat(),
+        fee.to_sat()
+    ))?;
+
+    println!("Sweeping {} input(s):", utxos.len());
+    print_inputs(&utxos);
+    println!("Payment: {} sat to {}", output.value.to_sat(), address);
+    println!(
+        "Fee: {} sat at {}",
+        fee.to_sat(),
+        fees::format_fee_rate(fee_rate)
+    );
+
+    Ok(Spend {
+        utxos,
+        outputs: vec![output],
+        weight,
+    })
+}
+
+fn selection_params(
+    wallet: &Wallet,
+    outputs: &[TxOut],
+    change_script: ScriptBuf,
+    fee_rate: FeeRate,
+) -> Result<SelectionParams, Box<dyn std::error::Error>> {
+    let change = TxOut {
+        value: Amount::ZERO,
+        script_pubkey: change_script,
+    };
+
+    Ok(SelectionParams {
+        fee_rate,
+        base_weight: fees::base_weight(outputs),
+        input_weight: fees::input_weight(wallet.descriptor(Keychain::External)?)?,
+        change_weight: fees::output_weight(&change),
+        min_change: change.script_pubkey.dust_value(),
+    })
+}
+
+fn print_selection(
+    wallet: &Wallet,
+    selection: &Selection,
+    change: Option<(Keychain, u32)>,
+    payments: &[Payment],
+    fee_rate: FeeRate,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!(
+        "Selected {} input(s) with {}:",
+        selection.utxos.len(),
+        selection.algorithm.name()
+    );
+    print_inputs(&selection.utxos);
+    for payment in payments {
+        println!(
+            "Payment: {} sat to {}",
+            payment.amount.to_sat(),
+            payment.address
+        );
+    }
+    match (selection.change, change) {
+        (Some(value), Some((keychain, index))) => println!(
+            "Change: {} sat to {} ({} index {})",
+            value.to_sat(),
+            wallet.address(keychain, index)?,
+            keychain.name(),
+            index
+        ),
+        _ => println!("Change: none, the selection matches the payment closely enough"),
+    }
+    println!(
+        "Fee: {} sat at {}",
+        selection.fee.to_sat(),
+        fees::format_fee_rate(fee_rate)
+    );
+
+    Ok(())
+}
+
+fn print_inputs(utxos: &[Utxo]) {
+    for Utxo {
+        outpoint,
+        txout,
+        keychain,
+        index,
+        ..
+    } in utxos
+    {
+        println!(
+            "  {} {} sat ({} index {})",
+            outpoint,
+            txout.value.to_sat(),
+            keychain.name(),
+            index
+        );
+    }
+}
+
+/// Labels the address of every payment that came with a label.
+fn save_labels(labels_path: &Path, payments: &[Payment]) -> Result<(), Box<dyn std::error::Error>> {
+    if payments.iter().all(|payment| payment.label.is_none()) {
+        return Ok(());
+    }
+    let mut labels = Labels::load(labels_path)?;
+    for payment in payments {
+        if let Some(label) = &payment.label {
+            labels.set(LabelType::Addr, &payment.address.to_string(), label);
+        }
+    }
+
+    labels.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Network;
+
+    use super::parse_batch;
+
+    #[test]
+    fn test_parse_batch() {
+        let csv = "address,amount,label
+# Regtest test accounts
+bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu,10000,\"Alice, QA\"
+
+bcrt1qset9wrnzguwgpk2uk7g7ewgxh660u9creeve79, 25000
+";
+        let payments = parse_batch(csv, Network::Regtest).expect("Valid batch");
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments[0].amount.to_sat(), 10_000);
+        assert_eq!(payments[0].label.as_deref(), Some("Alice, QA"));
+        assert_eq!(payments[1].amount.to_sat(), 25_000);
+        assert_eq!(payments[1].label, None);
+
+        // Bad rows are reported with their line number
+        let error = parse_batch(
+            "bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu,10000\nbcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu,1",
+            Network::Regtest,
+        );
+        assert!(error.is_err_and(|e| e.to_string().contains("Line 2")));
+        assert!(parse_batch(
+            "bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu,ten",
+            Network::Regtest
+        )
+        .is_err());
+        assert!(parse_batch(
+            "bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c,10000",
+            Network::Regtest
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use std::{slice, str::FromStr};
+
+use bitcoin::{
+    consensus, ecdsa,
+    hex::{Case, DisplayHex},
+    key::Secp256k1,
+    script::{Builder, PushBytesBuf},
+    secp256k1::{rand::RngCore, All, Message},
+    sighash::{EcdsaSighashType, Prevouts, SighashCache},
+    transaction::Version,
+    Address, Amount, FeeRate, Network, OutPoint, PrivateKey, ScriptBuf, Transaction, TxIn, TxOut,
+    Weight, Witness,
+};
+use electrum_client::{Client, ElectrumApi};
+
+use crate::{
+    common::{broadcast::Broadcaster, checks, fees, timelocks::Timelocks},
+    tr::keyspend,
+    wpkh,
+};
+
+/// The kinds of output a single key can control.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum KeyScript {
+    P2pkh,
+    /// P2WPKH nested in P2SH, as older segwit wallets used
+    P2shP2wpkh,
+    P2wpkh,
+    /// Key-path spends of the key tweaked with no script tree, as in BIP86
+    P2tr,
+}
+
+impl KeyScript {
+    const ALL: [KeyScript; 4] = [
+        KeyScript::P2pkh,
+        KeyScript::P2shP2wpkh,
+        KeyScript::P2wpkh,
+        KeyScript::P2tr,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            KeyScript::P2pkh => "P2PKH",
+            KeyScript::P2shP2wpkh => "P2SH-P2WPKH",
+            KeyScript::P2wpkh => "P2WPKH",
+            KeyScript::P2tr => "P2TR",
+        }
+    }
+
+    /// The address of this kind for `private_key`. Only P2PKH works with uncompressed keys.
+    fn address(
+        &self,
+        secp: &Secp256k1<All>,
+        private_key: &PrivateKey,
+        network: Network,
+    ) -> Option<Address> {
+        let public_key = private_key.public_key(secp);
+        match self {
+            KeyScript::P2pkh => Some(Address::p2pkh(&public_key, network)),
+            KeyScript::P2shP2wpkh => Address::p2shwpkh(&public_key, network).ok(),
+            KeyScript::P2wpkh => Address::p2wpkh(&public_key, network).ok(),
+            KeyScript::P2tr => public_key
+                .compressed
+                .then(|| Address::p2tr(secp, public_key.inner.into(), None, network)),
+        }
+    }
+
+    fn input_weight(&self, compressed: bool) -> Weight {
+        match self {
+            KeyScript::P2pkh if compressed => fees::P2PKH_INPUT_WEIGHT,
+            KeyScript::P2pkh => fees::P2PKH_INPUT_WEIGHT + Weight::from_non_witness_data_size(32),
+            KeyScript::P2shP2wpkh => fees::P2SH_WPKH_INPUT_WEIGHT,
+            KeyScript::P2wpkh => fees::WPKH_INPUT_WEIGHT,
+            KeyScript::P2tr => fees::TR_KEY_SPEND_INPUT_WEIGHT,
+        }
+    }
+}
+
+/// Spends every coin of a WIF key, on any of the outputs it can control, to
+/// `destination_address`, which gets whatever is left after the fee.
+#[allow(clippy::too_many_arguments)]
+pub fn sweep_key(
+    secp: &Secp256k1<All>,
+    wif: &str,
+    destination_address: &str,
+    fee_rate: FeeRate,
+    network: Network,
+    timelocks: &Timelocks,
+    force: bool,
+    electrum_client: &Client,
+    rng: &mut dyn RngCore,
+    broadcaster: Option<&Broadcaster>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let private_key = PrivateKey::from_wif(wif)?;
+    // WIF only encodes mainnet vs. test networks, so that's all we can check
+    if (private_key.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+        return Err(format!("The key is for another network than {}", network).into());
+    }
+    let dest_address = Address::from_str(destination_address)?.require_network(network)?;
+
+    let addresses = KeyScript::ALL
+        .into_iter()
+        .filter_map(|kind| Some((kind, kind.address(secp, &private_key, network)?)))
+        .collect::<Vec<_>>();
+    let script_pubkeys = addresses
+        .iter()
+        .map(|(_, address)| address.script_pubkey())
+        .collect::<Vec<_>>();
+    let unspent = electrum_client
+        .batch_script_list_unspent(script_pubkeys.iter().map(|spk| spk.as_script()))?;
+
+    let mut inputs = Vec::new();
+    for (((kind, address), script_pubkey), outputs) in
+        addresses.iter().zip(script_pubkeys).zip(unspent)
+    {
+        for output in outputs {
+            println!(
+                "  {}:{} {} sat on {} address {}",
+                output.tx_hash,
+                output.tx_pos,
+                output.value,
+                kind.name(),
+                address
+            );
+            let txout = TxOut {
+                value: Amount::from_sat(output.value),
+                script_pubkey: script_pubkey.clone(),
+            };
+            inputs.push((
+                OutPoint::new(output.tx_hash, output.tx_pos as u32),
+                txout,
+                *kind,
+            ));
+        }
+    }
+    if inputs.is_empty() {
+        let addresses = addresses
+            .iter()
+            .map(|(_, address)| address.to_string())
+            .collect::<Vec<_>>();
+        return Err(format!(
+            "No coins on any address of the key: {}",
+            addresses.join(", ")
+        )
+        .into());
+    }
+    let input_value = inputs
+        .iter()
+        .map(|(_, txout, _)| txout.value)
+        .sum::<Amount>();
+
+    let mut output = TxOut {
+        value: Amount::ZERO,
+        script_pubkey: dest_address.script_pubkey(),
+    };
+    let weight = fees::base_weight(slice::from_ref(&output))
+        + inputs
+            .iter()
+            .map(|(_, _, kind)| kind.input_weight(private_key.compressed))
+            .sum::<Weight>();
+    let fee = fees::fee_for(fee_rate, weight);
+    output.value = input_value.checked_sub(fee).ok_or(format!(
+        "The {} sat swept would not cover the {} sat fee",
+        input_value.to_sat(),
+        fee.to_sat()
+    ))?;
+    println!(
+        "Sweeping {} input(s), {} sat to {}",
+        inputs.len(),
+        output.value.to_sat(),
+        dest_address
+    );
+    println!(
+        "Fee: {} sat at {}",
+        fee.to_sat(),
+        fees::format_fee_rate(fee_rate)
+    );
+
+    let outputs = vec![output];
+    checks::check_spend(input_value, &outputs, weight, force)?;
+    let tx = create_transaction_internal(secp, &private_key, &inputs, outputs, timelocks, rng)?;
+
+    println!(
+        "Signed tx: {}",
+        consensus::serialize(&tx).to_hex_string(Case::Lower)
+    );
+    if let Some(broadcaster) = broadcaster {
+        broadcaster.broadcast(&tx)?;
+    }
+
+    Ok(())
+}
+
+/// Builds and signs a transaction spending `inputs`, all controlled by `private_key` but each
+/// through its own kind of output, to `outputs`.
+pub(crate) fn create_transaction_internal(
+    secp: &Secp256k1<All>,
+    private_key: &PrivateKey,
+    inputs: &[(OutPoint, TxOut, KeyScript)],
+    outputs: Vec<TxOut>,
+    timelocks: &Timelocks,
+    rng: &mut dyn RngCore,
+) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let mut tx = Transaction {
+        version: Version(2),
+        lock_time: timelocks.lock_time,
+        input: inputs
+            .iter()
+            .zip(timelocks.sequences(inputs.len())?)
+            .map(|((prevout, _, _), sequence)| TxIn {
+                previous_output: *prevout,
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: outputs,
+    };
+
+    let public_key = private_key.public_key(secp);
+    let utxos_to_spend = inputs.iter().map(|(_, utxo, _)| utxo).collect::<Vec<_>>();
+    let prevouts = Prevouts::All(&utxos_to_spend);
+
+    // Every sighash is worked out on the unsigned transaction: legacy ones blank the other
+    // inputs' script sigs anyway, and segwit ones don't cover them
+    let mut cache = SighashCache::new(tx.clone());
+    let mut signed = Vec::with_capacity(inputs.len());
+    for (index, (_, utxo_to_spend, kind)) in inputs.iter().enumerate() {
+        let (script_sig, witness) = match kind {
+            KeyScript::P2pkh => {
+                let sighash = cache.legacy_signature_hash(
+                    index,
+                    &utxo_to_spend.script_pubkey,
+                    EcdsaSighashType::All.to_u32(),
+                )?;
+                let signature = ecdsa::Signature {
+                    sig: secp.sign_ecdsa_low_r(
+                        &Message::from_digest_slice(&sighash[..])?,
+                        &private_key.inner,
+                    ),
+                    hash_ty: EcdsaSighashType::All,
+                };
+                let script_sig = Builder::new()
+                    .push_slice(PushBytesBuf::try_from(signature.to_vec())?)
+                    .push_key(&public_key)
+                    .into_script();
+                (script_sig, Witness::new())
+            }
+            KeyScript::P2shP2wpkh | KeyScript::P2wpkh => {
+                let wpkh_script = ScriptBuf::new_p2wpkh(
+                    &public_key
+                        .wpubkey_hash()
+                        .ok_or("Segwit needs a compressed key")?,
+                );
+                let signature = wpkh::sign_input(
+                    secp,
+                    &mut cache,
+                    index,
+                    &wpkh_script,
+                    utxo_to_spend.value,
+                    private_key,
+                )?;
+                // Nested segwit reveals the P2WPKH script as the P2SH redeem script
+                let script_sig = match kind {
+                    KeyScript::P2shP2wpkh => Builder::new()
+                        .push_slice(PushBytesBuf::try_from(wpkh_script.to_bytes())?)
+                        .into_script(),
+                    _ => ScriptBuf::new(),
+                };
+                (script_sig, Witness::p2wpkh(&signature, &public_key.inner))
+            }
+            KeyScript::P2tr => {
+                let signature =
+                    keyspend::sign_input(secp, &mut cache, index, &prevouts, private_key, rng)?;
+                let mut witness = Witness::new();
+                witness.push(signature.to_vec());
+                (ScriptBuf::new(), witness)
+            }
+        };
+        signed.push((script_sig, witness));
+    }
+    for (input, (script_sig, witness)) in tx.input.iter_mut().zip(signed) {
+        input.script_sig = script_sig;
+        input.witness = witness;
+    }
+
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{
+        key::Secp256k1,
+        secp256k1::{rand::SeedableRng, SecretKey},
+        sighash::Prevouts,
+        Address, Amount, Network, OutPoint, PrivateKey, TxOut, Weight,
+    };
+    use miniscript::Interpreter;
+    use rand_chacha::ChaCha20Rng;
+
+    use super::{create_transaction_internal, KeyScript};
+    use crate::common::{fees, timelocks::Timelocks};
+
+    #[test]
+    fn test_sweep_every_key_script() {
+        let secp = Secp256k1::new();
+        let private_key = PrivateKey::new(
+            SecretKey::from_slice(&[3; 32]).expect("Invalid private key"),
+            Network::Regtest,
+        );
+        let prevout = OutPoint::from_str(
+            "bf210c79258b733a0b5076c96fc26eef206f63789a14719db9552212b5e0ed8d:0",
+        )
+        .expect("Invalid outpoint");
+        let inputs = KeyScript::ALL
+            .into_iter()
+            .enumerate()
+            .map(|(vout, kind)| {
+                let address = kind
+                    .address(&secp, &private_key, Network::Regtest)
+                    .expect("Compressed keys have every kind of address");
+                let utxo = TxOut {
+                    value: Amount::from_sat(25_000),
+                    script_pubkey: address.script_pubkey(),
+                };
+                (
+                    OutPoint {
+                        vout: vout as u32,
+                        ..prevout
+                    },
+                    utxo,
+                    kind,
+                )
+            })
+            .collect::<Vec<_>>();
+        let outputs = vec![TxOut {
+            value: Amount::from_sat(99_000),
+            script_pubkey: Address::from_str("bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu")
+                .expect("Unable to parse address")
+                .assume_checked()
+                .script_pubkey(),
+        }];
+
+        let tx = create_transaction_internal(
+            &secp,
+            &private_key,
+            &inputs,
+            outputs.clone(),
+            &Timelocks::default(),
+            &mut ChaCha20Rng::from_seed([7; 32]),
+        )
+        .expect("Signed transaction");
+
+        // The sweep's fee relies on every kind of input weighing exactly what we estimate
+        assert_eq!(
+            tx.weight(),
+            fees::base_weight(&outputs)
+                + inputs
+                    .iter()
+                    .map(|(_, _, kind)| kind.input_weight(true))
+                    .sum::<Weight>()
+        );
+        assert!(tx.input[0].witness.is_empty() && !tx.input[0].script_sig.is_empty());
+        assert!(!tx.input[1].witness.is_empty() && !tx.input[1].script_sig.is_empty());
+        assert!(tx.input[2].script_sig.is_empty() && tx.input[3].script_sig.is_empty());
+
+        // ...and every kind of input must actually be spent by its signature
+        let spent = inputs
+            .iter()
+            .map(|(_, utxo, _)| utxo.clone())
+            .collect::<Vec<_>>();
+        for (index, ((_, utxo, kind), input)) in inputs.iter().zip(&tx.input).enumerate() {
+            let interpreter = Interpreter::from_txdata(
+                &utxo.script_pubkey,
+                &input.script_sig,
+                &input.witness,
+                input.sequence,
+                tx.lock_time,
+            )
+            .expect("Unable to interpret the input");
+            let satisfied = interpreter
+                .iter(&secp, &tx, index, &Prevouts::All(&spent))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap_or_else(|e| panic!("Invalid {} input: {}", kind.name(), e));
+            assert!(!satisfied.is_empty(), "Unsigned {} input", kind.name());
+        }
+
+        // Uncompressed keys only have a P2PKH address
+        let uncompressed = PrivateKey {
+            compressed: false,
+            ..private_key
+        };
+        assert!(KeyScript::ALL
+            .into_iter()
+            .filter(|kind| kind
+                .address(&secp, &uncompressed, Network::Regtest)
+                .is_some())
+            .eq([KeyScript::P2pkh]));
+    }
+}
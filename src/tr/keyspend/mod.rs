@@ -1,89 +1,199 @@
-use std::{fs::File, io::Read, str::FromStr};
+use std::{borrow::Borrow, path::Path, str::FromStr};
 
 use bitcoin::{
     consensus::Encodable,
     hex::{Case, DisplayHex},
     key::{Keypair, Secp256k1, TapTweak},
+    secp256k1::rand::RngCore,
     secp256k1::{All, Message},
     sighash::{Prevouts, SighashCache},
     taproot, Address, Amount,
     Denomination::Satoshi,
-    Network, OutPoint, PrivateKey, PublicKey, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
+    FeeRate, Network, OutPoint, PrivateKey, ScriptBuf, TapSighashType, Transaction, TxIn, TxOut,
     Witness, XOnlyPublicKey,
 };
-use electrum_client::{Client, ElectrumApi};
+use electrum_client::Client;
+
+use crate::common::{
+    broadcast::Broadcaster,
+    checks, fees,
+    keys::{self, Keychain, ScriptType},
+    timelocks::{self, Timelocks},
+    utxos,
+    wallet::{self, WalletStore},
+};
 
 pub fn generate_address(
     secp: &Secp256k1<All>,
     public_key: &str,
+    index: u32,
+    network: Network,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Parse the public key
-    let public_key = PublicKey::from_str(public_key)?;
+    // Parse the public key, deriving it first if we were given an account xpub
+    let public_key = keys::receive_public_key(secp, public_key, index)?;
     let internal_key: XOnlyPublicKey = public_key.into();
 
     // Let rust-bitcoin handle tweaking
-    let addr = Address::p2tr(secp, internal_key, None, Network::Regtest);
+    let addr = Address::p2tr(secp, internal_key, None, network);
     println!("Address: {}", addr);
 
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_transaction(
     secp: &Secp256k1<All>,
     electrum_client: &Client,
     destination_address: &str,
-    prevout: &str,
+    prevouts: &[String],
     amount: &str,
+    key_path: &Path,
+    wallet_store_path: &Path,
+    fee_rate: FeeRate,
+    network: Network,
+    timelocks: &Timelocks,
+    force: bool,
+    rng: &mut dyn RngCore,
+    broadcaster: Option<&Broadcaster>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let dest_address = Address::from_str(destination_address)?
-        .require_network(Network::Regtest)
-        .expect("Regtest address");
-    let prevout = OutPoint::from_str(prevout).expect("Invalid outpoint");
-    let amount = Amount::from_str_in(amount, Satoshi).expect("Invalid amount");
-
-    let prev_tx = electrum_client
-        .transaction_get(&prevout.txid)
-        .expect("Unable to get previous transaction details");
-    let utxo_to_spend = prev_tx
-        .output
-        .get(prevout.vout as usize)
-        .expect("Invalid vout");
+    let dest_address = Address::from_str(destination_address)?.require_network(network)?;
+    let prevouts = prevouts
+        .iter()
+        .map(|prevout| {
+            OutPoint::from_str(prevout).map_err(|e| format!("Invalid outpoint {}: {}", prevout, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let amount = Amount::from_str_in(amount, Satoshi)?;
+    let utxos_to_spend = utxos::get_txouts(electrum_client, &prevouts)?;
+
+    // Load the private key of the address that owns each previous output
+    let key = keys::read_key(key_path, network)?;
+    let mut inputs = Vec::with_capacity(prevouts.len());
+    for (prevout, utxo_to_spend) in prevouts.into_iter().zip(utxos_to_spend) {
+        let private_key = wallet::signing_key_for(
+            secp,
+            &key,
+            ScriptType::Tr,
+            wallet_store_path,
+            &utxo_to_spend.script_pubkey,
+        )?;
+        inputs.push((prevout, utxo_to_spend, private_key));
+    }
+
+    // Whatever the amount and fee leave goes back to us as change
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let (change, change_wallet) = wallet::change_output(secp, &key, ScriptType::Tr, &store)?;
+    let mut outputs = vec![TxOut {
+        value: amount,
+        script_pubkey: dest_address.script_pubkey(),
+    }];
+    let input_value = inputs.iter().map(|(_, utxo, _)| utxo.value).sum();
+    let input_weight = fees::TR_KEY_SPEND_INPUT_WEIGHT * inputs.len() as u64;
+    let fee = fees::add_change(&mut outputs, change, input_value, input_weight, fee_rate)?;
+    let change_wallet = change_wallet.filter(|_| outputs.len() > 1);
+    let weight = fees::base_weight(&outputs) + input_weight;
+    checks::check_spend(input_value, &outputs, weight, force)?;
+    let prevouts = inputs
+        .iter()
+        .map(|(prevout, utxo, _)| (*prevout, utxo.clone()))
+        .collect::<Vec<_>>();
+    timelocks::check_timelocks(timelocks, &prevouts, electrum_client, force)?;
+
+    let tx = create_transaction_internal(secp, &inputs, outputs, timelocks, rng)?;
 
+    // Only now that the transaction exists is the change address used up
+    if let Some(change_wallet) = change_wallet {
+        store.reveal_next(&change_wallet, Keychain::Internal);
+        store.save()?;
+    }
+    println!(
+        "Fee: {} sat at {}",
+        fee.to_sat(),
+        fees::format_fee_rate(fee_rate)
+    );
+
+    let mut encoded_tx_bytes = Vec::new();
+    tx.consensus_encode(&mut encoded_tx_bytes).unwrap();
+
+    // bytes to hex
+    println!("Signed tx: {}", encoded_tx_bytes.to_hex_string(Case::Lower));
+    if let Some(broadcaster) = broadcaster {
+        broadcaster.broadcast(&tx)?;
+    }
+
+    Ok(())
+}
+
+/// Builds and signs a transaction spending `inputs`, each with its own key, to `outputs`.
+pub(crate) fn create_transaction_internal(
+    secp: &Secp256k1<All>,
+    inputs: &[(OutPoint, TxOut, PrivateKey)],
+    outputs: Vec<TxOut>,
+    timelocks: &Timelocks,
+    rng: &mut dyn RngCore,
+) -> Result<Transaction, Box<dyn std::error::Error>> {
     let mut unsigned_tx = Transaction {
         version: bitcoin::transaction::Version(2),
-        lock_time: bitcoin::absolute::LockTime::from_height(0).unwrap(),
-        input: vec![TxIn {
-            previous_output: prevout,
-            script_sig: ScriptBuf::new(),
-            sequence: Sequence::MAX,
-            witness: Witness::new(),
-        }],
-        output: vec![TxOut {
-            value: amount,
-            script_pubkey: dest_address.script_pubkey(),
-        }],
+        lock_time: timelocks.lock_time,
+        input: inputs
+            .iter()
+            .zip(timelocks.sequences(inputs.len())?)
+            .map(|((prevout, _, _), sequence)| TxIn {
+                previous_output: *prevout,
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: outputs,
     };
 
-    // Compute sighash
-    let binding = vec![utxo_to_spend];
-    let prevouts = Prevouts::All(&binding);
+    // Taproot sighashes commit to every output being spent, not just the signed input's
+    let utxos_to_spend = inputs.iter().map(|(_, utxo, _)| utxo).collect::<Vec<_>>();
+    let prevouts = Prevouts::All(&utxos_to_spend);
 
+    let mut witnesses = Vec::with_capacity(inputs.len());
     let mut cache = SighashCache::new(&unsigned_tx);
+    for (index, (_, _, private_key)) in inputs.iter().enumerate() {
+        let signature = sign_input(secp, &mut cache, index, &prevouts, private_key, rng)?;
+        let mut witness = Witness::new();
+        witness.push(signature.to_vec());
+        witnesses.push(witness);
+    }
+    for (input, witness) in unsigned_tx.input.iter_mut().zip(witnesses) {
+        input.witness = witness;
+    }
+
+    Ok(unsigned_tx)
+}
+
+/// Signs input `index` of the cached transaction as a key-path spend of `private_key`'s output,
+/// over its BIP341 sighash.
+pub(crate) fn sign_input<T: Borrow<Transaction>, O: Borrow<TxOut>>(
+    secp: &Secp256k1<All>,
+    cache: &mut SighashCache<T>,
+    index: usize,
+    prevouts: &Prevouts<O>,
+    private_key: &PrivateKey,
+    rng: &mut dyn RngCore,
+) -> Result<taproot::Signature, Box<dyn std::error::Error>> {
+    // Compute sighash
     let sighash =
-        cache.taproot_key_spend_signature_hash(0, &prevouts, bitcoin::TapSighashType::Default)?;
+        cache.taproot_key_spend_signature_hash(index, prevouts, TapSighashType::Default)?;
     let msg = Message::from_digest_slice(&sighash[..])?;
 
     // Sign
-    // Load private key
-    let mut private_key_str = String::new();
-    File::open("key.txt")?.read_to_string(&mut private_key_str)?;
-    let private_key = PrivateKey::from_str(&private_key_str)?;
     let keypair = Keypair::from_secret_key(secp, &private_key.inner);
 
+    // Auxiliary randomness only hardens signing against side channels, so it may come from a
+    // seeded source to get reproducible signatures
+    let mut aux_rand = [0u8; 32];
+    rng.fill_bytes(&mut aux_rand);
+
     let tweaked_key_pair = keypair.tap_tweak(secp, None);
     let signature = taproot::Signature {
-        sig: secp.sign_schnorr(&msg, &tweaked_key_pair.to_inner()),
-        hash_ty: bitcoin::TapSighashType::Default,
+        sig: secp.sign_schnorr_with_aux_rand(&msg, &tweaked_key_pair.to_inner(), &aux_rand),
+        hash_ty: TapSighashType::Default,
     };
 
     // Sanity check
@@ -91,18 +201,128 @@ pub fn create_transaction(
         &signature.sig,
         &msg,
         &tweaked_key_pair.to_inner().x_only_public_key().0,
-    )
-    .unwrap();
+    )?;
 
-    let mut witness = Witness::new();
-    witness.push(signature.to_vec());
-    unsigned_tx.input[0].witness = witness;
+    Ok(signature)
+}
 
-    let mut encoded_tx_bytes = Vec::new();
-    unsigned_tx.consensus_encode(&mut encoded_tx_bytes).unwrap();
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
 
-    // bytes to hex
-    println!("Signed tx: {}", encoded_tx_bytes.to_hex_string(Case::Lower));
+    use bitcoin::{
+        key::Secp256k1,
+        secp256k1::{rand::SeedableRng, Message, SecretKey},
+        sighash::{Prevouts, SighashCache},
+        taproot, Address, Amount, Network, OutPoint, PrivateKey, TapSighashType, TxOut,
+        XOnlyPublicKey,
+    };
+    use rand_chacha::ChaCha20Rng;
 
-    Ok(())
+    use super::create_transaction_internal;
+    use crate::common::timelocks::Timelocks;
+
+    #[test]
+    fn test_deterministic_key_spend() {
+        let secp = Secp256k1::new();
+        let private_key = PrivateKey::new(
+            SecretKey::from_slice(&[1; 32]).expect("Invalid private key"),
+            Network::Regtest,
+        );
+        let internal_key = private_key.public_key(&secp).inner.x_only_public_key().0;
+        let utxo_to_spend = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: Address::p2tr(&secp, internal_key, None, Network::Regtest)
+                .script_pubkey(),
+        };
+        let prevout = OutPoint::from_str(
+            "bf210c79258b733a0b5076c96fc26eef206f63789a14719db9552212b5e0ed8d:1",
+        )
+        .expect("Invalid outpoint");
+        let destination_address = Address::from_str("bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu")
+            .expect("Unable to parse address")
+            .assume_checked();
+
+        // The same seed must give the same aux-rand, and so the same witness
+        let sign = || {
+            create_transaction_internal(
+                &secp,
+                &[(prevout, utxo_to_spend.clone(), private_key)],
+                vec![TxOut {
+                    value: Amount::from_sat(99_000),
+                    script_pubkey: destination_address.script_pubkey(),
+                }],
+                &Timelocks::default(),
+                &mut ChaCha20Rng::from_seed([7; 32]),
+            )
+            .expect("Signed transaction")
+        };
+        let tx = sign();
+        assert_eq!(tx, sign());
+        assert_eq!(tx.input[0].witness.len(), 1);
+        assert_eq!(
+            tx.wtxid().to_string(),
+            "9d08d21384e7d1c1950d2b1085a632d46770d659fbb531e03ef897af58b393cb"
+        );
+    }
+
+    #[test]
+    fn test_multi_input_key_spend() {
+        let secp = Secp256k1::new();
+        let inputs = [(1u8, 60_000, 0), (2, 40_000, 3)].map(|(seed, value, vout)| {
+            let private_key = PrivateKey::new(
+                SecretKey::from_slice(&[seed; 32]).expect("Invalid private key"),
+                Network::Regtest,
+            );
+            let internal_key = private_key.public_key(&secp).inner.x_only_public_key().0;
+            let utxo_to_spend = TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: Address::p2tr(&secp, internal_key, None, Network::Regtest)
+                    .script_pubkey(),
+            };
+            let prevout = OutPoint::from_str(&format!(
+                "bf210c79258b733a0b5076c96fc26eef206f63789a14719db9552212b5e0ed8d:{}",
+                vout
+            ))
+            .expect("Invalid outpoint");
+            (prevout, utxo_to_spend, private_key)
+        });
+        let destination_address = Address::from_str("bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu")
+            .expect("Unable to parse address")
+            .assume_checked();
+
+        let tx = create_transaction_internal(
+            &secp,
+            &inputs,
+            vec![TxOut {
+                value: Amount::from_sat(99_000),
+                script_pubkey: destination_address.script_pubkey(),
+            }],
+            &Timelocks::default(),
+            &mut ChaCha20Rng::from_seed([7; 32]),
+        )
+        .expect("Signed transaction");
+
+        // Every signature must commit to both spent outputs
+        let utxos = inputs.iter().map(|(_, utxo, _)| utxo).collect::<Vec<_>>();
+        let mut cache = SighashCache::new(&tx);
+        for (index, (_, utxo, _)) in inputs.iter().enumerate() {
+            let sighash = cache
+                .taproot_key_spend_signature_hash(
+                    index,
+                    &Prevouts::All(&utxos),
+                    TapSighashType::Default,
+                )
+                .expect("Sighash");
+            let signature =
+                taproot::Signature::from_slice(&tx.input[index].witness[0]).expect("Signature");
+            secp.verify_schnorr(
+                &signature.sig,
+                &Message::from_digest_slice(&sighash[..]).expect("Message"),
+                &XOnlyPublicKey::from_slice(&utxo.script_pubkey.as_bytes()[2..])
+                    .expect("Output key"),
+            )
+            .expect("Valid signature");
+        }
+    }
 }
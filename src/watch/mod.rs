@@ -0,0 +1,321 @@
+use std::{path::Path, str::FromStr};
+
+use bitcoin::{
+    psbt::Psbt, transaction::Version, Address, Amount, Denomination::Satoshi, FeeRate, Network,
+    OutPoint, ScriptBuf, Transaction, TxIn, TxOut, Witness,
+};
+use electrum_client::Client;
+use miniscript::psbt::PsbtExt;
+
+use crate::common::{
+    checks, fees, keyring,
+    keys::{Keychain, ScriptType},
+    timelocks::{self, Timelocks},
+    utxos,
+    wallet::{Wallet, WalletStore},
+};
+
+/// Registers a wallet built from an xpub or descriptor, without any private key.
+pub fn add_wallet(
+    wallet_store_path: &Path,
+    name: &str,
+    public: &str,
+    script_type: Option<ScriptType>,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    keyring::check_name(name)?;
+    let wallet = Wallet::from_public_str(public, script_type, network)?;
+
+    let mut store = WalletStore::load(wallet_store_path)?;
+    store.add_watch_only(name, &wallet.full_descriptor().to_string())?;
+    store.save()?;
+
+    println!("Watching `{}`: {}", name, wallet.full_descriptor());
+    println!("First address: {}", wallet.address(Keychain::External, 0)?);
+
+    Ok(())
+}
+
+pub fn list_wallets(wallet_store_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let store = WalletStore::load(wallet_store_path)?;
+    let mut wallets = store.watch_only_wallets().peekable();
+    if wallets.peek().is_none() {
+        println!("No watch-only wallets. Run `tx-fun watch add <name> <descriptor>` to add one");
+    }
+    for (name, descriptor) in wallets {
+        println!("{}: {}", name, descriptor);
+    }
+
+    Ok(())
+}
+
+/// Loads the watch-only wallet registered as `name`.
+pub fn load_wallet(
+    wallet_store_path: &Path,
+    name: &str,
+    network: Network,
+) -> Result<Wallet, Box<dyn std::error::Error>> {
+    let store = WalletStore::load(wallet_store_path)?;
+    let descriptor = store.watch_only(name).ok_or(format!(
+        "No watch-only wallet named `{}`. Run `tx-fun watch add {} <descriptor>` to add it",
+        name, name
+    ))?;
+
+    Wallet::from_descriptor_str(descriptor, network)
+}
+
+/// Resolves `--wallet`, either the name of a watch-only wallet or a descriptor.
+pub fn resolve_wallet(
+    wallet_store_path: &Path,
+    name_or_descriptor: &str,
+    network: Network,
+) -> Result<(String, Wallet), Box<dyn std::error::Error>> {
+    let store = WalletStore::load(wallet_store_path)?;
+    match store.watch_only(name_or_descriptor) {
+        Some(descriptor) => Ok((
+            name_or_descriptor.to_string(),
+            Wallet::from_descriptor_str(descriptor, network)?,
+        )),
+        None => {
+            let wallet = Wallet::from_public_str(name_or_descriptor, None, network)?;
+            Ok((wallet.full_descriptor().to_string(), wallet))
+        }
+    }
+}
+
+/// Builds an unsigned PSBT spending `prevout`, carrying everything an offline signer needs.
+/// What the amount and fee leave goes back to the wallet as change.
+#[allow(clippy::too_many_arguments)]
+pub fn create_psbt(
+    wallet: &Wallet,
+    wallet_store_path: &Path,
+    destination_address: &str,
+    prevout: &str,
+    amount: &str,
+    fee_rate: FeeRate,
+    network: Network,
+    timelocks: &Timelocks,
+    force: bool,
+    electrum_client: &Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dest_address = Address::from_str(destination_address)?.require_network(network)?;
+    let prevout =
+        OutPoint::from_str(prevout).map_err(|e| format!("Invalid outpoint {}: {}", prevout, e))?;
+    let amount = Amount::from_str_in(amount, Satoshi)?;
+    let utxo_to_spend = utxos::get_txouts(electrum_client, &[prevout])?.remove(0);
+
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let change_keychain = wallet.change_keychain();
+    let change_index = store
+        .last_index(&wallet.id(), change_keychain)
+        .map_or(0, |i| i + 1);
+    let change = TxOut {
+        value: Amount::ZERO,
+        script_pubkey: wallet
+            .address(change_keychain, change_index)?
+            .script_pubkey(),
+    };
+    let mut outputs = vec![TxOut {
+        value: amount,
+        script_pubkey: dest_address.script_pubkey(),
+    }];
+    let input_value = utxo_to_spend.value;
+    let input_weight = fees::input_weight(wallet.descriptor(Keychain::External)?)?;
+    let fee = fees::add_change(&mut outputs, change, input_value, input_weight, fee_rate)?;
+    let weight = fees::base_weight(&outputs) + input_weight;
+    checks::check_spend(input_value, &outputs, weight, force)?;
+    let inputs = [(prevout, utxo_to_spend)];
+    timelocks::check_timelocks(timelocks, &inputs, electrum_client, force)?;
+
+    let has_change = outputs.len() > 1;
+    let psbt = create_psbt_internal(wallet, &store, &inputs, outputs, timelocks)?;
+
+    // Only now that the PSBT exists is the change address used up
+    if has_change {
+        store.reveal_next(wallet, change_keychain);
+        store.save()?;
+    }
+    println!(
+        "Fee: {} sat at {}",
+        fee.to_sat(),
+        fees::format_fee_rate(fee_rate)
+    );
+    println!("Psbt: {}", psbt.serialize_hex());
+
+    Ok(())
+}
+
+/// Builds an unsigned PSBT spending `inputs` to `outputs`, with each input filled in from the
+/// wallet's descriptor.
+pub(crate) fn create_psbt_internal(
+    wallet: &Wallet,
+    store: &WalletStore,
+    inputs: &[(OutPoint, TxOut)],
+    outputs: Vec<TxOut>,
+    timelocks: &Timelocks,
+) -> Result<Psbt, Box<dyn std::error::Error>> {
+    let unsigned_tx = Transaction {
+        version: Version(2),
+        lock_time: timelocks.lock_time,
+        input: inputs
+            .iter()
+            .zip(timelocks.sequences(inputs.len())?)
+            .map(|((prevout, _), sequence)| TxIn {
+                previous_output: *prevout,
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: outputs,
+    };
+
+    // The descriptor fills in the witness script and the key origins signers look their keys up by
+    let mut psbt = Psbt::from_unsigned_tx(unsigned_tx)?;
+    for (index, (_, utxo_to_spend)) in inputs.iter().enumerate() {
+        let (keychain, derivation_index) = wallet
+            .derivation_of(store, &utxo_to_spend.script_pubkey)?
+            .ok_or("The previous output does not belong to this wallet")?;
+        psbt.inputs[index].witness_utxo = Some(utxo_to_spend.clone());
+        psbt.update_input_with_descriptor(
+            index,
+            &wallet
+                .descriptor(keychain)?
+                .at_derivation_index(derivation_index)?,
+        )?;
+    }
+
+    Ok(psbt)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{
+        bip32::Xpriv, key::Secp256k1, secp256k1::SecretKey, Address, Amount, Network, OutPoint,
+        PrivateKey, TxOut, Weight,
+    };
+    use miniscript::psbt::PsbtExt;
+
+    use super::create_psbt_internal;
+    use crate::common::{
+        fees,
+        keys::{self, Keychain, ScriptType, StoredKey},
+        timelocks::Timelocks,
+        wallet::{Wallet, WalletStore},
+    };
+    use crate::wsh::threshold_sig::sign_psbt_internal;
+
+    #[test]
+    fn test_watch_only_multisig() {
+        let secp = Secp256k1::new();
+        let xprivs = [1u8, 2, 3]
+            .map(|seed| Xpriv::new_master(Network::Regtest, &[seed; 32]).expect("Master key"));
+        let keys = xprivs
+            .iter()
+            .map(|xpriv| {
+                let xpub = keys::account_xpub(&secp, xpriv, ScriptType::Wsh).expect("Xpub");
+                format!("{}/<0;1>/*", xpub)
+            })
+            .collect::<Vec<_>>();
+        let descriptor = format!("wsh(sortedmulti(2,{}))", keys.join(","));
+
+        // The coordinator only knows the descriptor
+        let wallet =
+            Wallet::from_public_str(&descriptor, None, Network::Regtest).expect("Watch-only");
+        let utxo_to_spend = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: wallet
+                .address(Keychain::Internal, 2)
+                .expect("Address")
+                .script_pubkey(),
+        };
+        let prevout = OutPoint::from_str(
+            "bf210c79258b733a0b5076c96fc26eef206f63789a14719db9552212b5e0ed8d:1",
+        )
+        .expect("Invalid outpoint");
+        let destination_address = Address::from_str("bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu")
+            .expect("Unable to parse address")
+            .assume_checked();
+
+        let psbt = create_psbt_internal(
+            &wallet,
+            &WalletStore::default(),
+            &[(prevout, utxo_to_spend)],
+            vec![TxOut {
+                value: Amount::from_sat(99_000),
+                script_pubkey: destination_address.script_pubkey(),
+            }],
+            &Timelocks::default(),
+        )
+        .expect("Unsigned PSBT");
+        assert!(psbt.inputs[0].partial_sigs.is_empty());
+        assert!(psbt.inputs[0].witness_script.is_some());
+        assert_eq!(psbt.inputs[0].bip32_derivation.len(), 3);
+
+        // Two of the air-gapped signers sign it, then the coordinator finalizes
+        let mut psbt = sign_psbt_internal(&secp, psbt, &StoredKey::Master(xprivs[0]))
+            .expect("First signature");
+        let second = sign_psbt_internal(&secp, psbt.clone(), &StoredKey::Master(xprivs[2]))
+            .expect("Second signature");
+        psbt.combine(second).expect("Combined PSBT");
+        let psbt = psbt.finalize(&secp).expect("Finalized PSBT");
+        let tx = psbt.extract_tx().expect("Signed transaction");
+
+        // Fees are worked out for the largest signatures, which are at most one byte longer
+        let estimate = fees::base_weight(&tx.output)
+            + fees::input_weight(wallet.full_descriptor()).expect("Weight");
+        assert!(tx.weight() <= estimate && estimate - tx.weight() <= Weight::from_wu(2));
+    }
+
+    #[test]
+    fn test_single_key_cosigner() {
+        let secp = Secp256k1::new();
+        let xpriv = Xpriv::new_master(Network::Regtest, &[1; 32]).expect("Master key");
+        let xpub = keys::account_xpub(&secp, &xpriv, ScriptType::Wsh).expect("Xpub");
+        let private_key = PrivateKey::new(
+            SecretKey::from_slice(&[2; 32]).expect("Secret key"),
+            Network::Regtest,
+        );
+        let descriptor = format!(
+            "wsh(sortedmulti(2,{}/<0;1>/*,{}))",
+            xpub,
+            private_key.public_key(&secp)
+        );
+        let wallet =
+            Wallet::from_public_str(&descriptor, None, Network::Regtest).expect("Watch-only");
+        let utxo_to_spend = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: wallet
+                .address(Keychain::External, 0)
+                .expect("Address")
+                .script_pubkey(),
+        };
+        let prevout = OutPoint::from_str(
+            "bf210c79258b733a0b5076c96fc26eef206f63789a14719db9552212b5e0ed8d:0",
+        )
+        .expect("Invalid outpoint");
+
+        let psbt = create_psbt_internal(
+            &wallet,
+            &WalletStore::default(),
+            &[(prevout, utxo_to_spend.clone())],
+            vec![TxOut {
+                value: Amount::from_sat(99_000),
+                script_pubkey: utxo_to_spend.script_pubkey,
+            }],
+            &Timelocks::default(),
+        )
+        .expect("Unsigned PSBT");
+
+        // A single imported key signs through its public key rather than a key origin
+        let mut psbt = sign_psbt_internal(&secp, psbt, &StoredKey::Single(private_key))
+            .expect("Single key signature");
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+        let second = sign_psbt_internal(&secp, psbt.clone(), &StoredKey::Master(xpriv))
+            .expect("Master key signature");
+        psbt.combine(second).expect("Combined PSBT");
+        psbt.finalize(&secp).expect("Finalized PSBT");
+    }
+}
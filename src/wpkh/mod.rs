@@ -1,96 +1,265 @@
-use std::str::FromStr;
-use std::{fs::File, io::Read};
+use std::{borrow::Borrow, path::Path, str::FromStr};
 
 use bitcoin::{
     consensus::Encodable,
     ecdsa,
     hex::{Case, DisplayHex},
-    key::{PrivateKey, PublicKey, Secp256k1},
-    secp256k1::Message,
+    key::Secp256k1,
+    secp256k1::{All, Message},
     sighash::{EcdsaSighashType, SighashCache},
     transaction::Version,
     Address, Amount,
     Denomination::Satoshi,
-    Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+    FeeRate, Network, OutPoint, PrivateKey, Script, ScriptBuf, Transaction, TxIn, TxOut, Witness,
 };
 
-use electrum_client::{Client, ElectrumApi};
+use electrum_client::Client;
 
-pub fn generate_address(public_key: String) -> Result<(), Box<dyn std::error::Error>> {
-    // Parse the public key
-    let public_key = PublicKey::from_str(&public_key)?;
+use crate::common::{
+    broadcast::Broadcaster,
+    checks, fees,
+    keys::{self, Keychain, ScriptType},
+    timelocks::{self, Timelocks},
+    utxos,
+    wallet::{self, WalletStore},
+};
+
+pub fn generate_address(
+    secp: &Secp256k1<All>,
+    public_key: &str,
+    index: u32,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Parse the public key, deriving it first if we were given an account xpub
+    let public_key = keys::receive_public_key(secp, public_key, index)?;
 
-    Address::p2wpkh(&public_key, Network::Regtest).map(|a| {
+    Address::p2wpkh(&public_key, network).map(|a| {
         println!("Address: {}", a);
     })?;
 
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_transaction(
+    secp: &Secp256k1<All>,
     destination_address: &str,
-    prevout: &str,
+    prevouts: &[String],
     amount: &str,
+    key_path: &Path,
+    wallet_store_path: &Path,
+    fee_rate: FeeRate,
+    network: Network,
+    timelocks: &Timelocks,
+    force: bool,
     electrum_client: &Client,
+    broadcaster: Option<&Broadcaster>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let dest_address = Address::from_str(destination_address)?
-        .require_network(Network::Regtest)
-        .expect("Regtest address");
-    let prevout = OutPoint::from_str(prevout).expect("Invalid outpoint");
-    let amount = Amount::from_str_in(amount, Satoshi).expect("Invalid amount");
-
-    let prev_tx = electrum_client
-        .transaction_get(&prevout.txid)
-        .expect("Unable to get previous transaction details");
-    let utxo_to_spend = prev_tx
-        .output
-        .get(prevout.vout as usize)
-        .expect("Invalid vout");
+    let dest_address = Address::from_str(destination_address)?.require_network(network)?;
+    let prevouts = prevouts
+        .iter()
+        .map(|prevout| {
+            OutPoint::from_str(prevout).map_err(|e| format!("Invalid outpoint {}: {}", prevout, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let amount = Amount::from_str_in(amount, Satoshi)?;
+    let utxos_to_spend = utxos::get_txouts(electrum_client, &prevouts)?;
+
+    // Load the private key of the address that owns each previous output
+    let key = keys::read_key(key_path, network)?;
+    let mut inputs = Vec::with_capacity(prevouts.len());
+    for (prevout, utxo_to_spend) in prevouts.into_iter().zip(utxos_to_spend) {
+        let private_key = wallet::signing_key_for(
+            secp,
+            &key,
+            ScriptType::Wpkh,
+            wallet_store_path,
+            &utxo_to_spend.script_pubkey,
+        )?;
+        inputs.push((prevout, utxo_to_spend, private_key));
+    }
+
+    // Whatever the amount and fee leave goes back to us as change
+    let mut store = WalletStore::load(wallet_store_path)?;
+    let (change, change_wallet) = wallet::change_output(secp, &key, ScriptType::Wpkh, &store)?;
+    let mut outputs = vec![TxOut {
+        value: amount,
+        script_pubkey: dest_address.script_pubkey(),
+    }];
+    let input_value = inputs.iter().map(|(_, utxo, _)| utxo.value).sum();
+    let input_weight = fees::WPKH_INPUT_WEIGHT * inputs.len() as u64;
+    let fee = fees::add_change(&mut outputs, change, input_value, input_weight, fee_rate)?;
+    let change_wallet = change_wallet.filter(|_| outputs.len() > 1);
+    let weight = fees::base_weight(&outputs) + input_weight;
+    checks::check_spend(input_value, &outputs, weight, force)?;
+    let prevouts = inputs
+        .iter()
+        .map(|(prevout, utxo, _)| (*prevout, utxo.clone()))
+        .collect::<Vec<_>>();
+    timelocks::check_timelocks(timelocks, &prevouts, electrum_client, force)?;
+
+    let tx = create_transaction_internal(secp, &inputs, outputs, timelocks)?;
+
+    // Only now that the transaction exists is the change address used up
+    if let Some(change_wallet) = change_wallet {
+        store.reveal_next(&change_wallet, Keychain::Internal);
+        store.save()?;
+    }
+    println!(
+        "Fee: {} sat at {}",
+        fee.to_sat(),
+        fees::format_fee_rate(fee_rate)
+    );
+
+    let mut encoded_tx_bytes = Vec::new();
+    tx.consensus_encode(&mut encoded_tx_bytes).unwrap();
+
+    // bytes to hex
+    println!("Signed tx: {}", encoded_tx_bytes.to_hex_string(Case::Lower));
+    if let Some(broadcaster) = broadcaster {
+        broadcaster.broadcast(&tx)?;
+    }
+
+    Ok(())
+}
 
+/// Builds and signs a transaction spending `inputs`, each with its own key, to `outputs`.
+pub(crate) fn create_transaction_internal(
+    secp: &Secp256k1<All>,
+    inputs: &[(OutPoint, TxOut, PrivateKey)],
+    outputs: Vec<TxOut>,
+    timelocks: &Timelocks,
+) -> Result<Transaction, Box<dyn std::error::Error>> {
     // Construct transaction
     let mut tx = Transaction {
         version: Version(2),
-        lock_time: bitcoin::absolute::LockTime::from_height(0).unwrap(),
-        input: vec![TxIn {
-            previous_output: prevout,
-            script_sig: ScriptBuf::new(),
-            sequence: Sequence::MAX,
-            witness: Witness::new(),
-        }],
-        output: vec![TxOut {
-            value: amount,
-            script_pubkey: dest_address.script_pubkey(),
-        }],
+        lock_time: timelocks.lock_time,
+        input: inputs
+            .iter()
+            .zip(timelocks.sequences(inputs.len())?)
+            .map(|((prevout, _, _), sequence)| TxIn {
+                previous_output: *prevout,
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: outputs,
     };
 
-    // Compute sighash
+    // Each input commits to its own previous output, so sign them one at a time
     let mut cache = SighashCache::new(tx.clone());
-    let sighash = cache.p2wpkh_signature_hash(
-        0,
-        &utxo_to_spend.script_pubkey,
-        utxo_to_spend.value,
-        EcdsaSighashType::All,
-    )?;
-    let msg = Message::from_digest_slice(&sighash[..])?;
+    for (index, (_, utxo_to_spend, private_key)) in inputs.iter().enumerate() {
+        let signature = sign_input(
+            secp,
+            &mut cache,
+            index,
+            &utxo_to_spend.script_pubkey,
+            utxo_to_spend.value,
+            private_key,
+        )?;
+        let public_key = private_key.public_key(secp).inner;
+        tx.input[index].witness = Witness::p2wpkh(&signature, &public_key);
+    }
+
+    Ok(tx)
+}
 
-    // Load private key
-    let secp = Secp256k1::new();
-    let mut private_key_str = String::new();
-    File::open("key.txt")?.read_to_string(&mut private_key_str)?;
-    let private_key = PrivateKey::from_str(&private_key_str)?;
-    let public_key = private_key.public_key(&secp).inner;
+/// Signs input `index` of the cached transaction, which spends `value` from `private_key`'s
+/// P2WPKH script `wpkh_script`, over its BIP143 sighash. Nested P2SH-P2WPKH inputs sign the same.
+pub(crate) fn sign_input<T: Borrow<Transaction>>(
+    secp: &Secp256k1<All>,
+    cache: &mut SighashCache<T>,
+    index: usize,
+    wpkh_script: &Script,
+    value: Amount,
+    private_key: &PrivateKey,
+) -> Result<ecdsa::Signature, Box<dyn std::error::Error>> {
+    let sighash = cache.p2wpkh_signature_hash(index, wpkh_script, value, EcdsaSighashType::All)?;
+    let msg = Message::from_digest_slice(&sighash[..])?;
 
-    let signature = ecdsa::Signature {
-        sig: secp.sign_ecdsa(&msg, &private_key.inner),
+    // Grinding for a low R keeps signatures at (almost always) 70 bytes, so the fee can be
+    // worked out before signing
+    Ok(ecdsa::Signature {
+        sig: secp.sign_ecdsa_low_r(&msg, &private_key.inner),
         hash_ty: EcdsaSighashType::All,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{
+        ecdsa,
+        key::Secp256k1,
+        secp256k1::{Message, SecretKey},
+        sighash::{EcdsaSighashType, SighashCache},
+        Address, Amount, Network, OutPoint, PrivateKey, PublicKey, TxOut,
     };
-    tx.input[0].witness = Witness::p2wpkh(&signature, &public_key);
 
-    let mut encoded_tx_bytes = Vec::new();
-    tx.consensus_encode(&mut encoded_tx_bytes).unwrap();
+    use super::create_transaction_internal;
+    use crate::common::timelocks::Timelocks;
 
-    // bytes to hex
-    println!("Signed tx: {}", encoded_tx_bytes.to_hex_string(Case::Lower));
+    #[test]
+    fn test_multi_input_spend() {
+        let secp = Secp256k1::new();
+        let inputs = [(1u8, 60_000, 0), (2, 40_000, 3)].map(|(seed, value, vout)| {
+            let private_key = PrivateKey::new(
+                SecretKey::from_slice(&[seed; 32]).expect("Invalid private key"),
+                Network::Regtest,
+            );
+            let utxo_to_spend = TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: Address::p2wpkh(&private_key.public_key(&secp), Network::Regtest)
+                    .expect("Compressed key")
+                    .script_pubkey(),
+            };
+            let prevout = OutPoint::from_str(&format!(
+                "bf210c79258b733a0b5076c96fc26eef206f63789a14719db9552212b5e0ed8d:{}",
+                vout
+            ))
+            .expect("Invalid outpoint");
+            (prevout, utxo_to_spend, private_key)
+        });
+        let destination_address = Address::from_str("bcrt1qt72nlqdrlj3yrlslx5sx7ltle337gflz5s23xu")
+            .expect("Unable to parse address")
+            .assume_checked();
 
-    Ok(())
+        let tx = create_transaction_internal(
+            &secp,
+            &inputs,
+            vec![TxOut {
+                value: Amount::from_sat(99_000),
+                script_pubkey: destination_address.script_pubkey(),
+            }],
+            &Timelocks::default(),
+        )
+        .expect("Signed transaction");
+
+        // Each signature must commit to its own input's index, script and amount
+        let mut cache = SighashCache::new(&tx);
+        for (index, (_, utxo, private_key)) in inputs.iter().enumerate() {
+            let witness = &tx.input[index].witness;
+            assert_eq!(witness.len(), 2);
+            let public_key = PublicKey::from_slice(&witness[1]).expect("Public key");
+            assert_eq!(public_key, private_key.public_key(&secp));
+
+            let sighash = cache
+                .p2wpkh_signature_hash(
+                    index,
+                    &utxo.script_pubkey,
+                    utxo.value,
+                    EcdsaSighashType::All,
+                )
+                .expect("Sighash");
+            let signature = ecdsa::Signature::from_slice(&witness[0]).expect("Signature");
+            secp.verify_ecdsa(
+                &Message::from_digest_slice(&sighash[..]).expect("Message"),
+                &signature.sig,
+                &public_key.inner,
+            )
+            .expect("Valid signature");
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use std::{error::Error, fs::File, io::Read, str::FromStr};
+use std::{collections::BTreeMap, error::Error, path::Path, str::FromStr};
 
 use bitcoin::{
     consensus::Encodable,
@@ -10,10 +10,20 @@ use bitcoin::{
     sighash::SighashCache,
     Address, Amount,
     Denomination::Satoshi,
-    EcdsaSighashType, Network, OutPoint, PrivateKey, PublicKey, ScriptBuf, Sequence, Transaction,
-    TxIn, TxOut, Witness,
+    EcdsaSighashType, Network, OutPoint, PrivateKey, PublicKey, ScriptBuf, Transaction, TxIn,
+    TxOut, Witness,
+};
+use electrum_client::Client;
+use miniscript::{
+    psbt::PsbtExt, DefiniteDescriptorKey, Descriptor, DescriptorPublicKey, ForEachKey,
+};
+
+use crate::common::{
+    broadcast::Broadcaster,
+    keys::{self, Keychain, StoredKey},
+    timelocks::{self, Timelocks},
+    wallet::{Wallet, WalletStore},
 };
-use miniscript::{psbt::PsbtExt, Descriptor, DescriptorPublicKey};
 
 pub fn generate_descriptor(
     alice_pubkey: &str,
@@ -33,10 +43,12 @@ fn generate_descriptor_internal(
     bob_pubkey: &str,
     charlie_pubkey: &str,
 ) -> Result<String, Box<dyn std::error::Error>> {
-    let mut keys = vec![
-        DescriptorPublicKey::from_str(alice_pubkey)?,
-        DescriptorPublicKey::from_str(bob_pubkey)?,
-        DescriptorPublicKey::from_str(charlie_pubkey)?,
+    // Account xpubs are expanded to both keychains so that the descriptor is ranged and has a
+    // change chain
+    let mut keys = [
+        keys::account_keychains(DescriptorPublicKey::from_str(alice_pubkey)?),
+        keys::account_keychains(DescriptorPublicKey::from_str(bob_pubkey)?),
+        keys::account_keychains(DescriptorPublicKey::from_str(charlie_pubkey)?),
     ];
     keys.sort_by_key(|k| k.to_string());
 
@@ -49,18 +61,79 @@ fn generate_descriptor_internal(
     Ok(format!("wsh(sortedmulti(2,{}))", joined_keys))
 }
 
-pub fn generate_address(descriptor_str: &str) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Address: {}", generate_address_internal(descriptor_str)?);
+pub fn generate_address(
+    descriptor_str: &str,
+    index: u32,
+    network: Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!(
+        "Address: {}",
+        generate_address_internal(descriptor_str, index, network)?
+    );
     Ok(())
 }
 
-fn generate_address_internal(descriptor_str: &str) -> Result<Address, Box<dyn std::error::Error>> {
-    let descriptor = Descriptor::<PublicKey>::from_str(descriptor_str)?;
-    let address = descriptor.address(bitcoin::Network::Regtest).unwrap();
+fn generate_address_internal(
+    descriptor_str: &str,
+    index: u32,
+    network: Network,
+) -> Result<Address, Box<dyn std::error::Error>> {
+    Wallet::from_descriptor_str(descriptor_str, network)?.address(Keychain::External, index)
+}
 
-    Ok(address)
+/// Our private key in a multisig descriptor, along with the descriptor derived at its index.
+pub(crate) type SigningKey = (PrivateKey, Descriptor<PublicKey>);
+
+/// Finds the key in `descriptor` that belongs to `key` at derivation index `index`, and returns
+/// its private key alongside the derived descriptor, or `None` when none of its keys are ours. A
+/// single imported key only matches itself.
+pub(crate) fn find_signing_key(
+    secp: &Secp256k1<All>,
+    key: &StoredKey,
+    descriptor: &Descriptor<DescriptorPublicKey>,
+    index: u32,
+) -> Result<Option<SigningKey>, Box<dyn Error>> {
+    let definite = descriptor.at_derivation_index(index)?;
+
+    let mut private_key = Ok(None);
+    definite.for_each_key(|descriptor_key| {
+        private_key = signing_key_of(secp, key, descriptor_key);
+        // Stop at the first key of ours, or at the first error
+        matches!(private_key, Ok(None))
+    });
+
+    match private_key? {
+        Some(private_key) => Ok(Some((private_key, definite.derived_descriptor(secp)?))),
+        None => Ok(None),
+    }
+}
+
+/// The private key behind `descriptor_key` if it comes from `key`.
+fn signing_key_of(
+    secp: &Secp256k1<All>,
+    key: &StoredKey,
+    descriptor_key: &DefiniteDescriptorKey,
+) -> Result<Option<PrivateKey>, Box<dyn Error>> {
+    let candidate = match key {
+        StoredKey::Master(xpriv) => {
+            let path = match descriptor_key.full_derivation_path() {
+                Some(path) if descriptor_key.master_fingerprint() == xpriv.fingerprint(secp) => {
+                    path
+                }
+                _ => return Ok(None),
+            };
+            xpriv.derive_priv(secp, &path)?.to_priv()
+        }
+        StoredKey::Single(private_key) => *private_key,
+    };
+
+    match candidate.public_key(secp) == descriptor_key.derive_public_key(secp)? {
+        true => Ok(Some(candidate)),
+        false => Ok(None),
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_signed_psbt(
     secp: &Secp256k1<All>,
     utxo_to_spend: &TxOut,
@@ -68,28 +141,47 @@ pub fn create_signed_psbt(
     destination_address: &str,
     prevout: OutPoint,
     amount: &str,
+    key_path: &Path,
+    wallet_store_path: &Path,
+    network: Network,
+    timelocks: &Timelocks,
+    force: bool,
+    electrum_client: &Client,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let dest_address = Address::from_str(destination_address)?
-        .require_network(Network::Regtest)
-        .expect("Regtest address");
-    let amount = Amount::from_str_in(amount, Satoshi).expect("Invalid amount");
-    let descriptor = Descriptor::<PublicKey>::from_str(descriptor_str)?;
+    let dest_address = Address::from_str(destination_address)?.require_network(network)?;
+    let amount = Amount::from_str_in(amount, Satoshi)?;
+    let wallet = Wallet::from_descriptor_str(descriptor_str, network)?;
+    let (keychain, index) = wallet
+        .derivation_of(
+            &WalletStore::load(wallet_store_path)?,
+            &utxo_to_spend.script_pubkey,
+        )?
+        .ok_or("The previous output does not belong to this descriptor")?;
+
+    timelocks::check_timelocks(
+        timelocks,
+        &[(prevout, utxo_to_spend.clone())],
+        electrum_client,
+        force,
+    )?;
 
     // Load private key
-    let mut private_key_str = String::new();
-    File::open("key.txt")?.read_to_string(&mut private_key_str)?;
-    let private_key = PrivateKey::from_str(&private_key_str)?;
+    let key = keys::read_key(key_path, network)?;
+    let (private_key, descriptor) =
+        find_signing_key(secp, &key, wallet.descriptor(keychain)?, index)?
+            .ok_or("None of the descriptor keys belong to this key file")?;
 
     println!(
         "Psbt: {}",
         create_signed_psbt_internal(
-            &secp,
+            secp,
             utxo_to_spend,
             private_key,
             descriptor,
             dest_address,
             prevout,
-            amount
+            amount,
+            timelocks
         )?
         .serialize_hex()
     );
@@ -97,6 +189,7 @@ pub fn create_signed_psbt(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_signed_psbt_internal(
     secp: &Secp256k1<All>,
     utxo_to_spend: &TxOut,
@@ -105,14 +198,15 @@ fn create_signed_psbt_internal(
     destination_address: Address,
     prevout: OutPoint,
     amount: Amount,
+    timelocks: &Timelocks,
 ) -> Result<Psbt, Box<dyn Error>> {
     let unsigned_tx = Transaction {
         version: bitcoin::transaction::Version(2),
-        lock_time: bitcoin::absolute::LockTime::from_height(0).unwrap(),
+        lock_time: timelocks.lock_time,
         input: vec![TxIn {
             previous_output: prevout,
             script_sig: ScriptBuf::new(),
-            sequence: Sequence::MAX,
+            sequence: timelocks.sequences(1)?[0],
             witness: Witness::new(),
         }],
         output: vec![TxOut {
@@ -141,18 +235,59 @@ fn create_signed_psbt_internal(
     psbt.inputs[0].witness_utxo = Some(utxo_to_spend.clone());
     psbt.inputs[0]
         .partial_sigs
-        .insert(private_key.public_key(&secp), signature);
+        .insert(private_key.public_key(secp), signature);
 
     psbt.inputs[0].witness_script = Some(witness_script);
 
     Ok(psbt)
 }
 
-/// Combines two PSBTs given as hex-encoded strings.
+/// Adds our signatures to a PSBT prepared elsewhere, such as by a watch-only coordinator.
+pub fn sign_psbt(
+    secp: &Secp256k1<All>,
+    psbt_hex: &str,
+    key_path: &Path,
+    network: Network,
+) -> Result<(), Box<dyn Error>> {
+    let psbt = Psbt::deserialize(&Vec::from_hex(psbt_hex)?)?;
+    let key = keys::read_key(key_path, network)?;
+
+    println!(
+        "Psbt: {}",
+        sign_psbt_internal(secp, psbt, &key)?.serialize_hex()
+    );
+
+    Ok(())
+}
+
+pub(crate) fn sign_psbt_internal(
+    secp: &Secp256k1<All>,
+    mut psbt: Psbt,
+    key: &StoredKey,
+) -> Result<Psbt, Box<dyn Error>> {
+    // Keys are found through the key origins of each input, so nothing else needs to be known
+    let signed = match key {
+        StoredKey::Master(xpriv) => psbt.sign(xpriv, secp),
+        StoredKey::Single(private_key) => psbt.sign(
+            &BTreeMap::from([(private_key.public_key(secp), *private_key)]),
+            secp,
+        ),
+    }
+    .map_err(|(_, errors)| format!("Unable to sign PSBT: {:?}", errors))?;
+    if signed.values().all(|keys| keys.is_empty()) {
+        return Err("None of the PSBT inputs belong to this key file".into());
+    }
+
+    Ok(psbt)
+}
+
+/// Combines two PSBTs given as hex-encoded strings, broadcasting the finalized transaction when
+/// given a broadcaster.
 pub fn combine_psbts(
     secp: &Secp256k1<All>,
     psbt_1_hex: &str,
     psbt_2_hex: &str,
+    broadcaster: Option<&Broadcaster>,
 ) -> Result<(), Box<dyn Error>> {
     // Decode the hex string into bytes and deserialize into Psbt
     let psbt_1_bytes = Vec::from_hex(psbt_1_hex)?;
@@ -161,7 +296,7 @@ pub fn combine_psbts(
     let psbt_2_bytes = Vec::from_hex(psbt_2_hex)?;
     let second_psbt = Psbt::deserialize(&psbt_2_bytes)?;
 
-    let finalized_psbt = combine_psbts_internal(&secp, host_psbt, second_psbt)?;
+    let finalized_psbt = combine_psbts_internal(secp, host_psbt, second_psbt)?;
 
     // Serialize the combined PSBT back to hex for display or further use
     println!("Combined PSBT: {}", finalized_psbt.serialize_hex());
@@ -174,6 +309,9 @@ pub fn combine_psbts(
         "Transaction to broadcast: {}",
         encoded_tx_bytes.to_lower_hex_string()
     );
+    if let Some(broadcaster) = broadcaster {
+        broadcaster.broadcast(&finalized_tx)?;
+    }
 
     Ok(())
 }
@@ -185,7 +323,9 @@ fn combine_psbts_internal(
 ) -> Result<Psbt, Box<dyn Error>> {
     psbt_1.combine(psbt_2)?;
 
-    let finalized_psbt = psbt_1.finalize(secp).expect("Unable to finalize PSBT");
+    let finalized_psbt = psbt_1
+        .finalize(secp)
+        .map_err(|(_, errors)| format!("Unable to finalize the PSBT: {:?}", errors))?;
     Ok(finalized_psbt)
 }
 
@@ -194,11 +334,12 @@ mod tests {
     use std::str::FromStr;
 
     use bitcoin::{
-        key::Secp256k1, Address, Amount, Denomination::Satoshi, OutPoint, PrivateKey, PublicKey,
-        ScriptBuf, TxOut,
+        key::Secp256k1, Address, Amount, Denomination::Satoshi, Network, OutPoint, PrivateKey,
+        PublicKey, ScriptBuf, TxOut,
     };
     use miniscript::Descriptor;
 
+    use crate::common::timelocks::Timelocks;
     use crate::wsh::threshold_sig::{
         combine_psbts_internal, create_signed_psbt_internal, generate_address_internal,
     };
@@ -231,8 +372,8 @@ mod tests {
         .expect("Descriptor generation failed");
         assert_eq!(descriptor_str, "wsh(sortedmulti(2,02c843041d74e80d603de1c59fe9644cef04ded85076970d1141bcf04977397bde,02e3a6822881384e821a121bef8da55eaa3f7b905899d672bcaf353b54575db3ec,038000c4aa5c2ae6edeb3e350d10ef1c4167ae204c9fddb08cea5cc4ac699c00f6))");
 
-        let address =
-            generate_address_internal(&descriptor_str).expect("Address generation failed");
+        let address = generate_address_internal(&descriptor_str, 0, Network::Regtest)
+            .expect("Address generation failed");
         assert_eq!(
             address.to_string(),
             "bcrt1q8wmjmkf0qgshwmqnlptn5jfw4yhwhfc0ve49cg9u0m24ayee6llshuc5g9"
@@ -271,6 +412,7 @@ mod tests {
             destination_address.clone(),
             prevout,
             amount,
+            &Timelocks::default(),
         )
         .expect("Alice PSBT");
 
@@ -282,11 +424,12 @@ mod tests {
             destination_address,
             prevout,
             amount,
+            &Timelocks::default(),
         )
         .expect("Bob PSBT");
 
         let composed_psbt = combine_psbts_internal(&secp, psbt_1, psbt_2).expect("Combined PSBT");
 
-        assert_eq!(composed_psbt.serialize_hex(), "70736274ff01005202000000018dede0b5122255b99d71149a78636f20ef6ec26fc976500b3a738b25790c21bf0100000000ffffffff0150c30000000000001600145f953f81a3fca241fe1f35206f7d7fcc63e427e2000000000001012ba0860100000000002200203bb72dd92f0221776c13f8573a492ea92eeba70f666a5c20bc7ed55e9339d7ff0108fdfd000400483045022100a617cd8cd32e83478ac4939a70d2a68802d5a60c447e6eb72efac26d217e5a3802204b7c5b45b4827a7669694626a41cef90f222e00b73545874fe4d2dcbc00e701601473044022005a56b5498eb1d7f7a1009c69e0f801b4cd84477e349d35aa3448e8244baae6f02204b388e47e681dc6b00ae8f903c58d50a6d9c27e614534e9658890f36e9a78f270169522102c843041d74e80d603de1c59fe9644cef04ded85076970d1141bcf04977397bde2102e3a6822881384e821a121bef8da55eaa3f7b905899d672bcaf353b54575db3ec21038000c4aa5c2ae6edeb3e350d10ef1c4167ae204c9fddb08cea5cc4ac699c00f653ae0000")
+        assert_eq!(composed_psbt.serialize_hex(), "70736274ff01005202000000018dede0b5122255b99d71149a78636f20ef6ec26fc976500b3a738b25790c21bf0100000000fdffffff0150c30000000000001600145f953f81a3fca241fe1f35206f7d7fcc63e427e2000000000001012ba0860100000000002200203bb72dd92f0221776c13f8573a492ea92eeba70f666a5c20bc7ed55e9339d7ff0108fdfe000400483045022100a3b4cbbfa372b59a241d5a7910e91e284b1745580f536361af8d5e9c3eb75b61022027fc18063cc48a613f31ee9c8a82160b403ef1e80cc81e104c3c7f0f2f3e633f01483045022100e5837d6b5943dfe3a2dec01ae1f016b9255449576c107b6d8733667438fba74502204cad4a143269cb571d1bbc3857ad704dec86864e1525d7785339fca23d7b62840169522102c843041d74e80d603de1c59fe9644cef04ded85076970d1141bcf04977397bde2102e3a6822881384e821a121bef8da55eaa3f7b905899d672bcaf353b54575db3ec21038000c4aa5c2ae6edeb3e350d10ef1c4167ae204c9fddb08cea5cc4ac699c00f653ae0000")
     }
 }